@@ -48,10 +48,10 @@ pub fn criterion_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("copying");
     for i in sizes.iter() {
         group.bench_with_input(BenchmarkId::new("fscopy", i), i, |b, i| {
-            b.iter(|| fscopy(*i))
+            b.iter(|| fscopy(i))
         });
         group.bench_with_input(BenchmarkId::new("iocopy", i), i, |b, i| {
-            b.iter(|| iocopy(*i))
+            b.iter(|| iocopy(i))
         });
     }
     group.finish();
@@ -62,10 +62,10 @@ pub fn criterion_benchmark(c: &mut Criterion) {
     let mut group = c.benchmark_group("cp+hash");
     for i in sizes.iter() {
         group.bench_with_input(BenchmarkId::new("cp+re", i), i, |b, i| {
-            b.iter(|| copy_then_hash(*i))
+            b.iter(|| copy_then_hash(i))
         });
         group.bench_with_input(BenchmarkId::new("tee", i), i, |b, i| {
-            b.iter(|| tee_and_hash(*i))
+            b.iter(|| tee_and_hash(i))
         });
     }
     group.finish();
@@ -0,0 +1,68 @@
+/*
+The purpose of this file is to see how `gar add` scales with the size of its worker pool.
+
+The add walker fans file hashing and blobcas linking out to a pool of threads
+(see `AddWork::add_recurse_dir`).  Hashing is CPU bound and storage is quick,
+so on a tree with lots of files, more threads should help until we run out of cores --
+or until the filesystem becomes the bottleneck instead.
+
+The fixture is a synthetic tree of many smallish files spread over a handful of dirs,
+which is the shape where a single thread doing SHA-256 hurts the most.
+Files are made with distinct contents, so there's no dedup doing us any favors.
+
+Each iteration adds the same tree again to the same heap.  That's intentional:
+the blobcas and treecas entries already existing is the cheap path for *storage*,
+so what's left being measured is mostly the walk and the hashing, which is the part the pool is for.
+(Use `LinkOriginals` mode, so there's also no copying to muddy the numbers.)
+*/
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use std::{fs, io::Write, path::PathBuf};
+
+use gar_rust::{add, repo};
+
+fn pth(filename: &str) -> PathBuf {
+    PathBuf::from("/tmp/gar-test-parallel/").join(filename)
+}
+
+fn make_fixture() {
+    const DIRS: usize = 16;
+    const FILES_PER_DIR: usize = 256;
+    const FILE_SIZE: usize = 64 * 1024;
+    let _ = fs::remove_dir_all(pth(""));
+    for d in 0..DIRS {
+        let dir = pth("tree").join(format!("dir{d:02}"));
+        fs::create_dir_all(&dir).expect("");
+        for f in 0..FILES_PER_DIR {
+            let mut body = vec![0x61_u8; FILE_SIZE];
+            body[..8].copy_from_slice(&((d * FILES_PER_DIR + f) as u64).to_le_bytes());
+            fs::File::create(dir.join(format!("file{f:03}")))
+                .expect("")
+                .write_all(&body)
+                .expect("");
+        }
+    }
+}
+
+pub fn criterion_benchmark(c: &mut Criterion) {
+    make_fixture();
+    let r = repo::Repo::new_bare(pth("heap")).expect("");
+    r.create_dir_all().expect("");
+
+    let mut group = c.benchmark_group("add");
+    group.sample_size(10);
+    for threads in [1, 2, 4, 8] {
+        let opts = add::AddOptions {
+            faithmode: add::FaithMode::LinkOriginals,
+            threads,
+            ..Default::default()
+        };
+        group.bench_with_input(BenchmarkId::new("threads", threads), &opts, |b, opts| {
+            b.iter(|| add::add(&r, pth("tree"), opts).expect(""))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, criterion_benchmark);
+criterion_main!(benches);
@@ -1,47 +1,322 @@
+use std::ffi::{OsStr, OsString};
 use std::fs;
 use std::io;
 use std::os::unix::fs::MetadataExt as _;
+use std::os::unix::fs::OpenOptionsExt as _;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 
+use rayon::prelude::*;
+
+use crate::error::{Context as _, GarError, Stage};
+use crate::garidx;
+use crate::garignore;
 use crate::gittree;
+use crate::gittree::EntryKind;
+use crate::journal;
+use crate::labels;
+use crate::reflink;
 use crate::repo;
+use crate::statcache;
+use crate::treeobj;
+
+/// What came of an add: the hash, and some counts of how it went.
+#[derive(Debug)]
+pub struct AddReport {
+    pub hash: gittree::Hash,
+    pub stats: AddStats,
+    /// Whether the tree was new to the treecas, and so had to be built there.
+    /// (False when it was already there from an earlier add, and always for a single file added as just a blob.)
+    pub new_tree: bool,
+    /// Originals that didn't have a normal mode (0644 or 0755), and what their mode was.
+    /// What was done about each of them depends on the `ModePolicy`.
+    /// (Always empty under `ModePolicy::Ignore`, and in modes that don't put originals in the blobcas.)
+    pub odd_modes: Vec<(PathBuf, u32)>,
+    /// Special files that were left out, per `AddOptions::special_files`.
+    /// (Paths as found: under the path given to `add`, just as `gittree::hash_of_path_with_policy` reports them.)
+    pub skipped: Vec<PathBuf>,
+}
+
+/// Counts of how each file found its way into the blobcas.
+/// (Files whose blob was already present are still counted by the route they would have taken.)
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AddStats {
+    pub linked: u64,
+    pub copied: u64,
+    pub moved: u64,
+    pub reflinked: u64,
+    /// Files that the stat cache vouched for, and whose blob was already in the blobcas, so they weren't even read.
+    pub cached: u64,
+}
+
+/// Knobs for an add.  `AddOptions::default()` is a link-originals add that leaves modes alone.
+#[derive(Debug, Default, Clone)]
+pub struct AddOptions {
+    pub faithmode: FaithMode,
+    pub mode_policy: ModePolicy,
+    /// When adding a single file: wrap it in a tree, as the one entry, with this name.
+    /// Otherwise (and by default), a single file is added as just a blob, and its blobhash is the result.
+    /// Ignored when adding a directory.
+    pub wrap_name: Option<OsString>,
+    /// How many threads to hash and store files with.  Zero (the default) means one per CPU.
+    pub threads: usize,
+    /// Exclude patterns (in `.garignore` syntax) that apply from the root of the add.
+    /// These are in addition to any `.garignore` files in the tree, and take precedence over them.
+    pub excludes: Vec<OsString>,
+    /// What to do about FIFOs, sockets, device nodes, and other things that can't be part of a tree.
+    pub special_files: gittree::SpecialFilePolicy,
+    /// Don't write a garidx file for the tree into the treeidx.
+    pub no_index: bool,
+    /// Whether to use (and update) the stat cache.  See the `statcache` module.
+    pub stat_cache: statcache::StatCacheMode,
+    /// Point this label at the result, once it's in the heap.  (See the `labels` module.)
+    pub label: Option<String>,
+}
 
 pub fn add(
     repo: &repo::Repo,
     path: impl AsRef<Path>,
-    faithmode: FaithMode,
-) -> Result<gittree::Hash, io::Error> {
-    // Make the tempdir in the treecas root: we'll fill into this,
-    // then move the tempdir into it's CAS-named place at the very end.
-    //
-    // (n.b., Even if the entire tree we're adding turns out to be familiar... there's no real shortcut to noticing it.
-    // We'll notice only at the end, when we're about ready to commit to CAS.
-    // In Copy mode, this may be unfortunate, because we've done all this write IO already and now have to drop it;
-    // but in either case, we had to do all the read IO, and there's simply no way to avoid _that_.)
-    let td = tempdir::TempDir::new_in(repo.treecas_path(), ".wiptree-")?;
+    opts: &AddOptions,
+) -> Result<AddReport, GarError> {
+    let path = path.as_ref();
+    // A symlink given as the path stands for what it points at.  (Linking, copying, or moving it would get the link itself,
+    // not the file behind it, and a symlink in the blobcas would be a blob whose content is somewhere else entirely.)
+    let resolved;
+    let path = match fs::symlink_metadata(path)
+        .at(Stage::Scan, path)?
+        .is_symlink()
+    {
+        true => {
+            resolved = fs::canonicalize(path).at(Stage::Scan, path)?;
+            resolved.as_path()
+        }
+        false => path,
+    };
+    let path_meta = fs::symlink_metadata(path).at(Stage::Scan, path)?;
+    // Held until we're done, so a gc can't collect blobs out from under us.
+    let _lock = repo.lock_shared()?;
+    // (Checked now, so a bad name doesn't waste a whole add.)
+    if let Some(name) = &opts.label {
+        labels::check_name(repo, name)?;
+    }
+
+    // A single file is a blob, not a tree: it goes in the blobcas and that's it, unless asked to wrap it in a tree.
+    // Either way, we handle it as the one entry in its parent dir, so the rest of the add machinery works as usual.
+    let (scan_root, single_file) = if path_meta.is_file() {
+        let file_name = path.file_name().ok_or_else(|| GarError::InvalidInput {
+            reason: format!("{:?} has no file name", path),
+        })?;
+        (path.parent().unwrap_or(Path::new("")), Some(file_name))
+    } else if path_meta.is_dir() {
+        (path, None)
+    } else {
+        return Err(GarError::SpecialFile {
+            path: path.to_owned(),
+        });
+    };
+    let blob_only = single_file.is_some() && opts.wrap_name.is_none();
+    if let Some(wrap_name) = &opts.wrap_name {
+        let b = wrap_name.as_encoded_bytes();
+        if b.is_empty() || b == b"." || b == b".." || b.contains(&b'/') {
+            return Err(GarError::InvalidInput {
+                reason: format!("{:?} is not usable as a file name", wrap_name),
+            });
+        }
+    }
+
+    // Make a staging dir in the blobcas root, for modes that write new files.
+    // Those get written here first, and only linked into their CAS-named place once we know their hash.
+    // (Anything left in here when we're done -- e.g. after an error -- is discarded when this drops.)
+    let staging = tempdir::TempDir::new_in(repo.blobcas_path(), ".wipblob-")
+        .at(Stage::Blobcas, repo.blobcas_path())?;
+
+    // Move mode is destructive to the originals, so it keeps an intent journal until the very end.
+    // See the `journal` module for why.
+    // (A single file doesn't need one: see `move_original` for how that's kept safe instead.)
+    let journal = match opts.faithmode {
+        FaithMode::Move if single_file.is_none() => Some(
+            journal::MoveJournal::create(repo, scan_root, &journal_options(opts))
+                .at(Stage::Journal, repo.journal_path())?,
+        ),
+        _ => None,
+    };
 
-    // TODO: have not handled the case where a single file is given as target.  That doesn't really produce a treecas, by most definitions.
-    // I'm not exactly sure what the correct UX is for that.
-    // Gar may just end up forbidding this because I don't know what else it should do.
+    // Reflink modes find out up front if the heap's filesystem can do reflinks at all.
+    // The strict mode stops right here if not; the lenient one just won't bother trying each file.
+    let reflink_unsupported = match opts.faithmode {
+        FaithMode::Reflink | FaithMode::ReflinkOrCopy => {
+            !reflink::probe(staging.path()).at(Stage::Blobcas, staging.path())?
+        }
+        _ => false,
+    };
+    if reflink_unsupported && matches!(opts.faithmode, FaithMode::Reflink) {
+        return Err(GarError::ReflinkUnsupported {
+            path: repo.blobcas_path().to_owned(),
+        });
+    }
+
+    // The stat cache, if we're using it.  Entries made during this add are recorded as of when it started.
+    // (See the `statcache` module about racy mtimes for why that's the time that matters.)
+    let started = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("clock before 1970")
+        .as_secs() as i64;
+    let stat_cache = match opts.stat_cache {
+        statcache::StatCacheMode::Off => None,
+        _ => Some(statcache::StatCache::load(repo.statcache_path())?),
+    };
 
     let w = AddWork {
         repo,
-        repo_ino: fs::metadata(repo.repo_path())?.ino(),
-        scan_root: path.as_ref(),
-        wiptree_root: td.path(),
-        faithmode,
+        exclusions: garignore::Exclusions::new(
+            &opts.excludes,
+            Some(&fs::metadata(repo.repo_path()).at(Stage::Repo, repo.repo_path())?),
+        ),
+        blobcas_dev: fs::metadata(repo.blobcas_path())
+            .at(Stage::Repo, repo.blobcas_path())?
+            .dev(),
+        scan_root,
+        staging_root: staging.path(),
+        staging_seq: AtomicU64::new(0),
+        reflink_unsupported,
+        opts,
+        journal,
+        unlink_after_commit: Mutex::default(),
+        stats: Mutex::default(),
+        odd_modes: Mutex::default(),
+        skipped: Mutex::default(),
+        index: match blob_only || opts.no_index {
+            true => None,
+            false => Some(Mutex::default()),
+        },
+        stat_cache_updates: stat_cache.as_ref().map(|_| Mutex::default()),
+        stat_cache,
+        started,
     };
 
     // Walk the filesystem.
     // (n.b., individual things start entering the blobcas immediately;
     // even though we have one big commit at the end for the whole treecas,
     // removing things from the blobcas as soon as this walk begins would require a GC.)
-    let hash = w.add_recurse(Path::new(""), &fs::metadata(w.scan_root)?)?;
+    // The walk fans out over a pool of worker threads: see `add_recurse_dir`.
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(opts.threads)
+        .build()
+        .map_err(|e| GarError::InvalidInput {
+            reason: format!("couldn't start {} worker threads: {}", opts.threads, e),
+        })?;
+    let (hash, found) = match single_file {
+        None => pool.install(|| w.add_recurse_dir(Path::new(""), &garignore::Rules::new()))?,
+        Some(file_name) => {
+            let (hash, _) = w.put_blob(Path::new(file_name), &path_meta)?;
+            if let Some(wrap_name) = &opts.wrap_name {
+                // A tree with exactly one entry.
+                let kind = match is_executable(&path_meta) {
+                    true => EntryKind::Executable,
+                    false => EntryKind::File,
+                };
+                w.index_entry(Path::new(wrap_name), kind, Some(path_meta.len()), &hash);
+                let mut tha = gittree::TreeHashAccumulator::new(repo.object_format(), 1);
+                tha.append(kind, wrap_name.as_encoded_bytes(), &hash);
+                let (tree_hash, payload) = tha.finish_with_payload();
+                treeobj::store(repo, &tree_hash, &payload)?;
+                let found = vec![Found {
+                    name: wrap_name.clone(),
+                    kind,
+                    hash,
+                    content: FoundContent::Blob,
+                }];
+                (tree_hash, found)
+            } else {
+                (hash, Vec::new())
+            }
+        }
+    };
+
+    let AddWork {
+        journal,
+        unlink_after_commit,
+        stats,
+        odd_modes,
+        skipped,
+        index,
+        stat_cache_updates,
+        ..
+    } = w;
+
+    // Only now that we know the tree's hash do we build its link farm in the treecas -- and only if it's new.
+    // Re-adding a familiar tree (a nightly snapshot where nothing changed, say) costs no wiptree IO at all:
+    // the walk above read and hashed the source, which there's no way around, but it didn't write anything but new blobs.
+    //
+    // (There's no shortcut for familiar *subtrees* of a new tree, though.  Every entry of a treecas entry is its own hardlink,
+    // so copying a link farm from an older treecas entry would be exactly as many links as making it afresh from the blobcas;
+    // and since the walk kept everything it found, neither means looking at the source again.)
+    let dest_path = repo.treecas_path().join(hash.as_hex());
+    let new_tree = !blob_only && !dest_path.exists();
+    if new_tree {
+        // Make the wiptree in the treecas root, fill it in, and then move it into its CAS-named place.
+        // If it's there by then, someone else added the same tree in the meantime, which is just as good.
+        let td = tempdir::TempDir::new_in(repo.treecas_path(), ".wiptree-")
+            .at(Stage::Wiptree, repo.treecas_path())?;
+        pool.install(|| materialize(repo, td.path(), &found))?;
+        match commit_wiptree(td, &dest_path) {
+            Ok(()) | Err(GarError::TreeExists { .. }) => {}
+            Err(e) => return Err(e),
+        }
+    }
 
-    // The final commit: move the whole wiptree into CAS place.
-    let dest_path: PathBuf = repo.treecas_path().join(hash.as_hex());
-    let treecas_result = fs::rename(&td, &dest_path);
+    // The garidx goes in after the tree it describes.  (So one that exists always describes a tree that does.)
+    // If there's already one, it's for the same tree, so there's nothing to do;
+    // but if there isn't (say, the tree was added by something that didn't write one), this fills that in.
+    if let Some(index) = index {
+        let idx_path = repo.treeidx_path().join(hash.as_hex());
+        if !idx_path.exists() {
+            // (Entries get found in parallel, so put them back in order.)
+            let mut entries = index.into_inner().unwrap();
+            entries.sort_by(|a, b| a.path.cmp(&b.path));
+            garidx::write_file(&idx_path, &garidx::with_root(hash.clone(), entries))?;
+        }
+    }
+
+    if let Some(name) = &opts.label {
+        labels::set(repo, name, &hash, &labels::Expect::Anything, "gar add")?;
+    }
+
+    // Now that everything is committed, the originals of a move have nothing left to protect,
+    // and neither does a journal.
+    for orig_path in unlink_after_commit.into_inner().unwrap() {
+        fs::remove_file(&orig_path).at(Stage::Blobcas, &orig_path)?;
+    }
+    if let Some(journal) = journal {
+        journal.finish().at(Stage::Journal, repo.journal_path())?;
+    }
+
+    // What this add saw replaces the stat cache entirely: entries for files it didn't see (gone, or not part of this add) are dropped,
+    // so the cache doesn't grow without bound as files come and go.
+    if let Some(updates) = stat_cache_updates {
+        updates.into_inner().unwrap().save(repo.statcache_path())?;
+    }
+
+    // (Files get visited in parallel, so put these back in a sensible order.)
+    let mut odd_modes = odd_modes.into_inner().unwrap();
+    odd_modes.sort();
+    let mut skipped = skipped.into_inner().unwrap();
+    skipped.sort();
+    Ok(AddReport {
+        hash,
+        stats: stats.into_inner().unwrap(),
+        new_tree,
+        odd_modes,
+        skipped,
+    })
+}
+
+/// Move a finished wiptree into its CAS-named place in the treecas.
+/// Returns `GarError::TreeExists` if there was already an entry of that name.
+pub(crate) fn commit_wiptree(td: tempdir::TempDir, dest_path: &Path) -> Result<(), GarError> {
+    let treecas_result = fs::rename(&td, dest_path);
 
     // The error handling for this last step, however, is... wild.
     //
@@ -84,16 +359,106 @@ pub fn add(
             //      --> is what we really see, except its hidden behind `#![feature(io_error_more)]`.
             //
             // So... Check the actual outcomes instead.
-            // Return the error from renaming only if the destination doesn't exist.
-            if !dest_path.exists() {
-                return Err(e);
+            // If the destination exists, say so (and the caller can decide that's fine);
+            // otherwise, the error from renaming is a real one.
+            if dest_path.exists() {
+                return Err(GarError::TreeExists {
+                    path: dest_path.to_owned(),
+                });
             }
+            return Err(e).at(Stage::Commit, dest_path);
         }
     };
+    Ok(())
+}
+
+/// Finish an add in move mode that was interrupted, using the journal it left behind.
+///
+/// Files that were already moved into the blobcas get linked back to where they came from,
+/// and then the add is simply done again (in move mode, and with the options the journal recorded) from the top.
+/// Everything that was moved the first time is already in the blobcas, so the second time through is cheap.
+/// (Note that this requires the rest of the originals to be where they were; if they've been changed since,
+/// the resulting tree hash will describe what's there now.)
+pub fn roll_forward(repo: &repo::Repo, journal_path: &Path) -> Result<gittree::Hash, GarError> {
+    let contents = journal::read(journal_path).at(Stage::Journal, journal_path)?;
+    for (path, blob_name) in &contents.moves {
+        let orig_path = contents.scan_root.join(path);
+        if orig_path.symlink_metadata().is_ok() {
+            // Recorded, but the move itself never happened.  Nothing to put back.
+            continue;
+        }
+        ok_if_exists(fs::hard_link(
+            repo.blobcas_path().join(blob_name),
+            &orig_path,
+        ))
+        .at(Stage::Journal, &orig_path)?;
+    }
+    let opts = options_from_journal(&contents.options, journal_path)?;
+    let report = add(repo, &contents.scan_root, &opts)?;
+    fs::remove_file(journal_path).at(Stage::Journal, journal_path)?;
+    Ok(report.hash)
+}
 
-    Ok(hash)
+/// The options of a move-mode add, as its journal records them, so `roll_forward` can do the add again the same way.
+/// (Not the thread count, which doesn't change the result; nor the stat cache or a wrap name, which move mode never uses.)
+fn journal_options(opts: &AddOptions) -> Vec<(&'static str, &OsStr)> {
+    let mut options = vec![
+        ("mode-policy", OsStr::new(opts.mode_policy.name())),
+        ("special-files", OsStr::new(opts.special_files.name())),
+        (
+            "no-index",
+            OsStr::new(if opts.no_index { "yes" } else { "no" }),
+        ),
+    ];
+    for pattern in &opts.excludes {
+        options.push(("exclude", pattern));
+    }
+    if let Some(label) = &opts.label {
+        options.push(("label", OsStr::new(label)));
+    }
+    options
 }
 
+/// The inverse of `journal_options`.
+fn options_from_journal(
+    options: &[(String, OsString)],
+    journal_path: &Path,
+) -> Result<AddOptions, GarError> {
+    let mut opts = AddOptions {
+        faithmode: FaithMode::Move,
+        ..Default::default()
+    };
+    for (name, value) in options {
+        let bad = || GarError::Corrupt {
+            path: journal_path.to_owned(),
+            reason: format!("bad option in journal: {} {:?}", name, value),
+        };
+        let value_str = value.to_str();
+        match name.as_str() {
+            "mode-policy" => {
+                opts.mode_policy = value_str.and_then(ModePolicy::from_name).ok_or_else(bad)?
+            }
+            "special-files" => {
+                opts.special_files = value_str
+                    .and_then(gittree::SpecialFilePolicy::from_name)
+                    .ok_or_else(bad)?
+            }
+            "no-index" => {
+                opts.no_index = match value_str {
+                    Some("yes") => true,
+                    Some("no") => false,
+                    _ => return Err(bad()),
+                }
+            }
+            "exclude" => opts.excludes.push(value.clone()),
+            "label" => opts.label = Some(value_str.ok_or_else(bad)?.to_owned()),
+            _ => return Err(bad()),
+        }
+    }
+    Ok(opts)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub enum FaithMode {
     /// Copy files into the blobstore while adding to gar.
     ///
@@ -106,6 +471,7 @@ pub enum FaithMode {
     /// Only do this when you're very sure the original files won't be mutated ever again.
     /// A mutation to the original will mutate all hardlinked files (they're not distinct files!),
     /// and that would result in a corruption of the gar blobcas and thereafter undefined (and bad) behavior.
+    #[default]
     LinkOriginals,
 
     /// Move originals into the blobstore while adding to gar.
@@ -114,70 +480,109 @@ pub enum FaithMode {
     /// This can be a suitable option if you made a file set entirely for the purpose of adding to gar.
     /// (A similar outcome is possible with `LinkOriginals` mode followed by a recursive rm,
     /// but `Move` mode will save on that handful of rm syscalls.)
+    ///
+    /// The originals must be on the same filesystem as the gar heap.
+    /// Only files are moved: directories and symlinks are left behind (they're not blobcas'd in the first place).
+    ///
+    /// An interruption part way through would leave the originals half-gone and the treecas incomplete,
+    /// so this mode writes an intent journal as it goes; see `roll_forward` for how to pick up the pieces.
     Move,
-    // On second thought, I don't really know why I'd want this "move" mode, because the performance gain is irrelevant,
-    // and the situation you'd end up in if there's an interruption of some kind would be quite unpleasant unless the
-    // subject directory structures were totally disposable.
 
-    // Some filesystems support a concept of "reflinking", which is the COW we've always wanted
-    // and *does* create new files with fresh attributes and no mutation craziness.
-    // Unfortunately, this is far from a universally available feature, and we're concerned with that.
+    /// Hardlink originals into the blobstore if possible, and copy them if not.
+    ///
+    /// Hardlinks can't cross filesystems, so this is for when the originals may or may not be on the same filesystem as the gar heap
+    /// (for example, build outputs on a tmpfs).  The same caveats as `LinkOriginals` apply to any files that do get linked.
+    LinkOrCopy,
+
+    /// Reflink originals into the blobstore while adding to gar.
+    ///
+    /// A reflink is a copy-on-write clone: it's a distinct file (so later edits to the original can't corrupt the blobcas),
+    /// but it costs no additional space until one of them is changed.
+    /// Only some filesystems (e.g. btrfs, XFS) support this, and only within one filesystem;
+    /// in this mode, the add fails if a reflink can't be made.
+    Reflink,
 
-    // Possible future work: It might be useful to have some more mode variants to describe situations like
-    // "hardlink if you can; but fall back to copy without erroring if it would be cross-device".
+    /// Reflink originals into the blobstore if possible, and copy them if not.
+    ReflinkOrCopy,
+}
+
+/// What to do about originals whose mode is anything other than exactly 0644 or 0755,
+/// in faith modes that put the original file itself into the blobcas (`LinkOriginals`, `LinkOrCopy`, and `Move`).
+///
+/// The blobcas file *is* the original in those modes, so it keeps the original's mode,
+/// and then so does every treecas entry that links to it.
+/// (Only the executable bit makes it into the hash, so this doesn't change any hashes.  It's just... untidy, or worse:
+/// a 0600 file in the blobcas is unreadable to anyone else who's allowed to read the heap.)
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ModePolicy {
+    /// Leave the mode as it is.
+    #[default]
+    Ignore,
+    /// Change the mode of the original to the normal one (0755 if any executable bit was set; 0644 otherwise).
+    Normalize,
+    /// Copy the file instead (which gets the normal mode), and leave the original alone.
+    Copy,
+    /// Stop the add with an error.
+    Refuse,
+}
+
+impl ModePolicy {
+    /// The name, as the `--mode-policy` flag spells it.
+    pub fn name(self) -> &'static str {
+        match self {
+            ModePolicy::Ignore => "ignore",
+            ModePolicy::Normalize => "normalize",
+            ModePolicy::Copy => "copy",
+            ModePolicy::Refuse => "refuse",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ignore" => Some(ModePolicy::Ignore),
+            "normalize" => Some(ModePolicy::Normalize),
+            "copy" => Some(ModePolicy::Copy),
+            "refuse" => Some(ModePolicy::Refuse),
+            _ => None,
+        }
+    }
 }
 
 /// Bundles up all the parameters we'd pass down in recursion.
 struct AddWork<'a> {
     repo: &'a repo::Repo,
-    repo_ino: u64,
+    blobcas_dev: u64,
     scan_root: &'a Path,
-    wiptree_root: &'a Path,
-    faithmode: FaithMode,
+    staging_root: &'a Path,
+    staging_seq: AtomicU64,
+    reflink_unsupported: bool,
+    exclusions: garignore::Exclusions,
+    opts: &'a AddOptions,
+    journal: Option<journal::MoveJournal>,
+    unlink_after_commit: Mutex<Vec<PathBuf>>,
+    stats: Mutex<AddStats>,
+    odd_modes: Mutex<Vec<(PathBuf, u32)>>,
+    skipped: Mutex<Vec<PathBuf>>,
+    /// Entries for the garidx, if we're writing one.
+    index: Option<Mutex<Vec<gittree::HashedEntry>>>,
+    /// The stat cache as it was when we started (if it's in use), and what this add has seen since (which replaces it at the end).
+    stat_cache: Option<statcache::StatCache>,
+    stat_cache_updates: Option<Mutex<statcache::StatCache>>,
+    /// When the add started, in seconds since the epoch.
+    started: i64,
 }
 
 impl AddWork<'_> {
-    /// Walk the filesystem.  Depth first.
-    /// Hardlink (or move, or copy, depending on faithmode) stuff into the repo's blobcas,
-    /// and then hardlink *that* it into wiptree.
-    /// Return the treehash (or blobhash) at every step
-    fn add_recurse(&self, path: &Path, path_meta: &fs::Metadata) -> io::Result<gittree::Hash> {
-        // TODO: this whole function is somewhat silly.  It is only used at the root, and even there does not do anything special.  Remove it.
-        let path_ft = path_meta.file_type();
-        if path_ft.is_file() {
-            self.add_recurse_file(path, path_meta)
-        } else if path_ft.is_symlink() {
-            self.add_recurse_symlink(path)
-        } else if path_ft.is_dir() {
-            self.add_recurse_dir(path)
-        } else {
-            panic!("unknown file type")
-        }
-    }
-
-    fn add_recurse_file(&self, path: &Path, path_meta: &fs::Metadata) -> io::Result<gittree::Hash> {
-        // Files:
-        // - First get it in the blobcas.
-        // - Then hardlink it into the treecas.
-        //
-        // The treecas is *always* a hardlink to the blobcas,
-        // and also we'll outright error if that gives a cross-device link, because what are you even doing.
-        //
-        // Getting it into the blobcas... that can happen a couple different ways; that's what the FaithMode param is about.
-
-        // TODO: some design choices still needed about metadata in the case of hardlinking.
-        // This is a little tricky to figure out, because we want:
-        //   - to support hardlinking the original, if requested
-        //   - but *maybe* we don't want to do that if the permissions differ from our normalized permissions
-        //   - or *maybe* we'll respond to non-normal permissions by normalizing them, if requested
-        //   - and then (orthagonal, except it might skip some of the other choices) there's the *maybe* fallback to copy if hardlink would be a cross-device link.
-
-        // Blobhash each file.  Gotta know where to put it.
-        // TODO: if we have to copy, would rather hold off on this so we don't read twice.
-        let hash = gittree::hash_of_stream(
-            &mut fs::File::open(self.scan_root.join(path))?,
-            path_meta.size(),
-        )?;
+    /// Get a file into the blobcas, by whatever means the faithmode says.
+    /// Returns its hash, and its path in the blobcas.
+    fn put_blob(
+        &self,
+        path: &Path,
+        path_meta: &fs::Metadata,
+    ) -> Result<(gittree::Hash, PathBuf), GarError> {
+        // When the original itself is going to be the blobcas file, its mode comes along with it.
+        // See `ModePolicy` for the choices about what to do when that mode is odd.
+        // (Falling back to copy if a hardlink would be a cross-device link is covered by `FaithMode::LinkOrCopy`.)
 
         // First: Populate the blobcas, either by copy or by hardlink.
         // There is one piece of data we have to represent in the blob name beyond the hash itself:
@@ -185,102 +590,1143 @@ impl AddWork<'_> {
         // we have to store the executable bit as a suffix of the blobhash.
         // (We can't just add executable bit back onto things in the treecas, because that's...
         // not how hardlinks work, unfortunately.  Oh how I wish it was!  But, nope.)
-        let attrib_suffix = if path_meta.permissions().mode() & 0o111 > 0 {
-            "-x"
-        } else {
-            ""
-        };
-        let blobcas_path = self.repo.blobcas_path().join(hash.as_hex() + attrib_suffix);
+        let executable = is_executable(path_meta);
+
+        // If the stat cache vouches for the file, and its blob is already in place, there's nothing to read or store at all.
+        if let Some(hash) = self.stat_cache_lookup(path_meta) {
+            let blobcas_path = self.repo.blobcas_path().join(blob_name(&hash, executable));
+            if blobcas_path.exists() {
+                self.stats.lock().unwrap().cached += 1;
+                // Seen again, so it stays in the cache.  (Only what an add saw is kept: see the end of `add`.)
+                if let Some(updates) = &self.stat_cache_updates {
+                    updates
+                        .lock()
+                        .unwrap()
+                        .record(path_meta, &hash, self.started);
+                }
+                return Ok((hash, blobcas_path));
+            }
+        }
+
         // BRANCH: are we in paranoia mode, or are we hardlinking orignals and trusting in a lack of mutation?
-        // Note that in the copy mode, we use `io::copy` rather than `fs::copy`, because the latter puts work into copying permissions, attribs, etc, and we have no need for that.
-        let blobcas_result: io::Result<_> = match self.faithmode {
-            FaithMode::Copy => {
-                todo!("i sure wish our hashing and copying could work on one read pass")
-                // io::copy(reader, writer)
-                // ... `io_tee` crate with `TeeReader` ...?
+        let result = match self.opts.faithmode {
+            FaithMode::Copy => self.copy_original(path, executable)?,
+            FaithMode::LinkOriginals => {
+                if self.apply_mode_policy(path, path_meta, executable)? {
+                    self.copy_original(path, executable)?
+                } else {
+                    self.link_original(path, path_meta, executable)?
+                }
             }
-            FaithMode::LinkOriginals => fs::hard_link(self.scan_root.join(path), &blobcas_path),
-            FaithMode::Move => todo!(),
-            // REVIEW: what do if permissions aren't normal?  Copy instead?  Add a mode for halt-if-not-ezlinkable?
-        };
-        match blobcas_result {
-            Ok(_) => {} // cool
-            Err(e) => {
-                if e.kind() == io::ErrorKind::AlreadyExists {
-                    // cool
+            FaithMode::LinkOrCopy => {
+                // If we can tell up front that it's on another device, don't bother hashing it just to fail the link.
+                // (Bind mounts can still give us EXDEV even with the same st_dev, though, so check the result too.)
+                // And the mode policy only matters if we'd be linking.
+                if path_meta.dev() != self.blobcas_dev
+                    || self.apply_mode_policy(path, path_meta, executable)?
+                {
+                    self.copy_original(path, executable)?
+                } else {
+                    match self.link_original(path, path_meta, executable) {
+                        Err(GarError::CrossDevice { .. }) => {
+                            self.copy_original(path, executable)?
+                        }
+                        result => result?,
+                    }
+                }
+            }
+            FaithMode::Reflink => self.reflink_original(path, executable)?,
+            FaithMode::ReflinkOrCopy => {
+                if self.reflink_unsupported {
+                    self.copy_original(path, executable)?
                 } else {
-                    return Err(e);
+                    match self.reflink_original(path, executable) {
+                        Err(GarError::ReflinkUnsupported { .. }) => {
+                            // Could be just this file (e.g. it's on another filesystem), so don't give up on reflinks entirely.
+                            self.copy_original(path, executable)?
+                        }
+                        result => result?,
+                    }
+                }
+            }
+            FaithMode::Move => {
+                let copy_instead = self.apply_mode_policy(path, path_meta, executable)?;
+                self.move_original(path, path_meta, executable, copy_instead)?
+            }
+        };
+        self.stat_cache_record(path, path_meta, &result.0)?;
+        Ok(result)
+    }
+
+    /// Look a file up in the stat cache, if we're trusting it.
+    /// (Never in move mode: the original has to be dealt with whether we know its hash or not.)
+    fn stat_cache_lookup(&self, path_meta: &fs::Metadata) -> Option<gittree::Hash> {
+        if self.opts.stat_cache != statcache::StatCacheMode::Trust
+            || self.opts.faithmode == FaithMode::Move
+        {
+            return None;
+        }
+        self.stat_cache.as_ref()?.lookup(path_meta).cloned()
+    }
+
+    /// Record a file we've just stored in the stat cache, if we're updating it.
+    ///
+    /// It's stat'd again first, and only recorded if its size and mtime are the same as before we read it:
+    /// otherwise it changed under us, and there's no telling which version the hash describes.
+    /// The fresh stat is the one recorded, since storing the file can change its ctime (hardlinking does, for one).
+    fn stat_cache_record(
+        &self,
+        path: &Path,
+        path_meta: &fs::Metadata,
+        hash: &gittree::Hash,
+    ) -> Result<(), GarError> {
+        let updates = match &self.stat_cache_updates {
+            Some(updates) if self.opts.faithmode != FaithMode::Move => updates,
+            _ => return Ok(()),
+        };
+        let orig_path = self.scan_root.join(path);
+        let meta = fs::symlink_metadata(&orig_path).at(Stage::Scan, &orig_path)?;
+        let unchanged = meta.dev() == path_meta.dev()
+            && meta.ino() == path_meta.ino()
+            && meta.size() == path_meta.size()
+            && (meta.mtime(), meta.mtime_nsec()) == (path_meta.mtime(), path_meta.mtime_nsec());
+        if unchanged {
+            updates.lock().unwrap().record(&meta, hash, self.started);
+        }
+        Ok(())
+    }
+
+    /// Remember an entry for the garidx (if we're writing one).
+    fn index_entry(&self, path: &Path, kind: EntryKind, size: Option<u64>, hash: &gittree::Hash) {
+        if let Some(index) = &self.index {
+            index.lock().unwrap().push(gittree::HashedEntry {
+                path: path.to_owned(),
+                kind,
+                size,
+                hash: hash.clone(),
+            });
+        }
+    }
+
+    /// Deal with an original's mode, if it's odd and we're about to put it in the blobcas as-is.
+    /// Returns true if the policy says to copy it instead.
+    fn apply_mode_policy(
+        &self,
+        path: &Path,
+        path_meta: &fs::Metadata,
+        executable: bool,
+    ) -> Result<bool, GarError> {
+        let mode = path_meta.permissions().mode() & 0o7777;
+        let normal_mode = if executable { 0o755 } else { 0o644 };
+        if mode == normal_mode {
+            return Ok(false);
+        }
+        match self.opts.mode_policy {
+            ModePolicy::Ignore => return Ok(false),
+            ModePolicy::Normalize => {
+                let orig_path = self.scan_root.join(path);
+                fs::set_permissions(&orig_path, fs::Permissions::from_mode(normal_mode))
+                    .at(Stage::Blobcas, &orig_path)?
+            }
+            ModePolicy::Copy => {}
+            ModePolicy::Refuse => {
+                return Err(GarError::OddMode {
+                    path: self.scan_root.join(path),
+                    mode,
+                })
+            }
+        }
+        self.odd_modes.lock().unwrap().push((path.to_owned(), mode));
+        Ok(self.opts.mode_policy == ModePolicy::Copy)
+    }
+
+    /// Move an original into the blobcas (or copy it, and then remove it, if `copy_instead`).
+    /// Returns the hash and the blobcas path.
+    fn move_original(
+        &self,
+        path: &Path,
+        path_meta: &fs::Metadata,
+        executable: bool,
+        copy_instead: bool,
+    ) -> Result<(gittree::Hash, PathBuf), GarError> {
+        let orig_path = self.scan_root.join(path);
+        let journal = match &self.journal {
+            Some(journal) => journal,
+            None => {
+                // No journal means this is the single-file case.  With only one file, it's simpler to be safe
+                // by putting it in the blobcas without removing it, and removing it only once everything's committed.
+                if copy_instead {
+                    let result = self.copy_original(path, executable)?;
+                    self.unlink_after_commit.lock().unwrap().push(orig_path);
+                    return Ok(result);
                 }
+                let hash = hash_file(self.repo.object_format(), &orig_path, path_meta.size())?;
+                let blobcas_path = self.repo.blobcas_path().join(blob_name(&hash, executable));
+                ok_if_exists(fs::hard_link(&orig_path, &blobcas_path))
+                    .at(Stage::Blobcas, &orig_path)?;
+                self.unlink_after_commit.lock().unwrap().push(orig_path);
+                self.stats.lock().unwrap().moved += 1;
+                return Ok((hash, blobcas_path));
             }
         };
+        if copy_instead {
+            // The copy is already committed in the blobcas by the time we record it,
+            // so the original isn't in any danger until after the journal knows where it went.
+            let (hash, blobcas_path) = self.copy_original(path, executable)?;
+            journal
+                .record(path, &blob_name(&hash, executable))
+                .at(Stage::Journal, &orig_path)?;
+            fs::remove_file(&orig_path).at(Stage::Blobcas, &orig_path)?;
+            return Ok((hash, blobcas_path));
+        }
+        let hash = hash_file(self.repo.object_format(), &orig_path, path_meta.size())?;
+        let blob_name = blob_name(&hash, executable);
+        let blobcas_path = self.repo.blobcas_path().join(&blob_name);
+        // Write down what we're about to do before we do it.
+        journal
+            .record(path, &blob_name)
+            .at(Stage::Journal, &orig_path)?;
+        // Hardlink then unlink, rather than rename: a rename would replace a blob that's already there
+        // (swapping out the inode that existing treecas entries share), and checking first would race
+        // with any other worker or add putting the same blob in meanwhile.
+        // If the blob's already there, the link is a no-op, and we simply don't need this copy of it.
+        ok_if_exists(fs::hard_link(&orig_path, &blobcas_path))
+            .and_then(|_| fs::remove_file(&orig_path))
+            .at(Stage::Blobcas, &orig_path)?;
+        self.stats.lock().unwrap().moved += 1;
+        Ok((hash, blobcas_path))
+    }
+
+    /// Blobhash an original and hardlink it into the blobcas.
+    /// Returns the hash and the blobcas path.
+    fn link_original(
+        &self,
+        path: &Path,
+        path_meta: &fs::Metadata,
+        executable: bool,
+    ) -> Result<(gittree::Hash, PathBuf), GarError> {
+        // Blobhash the original.  Gotta know where to put it.
+        let orig_path = self.scan_root.join(path);
+        let hash = hash_file(self.repo.object_format(), &orig_path, path_meta.size())?;
+        let blobcas_path = self.repo.blobcas_path().join(blob_name(&hash, executable));
+        ok_if_exists(fs::hard_link(&orig_path, &blobcas_path)).at(Stage::Blobcas, &orig_path)?;
+        self.stats.lock().unwrap().linked += 1;
+        Ok((hash, blobcas_path))
+    }
+
+    /// Copy an original into the blobcas (via the staging dir).
+    /// Returns the hash and the blobcas path.
+    fn copy_original(
+        &self,
+        path: &Path,
+        executable: bool,
+    ) -> Result<(gittree::Hash, PathBuf), GarError> {
+        let (hash, staged_path) = self.copy_to_staging(path, executable)?;
+        let blobcas_path = self.repo.blobcas_path().join(blob_name(&hash, executable));
+        self.commit_staged(&staged_path, &blobcas_path)?;
+        self.stats.lock().unwrap().copied += 1;
+        Ok((hash, blobcas_path))
+    }
+
+    /// Reflink an original into the blobcas (via the staging dir).
+    /// Returns the hash and the blobcas path.
+    fn reflink_original(
+        &self,
+        path: &Path,
+        executable: bool,
+    ) -> Result<(gittree::Hash, PathBuf), GarError> {
+        let (hash, staged_path) = self.stage(executable, |dst| {
+            let orig_path = self.scan_root.join(path);
+            let orig = fs::File::open(&orig_path).at(Stage::Hash, &orig_path)?;
+            reflink::clone_file(&orig, dst).map_err(|e| match reflink::is_unsupported(&e) {
+                true => GarError::ReflinkUnsupported { path: orig_path },
+                false => GarError::Io {
+                    stage: Stage::Blobcas,
+                    path: orig_path,
+                    source: e,
+                },
+            })
+        })?;
+        let blobcas_path = self.repo.blobcas_path().join(blob_name(&hash, executable));
+        self.commit_staged(&staged_path, &blobcas_path)?;
+        self.stats.lock().unwrap().reflinked += 1;
+        Ok((hash, blobcas_path))
+    }
+
+    /// Copy a file from the scan into a fresh file in the staging dir, and blobhash it.
+    /// Returns the hash and the path of the staged file, which the caller should then commit.
+    ///
+    /// The hash is computed by reading back the staged copy, not the original.
+    /// This is deliberate: `benches/direct_copy.rs` shows that letting `io::copy` use the kernel's copy paths
+    /// and then reading the copy (which is hot in cache) is on par with or faster than teeing into the hasher in userspace.
+    /// It also means the original is only read once, and the hash always describes exactly what we stored,
+    /// even if someone is mutating the original while we work.
+    fn copy_to_staging(
+        &self,
+        path: &Path,
+        executable: bool,
+    ) -> Result<(gittree::Hash, PathBuf), GarError> {
+        // Note that we use `io::copy` rather than `fs::copy`, because the latter puts work into copying permissions, attribs, etc, and we have no need for that.
+        self.stage(executable, |mut dst| {
+            let orig_path = self.scan_root.join(path);
+            let mut orig = fs::File::open(&orig_path).at(Stage::Hash, &orig_path)?;
+            io::copy(&mut orig, &mut dst)
+                .map(|_| ())
+                .at(Stage::Blobcas, &orig_path)
+        })
+    }
+
+    /// Create a fresh file in the staging dir, let `fill` put the content in it, and then blobhash it.
+    /// Returns the hash and the path of the staged file, which the caller should then commit.
+    fn stage(
+        &self,
+        executable: bool,
+        fill: impl FnOnce(&fs::File) -> Result<(), GarError>,
+    ) -> Result<(gittree::Hash, PathBuf), GarError> {
+        let seq = self.staging_seq.fetch_add(1, Ordering::Relaxed);
+        let staged_path = self.staging_root.join(seq.to_string());
+
+        // Permissions are set to our normalized ones explicitly (the mode given at open time is subject to umask).
+        let mode = if executable { 0o755 } else { 0o644 };
+        let dst = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&staged_path)
+            .at(Stage::Blobcas, &staged_path)?;
+        fill(&dst)?;
+        let size = dst
+            .set_permissions(fs::Permissions::from_mode(mode))
+            .and_then(|_| dst.metadata())
+            .at(Stage::Blobcas, &staged_path)?
+            .len();
+        drop(dst);
 
-        // Second: hardlink a new entry in the treecas to the blobcas.
-        fs::hard_link(&blobcas_path, self.wiptree_root.join(path))?;
+        let hash = fs::File::open(&staged_path)
+            .and_then(|mut f| gittree::hash_of_stream(self.repo.object_format(), &mut f, size))
+            .at(Stage::Blobcas, &staged_path)?;
+        Ok((hash, staged_path))
+    }
 
-        // And return the hash so dir treehashing can accumulate.
-        Ok(hash)
+    /// Move a staged file into its place in the blobcas.
+    /// If the blob is already there, the staged file is just discarded.
+    ///
+    /// This is done with a hardlink and an unlink rather than a rename,
+    /// because rename would silently replace an existing blob with a new inode,
+    /// and then existing treecas entries wouldn't share it anymore.
+    fn commit_staged(&self, staged_path: &Path, blobcas_path: &Path) -> Result<(), GarError> {
+        ok_if_exists(fs::hard_link(staged_path, blobcas_path))
+            .and_then(|_| fs::remove_file(staged_path))
+            .at(Stage::Blobcas, blobcas_path)
     }
 
-    fn add_recurse_symlink(&self, path: &Path) -> io::Result<gittree::Hash> {
+    /// Returns the symlink's hash, and its target.
+    fn add_recurse_symlink(&self, path: &Path) -> Result<(gittree::Hash, PathBuf), GarError> {
         // There's no point in blobcas'ing these, but we still do need their git hash to construct tree IDs, so do so.
         // TODO make `gittree::hash_of_symlink` return the body too so we can DRY this better
-        let target = fs::read_link(self.scan_root.join(path))?;
-        let mut foo = target.as_os_str().as_encoded_bytes();
-        let size = foo.len().try_into().expect("int size nonsense");
-        let hash = gittree::hash_of_stream(&mut foo, size)?;
+        let orig_path = self.scan_root.join(path);
+        let target = fs::read_link(&orig_path).at(Stage::Scan, &orig_path)?;
+        let mut body = target.as_os_str().as_encoded_bytes();
+        let size = body.len().try_into().expect("int size nonsense");
+        let hash = gittree::hash_of_stream(self.repo.object_format(), &mut body, size)
+            .at(Stage::Hash, &orig_path)?;
 
-        // Make a new symlink in the treecas output dir.
-        std::os::unix::fs::symlink(target, self.wiptree_root.join(path))?;
-
-        // And return the hash so dir treehashing can accumulate.
-        Ok(hash)
+        // Return the hash so dir treehashing can accumulate, and the target so the wiptree can have a symlink made later.
+        Ok((hash, target))
     }
 
-    fn add_recurse_dir(&self, path: &Path) -> io::Result<gittree::Hash> {
+    /// `rules` are the exclusion rules from `.garignore` files in parent dirs.
+    /// Returns the dir's tree hash, and what was found in it.
+    fn add_recurse_dir(
+        &self,
+        path: &Path,
+        rules: &garignore::Rules,
+    ) -> Result<(gittree::Hash, Vec<Found>), GarError> {
         // Begin to walk.
-        // Sort all entries first; we need to form the tree data this way.
         let scan_path = self.scan_root.join(path);
-        let mut entries = fs::read_dir(scan_path)?.collect::<Result<Vec<_>, io::Error>>()?;
-        entries.sort_by(|a, b| a.path().partial_cmp(&b.path()).unwrap());
+
+        // If this dir has a `.garignore` of its own, its rules apply here and below.
+        let rules = garignore::rules_in(rules, &scan_path, path)?;
+        let entries = fs::read_dir(&scan_path)
+            .and_then(|rd| rd.collect::<Result<Vec<_>, io::Error>>())
+            .at(Stage::Scan, &scan_path)?;
+
+        // Hash and store all the entries -- in parallel, on whatever pool we've been `install`ed in.
+        // The work can finish in any order, and that's fine: the accumulator puts entries in git's order itself,
+        // so the tree comes out byte-for-byte the same no matter what.
+        // (Subdirs recurse in here too, so the pool is shared by the whole walk, and its size bounds the whole thing.)
+        let children = entries
+            .par_iter()
+            .map(|ent| self.add_recurse_entry(path, ent, &rules))
+            .collect::<Result<Vec<_>, GarError>>()?;
+        let children: Vec<Found> = children.into_iter().flatten().collect();
 
         // Accumulation begins.
-        let mut tha = gittree::TreeHashAccumulator::new(entries.len());
-
-        for ent in entries {
-            let ft = ent.file_type()?;
-            let file_name = ent.file_name(); // for lifetime purposes.
-            let fnb = file_name.as_os_str().as_encoded_bytes();
-
-            if ft.is_file() {
-                let hash = self.add_recurse_file(&path.join(&file_name), &ent.metadata()?)?;
-                if ent.metadata()?.permissions().mode() & 0o111 > 0 {
-                    // TODO: we should probably normalize that if any of those bits are set, all of them are set.
-                    // Among other defensible normalizations that would certainly occur during a copy.
-                    tha.append_executable(fnb, &hash);
-                } else {
-                    tha.append_file(fnb, &hash);
-                }
+        let mut tha = gittree::TreeHashAccumulator::new(self.repo.object_format(), children.len());
+        for child in &children {
+            tha.append(child.kind, child.name.as_encoded_bytes(), &child.hash);
+        }
+        // Keep the tree object, too.  (See the `treeobj` module.)
+        let (hash, payload) = tha.finish_with_payload();
+        treeobj::store(self.repo, &hash, &payload)?;
+        Ok((hash, children))
+    }
+
+    /// Add one entry of a dir.  Returns None if it's to be skipped.
+    fn add_recurse_entry(
+        &self,
+        path: &Path,
+        ent: &fs::DirEntry,
+        rules: &garignore::Rules,
+    ) -> Result<Option<Found>, GarError> {
+        // (Stat before adding: in move mode, the original is gone afterwards.)
+        let meta = ent.metadata().at(Stage::Scan, &ent.path())?;
+        let ft = meta.file_type();
+        let file_name = ent.file_name();
+
+        // Excluded things never get anywhere near the blobcas (or the tree).
+        // (Nor does the repo itself, if we encounter it.  This is not super uncommon: "gar add ." is generally expected to DTRT.)
+        let child_path = path.join(&file_name);
+        if self.exclusions.excludes(rules, &child_path, &meta) {
+            return Ok(None);
+        }
+
+        let (kind, size, hash, content) = if ft.is_file() {
+            // Files only need to get into the blobcas.
+            // Getting them there can happen a couple different ways; that's what the FaithMode param is about.
+            let (hash, _) = self.put_blob(&child_path, &meta)?;
+            // (Any executable bit counts.  See `ModePolicy` for how the rest of the mode gets tidied up.)
+            let kind = match is_executable(&meta) {
+                true => EntryKind::Executable,
+                false => EntryKind::File,
+            };
+            (kind, Some(meta.len()), hash, FoundContent::Blob)
+        } else if ft.is_symlink() {
+            // (The size of a symlink is the length of its target, which is exactly its blob's body.)
+            let size = meta.len();
+            let (hash, target) = self.add_recurse_symlink(&child_path)?;
+            (
+                EntryKind::Symlink,
+                Some(size),
+                hash,
+                FoundContent::Symlink(target),
+            )
+        } else if ft.is_dir() {
+            // Recurse.
+            let (hash, children) = self.add_recurse_dir(&child_path, rules)?;
+            (EntryKind::Dir, None, hash, FoundContent::Dir(children))
+        } else {
+            // Something a tree can't hold.  (The same policy applies to it as in `gittree::hash_of_path_with_policy`.)
+            let mut skipped = self.skipped.lock().unwrap();
+            self.opts
+                .special_files
+                .handle(&self.scan_root.join(&child_path), &mut skipped)?;
+            return Ok(None);
+        };
+        self.index_entry(&child_path, kind, size, &hash);
+        Ok(Some(Found {
+            name: file_name,
+            kind,
+            hash,
+            content,
+        }))
+    }
+}
+
+/// One entry the walk found, with everything needed to build it into a wiptree later, without looking at the source again.
+pub(crate) struct Found {
+    pub(crate) name: OsString,
+    pub(crate) kind: EntryKind,
+    pub(crate) hash: gittree::Hash,
+    pub(crate) content: FoundContent,
+}
+
+pub(crate) enum FoundContent {
+    /// A file (executable or not).  Where it is in the blobcas follows from its hash and kind.
+    Blob,
+    /// A symlink, and its target.
+    Symlink(PathBuf),
+    /// A dir, and its entries.
+    Dir(Vec<Found>),
+}
+
+/// Build a dir of the wiptree: hardlinks to the blobcas for files, new symlinks, and subdirs, recursively.
+/// Entries are made in parallel, on whatever pool we've been `install`ed in.
+pub(crate) fn materialize(repo: &repo::Repo, dir: &Path, found: &[Found]) -> Result<(), GarError> {
+    found.par_iter().try_for_each(|ent| {
+        let wip_path = dir.join(&ent.name);
+        match &ent.content {
+            // The treecas is *always* a hardlink to the blobcas,
+            // and also we'll outright error if that gives a cross-device link, because what are you even doing.
+            FoundContent::Blob => {
+                let executable = ent.kind == EntryKind::Executable;
+                let blobcas_path = repo.blobcas_path().join(blob_name(&ent.hash, executable));
+                fs::hard_link(&blobcas_path, &wip_path).at(Stage::Wiptree, &wip_path)
+            }
+            FoundContent::Symlink(target) => {
+                std::os::unix::fs::symlink(target, &wip_path).at(Stage::Wiptree, &wip_path)
+            }
+            FoundContent::Dir(children) => {
+                fs::create_dir(&wip_path).at(Stage::Wiptree, &wip_path)?;
+                materialize(repo, &wip_path, children)
+            }
+        }
+    })
+}
+
+/// Any executable bit counts.  (Git only has the one bit's worth of difference.)
+fn is_executable(meta: &fs::Metadata) -> bool {
+    meta.permissions().mode() & 0o111 > 0
+}
+
+/// Blobhash a source file.
+fn hash_file(
+    format: gittree::ObjectFormat,
+    path: &Path,
+    size: u64,
+) -> Result<gittree::Hash, GarError> {
+    fs::File::open(path)
+        .and_then(|mut f| gittree::hash_of_stream(format, &mut f, size))
+        .at(Stage::Hash, path)
+}
+
+/// The name of a blob in the blobcas: its hash, plus a suffix if it's executable.
+pub(crate) fn blob_name(hash: &gittree::Hash, executable: bool) -> String {
+    hash.as_hex() + if executable { "-x" } else { "" }
+}
+
+/// Blobcas entries are content-addressed, so if one is already there, that's fine:
+/// someone else already put the same content there.
+pub(crate) fn ok_if_exists(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALPHA_HASH: &str = "9024a7f8afa43db06ff2b50d9ac9c21b791bee49d8092d3f14f1e433bfd927fa";
+
+    fn fresh_repo(td: &tempdir::TempDir) -> repo::Repo {
+        let r = repo::Repo::new(td.path()).expect("repo");
+        r.create_dir_all().expect("creating repo dirs");
+        r
+    }
+
+    fn faith(faithmode: FaithMode) -> AddOptions {
+        AddOptions {
+            faithmode,
+            ..Default::default()
+        }
+    }
+
+    /// Make a scratch copy of a fixture, for tests of modes that are destructive to their source.
+    fn copy_fixture(src: &Path, dst: &Path) {
+        fs::create_dir(dst).unwrap();
+        for ent in fs::read_dir(src).unwrap() {
+            let ent = ent.unwrap();
+            let ft = ent.file_type().unwrap();
+            let dst = dst.join(ent.file_name());
+            if ft.is_dir() {
+                copy_fixture(&ent.path(), &dst);
             } else if ft.is_symlink() {
-                let hash = self.add_recurse_symlink(&path.join(&file_name))?;
-                tha.append_symlink(fnb, &hash);
-            } else if ft.is_dir() {
-                // Special case: if we're encounter the repo itself: do not add that!
-                // (This is not super uncommon: "gar add ." is generally expected to DTRT.)
-                if ent.metadata()?.ino() == self.repo_ino {
-                    continue;
+                std::os::unix::fs::symlink(fs::read_link(ent.path()).unwrap(), dst).unwrap();
+            } else {
+                // (Normalize the mode while we're at it: the fixtures have whatever mode git checked them out with.)
+                fs::copy(ent.path(), &dst).unwrap();
+                fs::set_permissions(&dst, fs::Permissions::from_mode(0o644)).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_add_copy() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let report = add(&r, "fixtures/alpha", &faith(FaithMode::Copy)).expect("add to succeed");
+        assert_eq!(report.hash.as_hex(), ALPHA_HASH);
+        assert_eq!(report.stats.copied, 4);
+
+        // The blob must be a copy, not the original.
+        let blob_path = r
+            .blobcas_path()
+            .join("2909489adcb095aa795a9a7e6d92db735d0a0ced0782c43496675bdb7beec3ce");
+        let blob_meta = fs::metadata(&blob_path).expect("blob to exist");
+        assert_ne!(
+            blob_meta.ino(),
+            fs::metadata("fixtures/alpha/a_file").unwrap().ino()
+        );
+        assert_eq!(blob_meta.permissions().mode() & 0o777, 0o644);
+        assert_eq!(fs::read(&blob_path).unwrap(), b"a file\n");
+
+        // No staging leftovers.
+        let leftovers: Vec<_> = fs::read_dir(r.blobcas_path())
+            .unwrap()
+            .filter_map(|ent| ent.ok())
+            .filter(|ent| ent.file_name().as_encoded_bytes().starts_with(b"."))
+            .collect();
+        assert!(leftovers.is_empty());
+
+        // Adding again is fine, and converges on the same thing.
+        let report = add(&r, "fixtures/alpha", &faith(FaithMode::Copy)).expect("re-add to succeed");
+        assert_eq!(report.hash.as_hex(), ALPHA_HASH);
+    }
+
+    #[test]
+    fn test_add_move() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let scratch = td.path().join("scratch");
+        copy_fixture(Path::new("fixtures/alpha"), &scratch);
+
+        let report = add(&r, &scratch, &faith(FaithMode::Move)).expect("add to succeed");
+        assert_eq!(report.hash.as_hex(), ALPHA_HASH);
+
+        // Files are gone from the source (samefile too, even though its blob was already there); the rest is left.
+        assert!(!scratch.join("a_file").exists());
+        assert!(!scratch.join("a_dir/deeper/samefile").exists());
+        assert!(scratch.join("a_dir/deeper").is_dir());
+        // And the journal was cleaned up.
+        assert!(journal::list(&r).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_roll_forward() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let scratch = td.path().join("scratch");
+        copy_fixture(Path::new("fixtures/alpha"), &scratch);
+
+        // Fake up a crash just after the first file got moved.
+        let blob_name = "2909489adcb095aa795a9a7e6d92db735d0a0ced0782c43496675bdb7beec3ce";
+        let j =
+            journal::MoveJournal::create(&r, &scratch, &journal_options(&faith(FaithMode::Move)))
+                .unwrap();
+        j.record(Path::new("a_file"), blob_name).unwrap();
+        fs::rename(scratch.join("a_file"), r.blobcas_path().join(blob_name)).unwrap();
+        drop(j);
+
+        let journals = journal::list(&r).unwrap();
+        assert_eq!(journals.len(), 1);
+        let hash = roll_forward(&r, &journals[0]).expect("roll forward to succeed");
+        assert_eq!(hash.as_hex(), ALPHA_HASH);
+        assert!(r.treecas_path().join(ALPHA_HASH).join("a_file").exists());
+        assert!(!scratch.join("a_file").exists());
+        assert!(journal::list(&r).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_roll_forward_keeps_options() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let scratch = td.path().join("scratch");
+        copy_fixture(Path::new("fixtures/alpha"), &scratch);
+        fs::create_dir(scratch.join("target")).unwrap();
+        fs::write(scratch.join("target/build.out"), b"not for the heap\n").unwrap();
+        let opts = AddOptions {
+            faithmode: FaithMode::Move,
+            excludes: vec!["target".into()],
+            label: Some("nightly".to_owned()),
+            ..Default::default()
+        };
+
+        // Fake up a crash just after the first file got moved, in an add that excluded something.
+        let blob_name = "2909489adcb095aa795a9a7e6d92db735d0a0ced0782c43496675bdb7beec3ce";
+        let j = journal::MoveJournal::create(&r, &scratch, &journal_options(&opts)).unwrap();
+        j.record(Path::new("a_file"), blob_name).unwrap();
+        fs::rename(scratch.join("a_file"), r.blobcas_path().join(blob_name)).unwrap();
+        drop(j);
+
+        // Finishing it leaves the excluded things where they were, and does everything else the add would have.
+        let journals = journal::list(&r).unwrap();
+        let hash = roll_forward(&r, &journals[0]).expect("roll forward to succeed");
+        assert_eq!(hash.as_hex(), ALPHA_HASH);
+        assert_eq!(
+            fs::read(scratch.join("target/build.out")).unwrap(),
+            b"not for the heap\n"
+        );
+        assert!(!scratch.join("a_file").exists());
+        assert_eq!(labels::get(&r, "nightly").unwrap(), Some(hash));
+    }
+
+    #[test]
+    fn test_add_link_or_copy() {
+        // Same device as the fixtures: everything gets linked.
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let report =
+            add(&r, "fixtures/alpha", &faith(FaithMode::LinkOrCopy)).expect("add to succeed");
+        assert_eq!(report.hash.as_hex(), ALPHA_HASH);
+        assert_eq!(
+            report.stats,
+            AddStats {
+                linked: 4,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_add_link_or_copy_cross_device() {
+        // Another device: everything gets copied instead.
+        // Only testable if there's a tmpfs handy at /dev/shm, on a different filesystem than the fixtures;
+        // where there isn't, there's nothing to test, and this passes without doing anything.
+        let shm = Path::new("/dev/shm");
+        let fixtures_dev = fs::metadata("fixtures").unwrap().dev();
+        if fs::metadata(shm).map_or(true, |m| !m.is_dir() || m.dev() == fixtures_dev) {
+            return;
+        }
+        let td = tempdir::TempDir::new_in(shm, "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let report =
+            add(&r, "fixtures/alpha", &faith(FaithMode::LinkOrCopy)).expect("add to succeed");
+        assert_eq!(report.hash.as_hex(), ALPHA_HASH);
+        assert_eq!(
+            report.stats,
+            AddStats {
+                copied: 4,
+                ..Default::default()
+            }
+        );
+
+        // Whereas plain linking can't, and says exactly why.
+        // (Into a fresh heap: blobs that are already there don't need linking, so they wouldn't fail.)
+        let td = tempdir::TempDir::new_in(shm, "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let err = add(&r, "fixtures/alpha", &faith(FaithMode::LinkOriginals))
+            .expect_err("cross-device links to fail");
+        assert!(
+            matches!(err, GarError::CrossDevice { ref path, .. } if path.starts_with("fixtures/alpha"))
+        );
+        assert_eq!(err.exit_code(), 12);
+    }
+
+    #[test]
+    fn test_add_reflink() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+
+        // The lenient mode works everywhere.
+        let report =
+            add(&r, "fixtures/alpha", &faith(FaithMode::ReflinkOrCopy)).expect("add to succeed");
+        assert_eq!(report.hash.as_hex(), ALPHA_HASH);
+        assert_eq!(report.stats.reflinked + report.stats.copied, 4);
+
+        // The strict mode works exactly when the filesystem can do reflinks, and says so clearly when it can't.
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let result = add(&r, "fixtures/alpha", &faith(FaithMode::Reflink));
+        if reflink::probe(r.blobcas_path()).unwrap() {
+            let report = result.expect("add to succeed");
+            assert_eq!(report.hash.as_hex(), ALPHA_HASH);
+            assert_eq!(
+                report.stats,
+                AddStats {
+                    reflinked: 4,
+                    ..Default::default()
                 }
+            );
+        } else {
+            let err = result.expect_err("strict mode to refuse");
+            assert!(matches!(err, GarError::ReflinkUnsupported { .. }));
+            assert_eq!(err.exit_code(), 13);
+        }
+    }
 
-                // Go ahead and make the path of the same name in the temp new treecas.
-                // (This happens here because at the very root, we don't need to do it, because we started with a tempdir there already.)
-                let wip_path = self.wiptree_root.join(path.join(&file_name));
-                std::fs::create_dir(wip_path)?;
-                // Recurse.
-                let hash = self.add_recurse_dir(&path.join(&file_name))?;
-                tha.append_dir(fnb, &hash);
-            } else {
-                panic!("unknown file type")
+    #[test]
+    fn test_mode_policy() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let scratch = td.path().join("scratch");
+        copy_fixture(Path::new("fixtures/alpha"), &scratch);
+        let odd_path = scratch.join("a_dir/other_file");
+        let opts = |mode_policy| AddOptions {
+            mode_policy,
+            ..Default::default()
+        };
+
+        // Refuse.
+        fs::set_permissions(&odd_path, fs::Permissions::from_mode(0o600)).unwrap();
+        let r = repo::Repo::new(td.path().join("refuse")).unwrap();
+        r.create_dir_all().unwrap();
+        let err = add(&r, &scratch, &opts(ModePolicy::Refuse)).expect_err("add to refuse");
+        assert!(matches!(err, GarError::OddMode { mode: 0o600, .. }));
+
+        // Copy.
+        let r = repo::Repo::new(td.path().join("copy")).unwrap();
+        r.create_dir_all().unwrap();
+        let report = add(&r, &scratch, &opts(ModePolicy::Copy)).expect("add to succeed");
+        assert_eq!(report.hash.as_hex(), ALPHA_HASH);
+        assert_eq!(
+            report.stats,
+            AddStats {
+                linked: 3,
+                copied: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            report.odd_modes,
+            vec![(PathBuf::from("a_dir/other_file"), 0o600)]
+        );
+        assert_eq!(
+            fs::metadata(&odd_path).unwrap().permissions().mode() & 0o7777,
+            0o600
+        );
+
+        // Normalize.
+        let r = repo::Repo::new(td.path().join("normalize")).unwrap();
+        r.create_dir_all().unwrap();
+        let report = add(&r, &scratch, &opts(ModePolicy::Normalize)).expect("add to succeed");
+        assert_eq!(report.hash.as_hex(), ALPHA_HASH);
+        assert_eq!(
+            report.stats,
+            AddStats {
+                linked: 4,
+                ..Default::default()
             }
+        );
+        assert_eq!(
+            report.odd_modes,
+            vec![(PathBuf::from("a_dir/other_file"), 0o600)]
+        );
+        assert_eq!(
+            fs::metadata(&odd_path).unwrap().permissions().mode() & 0o7777,
+            0o644
+        );
+    }
+
+    #[test]
+    fn test_add_single_file() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+
+        // Just a blob.  Nothing in the treecas.
+        let report =
+            add(&r, "fixtures/alpha/a_file", &faith(FaithMode::Copy)).expect("add to succeed");
+        assert_eq!(
+            report.hash.as_hex(),
+            "2909489adcb095aa795a9a7e6d92db735d0a0ced0782c43496675bdb7beec3ce"
+        );
+        assert!(r.blobcas_path().join(report.hash.as_hex()).is_file());
+        assert_eq!(fs::read_dir(r.treecas_path()).unwrap().count(), 0);
+
+        // Wrapped in a tree.  (This should come out the same as the "deeper" fixture dir, which has only this content.)
+        let opts = AddOptions {
+            faithmode: FaithMode::Copy,
+            wrap_name: Some("samefile".into()),
+            ..Default::default()
+        };
+        let report = add(&r, "fixtures/alpha/a_dir/more_files", &opts).expect("add to succeed");
+        assert_eq!(
+            report.hash.as_hex(),
+            "9897054d9f01c666ac1371d3e0a022a67b5df59ddb1608e8165a3b1fa22da706"
+        );
+        assert!(r
+            .treecas_path()
+            .join(report.hash.as_hex())
+            .join("samefile")
+            .is_file());
+
+        // A symlink to a file stands for the file: the blob is the file itself, and never the link.
+        let linked = td.path().join("linked");
+        fs::create_dir(&linked).unwrap();
+        fs::write(linked.join("real"), b"hello\n").unwrap();
+        std::os::unix::fs::symlink("real", linked.join("link")).unwrap();
+        let report = add(&r, linked.join("link"), &AddOptions::default()).expect("add to succeed");
+        assert_eq!(
+            report.hash,
+            gittree::hash_of_path(r.object_format(), linked.join("real")).unwrap()
+        );
+        let blob_meta = fs::symlink_metadata(r.blobcas_path().join(report.hash.as_hex())).unwrap();
+        assert!(blob_meta.is_file());
+        assert_eq!(
+            blob_meta.ino(),
+            fs::metadata(linked.join("real")).unwrap().ino()
+        );
+
+        // Moving a single file: it's gone afterwards.
+        let scratch = td.path().join("scratch");
+        copy_fixture(Path::new("fixtures/alpha/a_dir/deeper"), &scratch);
+        let report =
+            add(&r, scratch.join("samefile"), &faith(FaithMode::Move)).expect("add to succeed");
+        assert_eq!(
+            report.stats,
+            AddStats {
+                moved: 1,
+                ..Default::default()
+            }
+        );
+        assert!(!scratch.join("samefile").exists());
+    }
+
+    #[test]
+    fn test_add_threads() {
+        // However many threads, the tree comes out the same.
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        for threads in [1, 2, 8] {
+            let opts = AddOptions {
+                faithmode: FaithMode::Copy,
+                threads,
+                ..Default::default()
+            };
+            let report = add(&r, "fixtures/alpha", &opts).expect("add to succeed");
+            assert_eq!(report.hash.as_hex(), ALPHA_HASH);
+        }
+    }
+
+    #[test]
+    fn test_add_excludes() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let scratch = td.path().join("scratch");
+        copy_fixture(Path::new("fixtures/alpha"), &scratch);
+        fs::create_dir(scratch.join("target")).unwrap();
+        fs::write(scratch.join("target/junk"), "build output").unwrap();
+        fs::write(scratch.join("a_dir/.more_files.swp"), "editor junk").unwrap();
+
+        // With flags: comes out the same as the fixture, and the junk never reaches the blobcas.
+        let opts = AddOptions {
+            faithmode: FaithMode::Copy,
+            excludes: vec!["target/".into(), "*.swp".into()],
+            ..Default::default()
+        };
+        let report = add(&r, &scratch, &opts).expect("add to succeed");
+        assert_eq!(report.hash.as_hex(), ALPHA_HASH);
+        assert_eq!(report.stats.copied, 4);
+
+        // With a .garignore: the same, except the .garignore itself is part of the tree.
+        fs::write(scratch.join("a_dir/.garignore"), "*.swp\n").unwrap();
+        fs::write(scratch.join(".garignore"), "/target/\n").unwrap();
+        let report = add(&r, &scratch, &faith(FaithMode::Copy)).expect("add to succeed");
+        fs::remove_dir_all(scratch.join("target")).unwrap();
+        fs::remove_file(scratch.join("a_dir/.more_files.swp")).unwrap();
+        assert_eq!(
+            report.hash,
+            gittree::hash_of_path(r.object_format(), &scratch).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_hash_matches_add() {
+        // A tree with the heap in it, and a .garignore, and more to leave out by flag: `gar add .` in a project, say.
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let scratch = td.path().join("scratch");
+        copy_fixture(Path::new("fixtures/alpha"), &scratch);
+        let r = repo::Repo::new(&scratch).expect("repo");
+        r.create_dir_all().expect("creating repo dirs");
+        fs::create_dir(scratch.join("target")).unwrap();
+        fs::write(scratch.join("target/junk"), "build output").unwrap();
+        fs::write(scratch.join("a_dir/.more_files.swp"), "editor junk").unwrap();
+        fs::write(scratch.join("a_dir/.garignore"), "*.swp\n").unwrap();
+
+        let opts = AddOptions {
+            faithmode: FaithMode::Copy,
+            excludes: vec!["target".into()],
+            ..Default::default()
+        };
+        let report = add(&r, &scratch, &opts).expect("add to succeed");
+
+        let heap_meta = fs::metadata(r.repo_path()).unwrap();
+        let exclusions = garignore::Exclusions::new(&opts.excludes, Some(&heap_meta));
+        let (hash, entries) = gittree::hash_of_path_with_entries(
+            r.object_format(),
+            &scratch,
+            gittree::SpecialFilePolicy::Error,
+            Some(&exclusions),
+            &mut Vec::new(),
+        )
+        .expect("hash to succeed");
+        assert_eq!(hash, report.hash);
+        assert!(entries
+            .iter()
+            .any(|ent| ent.path == Path::new("a_dir/.garignore")));
+        assert!(!entries.iter().any(|ent| ent.path.starts_with(".gar")
+            || ent.path.starts_with("target")
+            || ent.path == Path::new("a_dir/.more_files.swp")));
+    }
+
+    #[test]
+    fn test_add_special_files() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let scratch = td.path().join("scratch");
+        copy_fixture(Path::new("fixtures/alpha"), &scratch);
+        let _sock =
+            std::os::unix::net::UnixListener::bind(scratch.join("a_dir/a.sock")).expect("bind");
+
+        let err = add(&r, &scratch, &faith(FaithMode::Copy)).expect_err("add to refuse");
+        assert!(matches!(err, GarError::SpecialFile { path } if path.ends_with("a_dir/a.sock")));
+
+        let opts = AddOptions {
+            faithmode: FaithMode::Copy,
+            special_files: gittree::SpecialFilePolicy::Warn,
+            ..Default::default()
+        };
+        let report = add(&r, &scratch, &opts).expect("add to succeed");
+        assert_eq!(report.hash.as_hex(), ALPHA_HASH);
+        assert_eq!(report.skipped, vec![scratch.join("a_dir/a.sock")]);
+    }
+
+    #[test]
+    fn test_add_writes_index() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let report = add(&r, "fixtures/alpha", &faith(FaithMode::Copy)).expect("add to succeed");
+        let idx_path = r.treeidx_path().join(report.hash.as_hex());
+        let written = fs::read(&idx_path).expect("garidx to exist");
+        assert!(written.starts_with(b"# garidx v1\n    2 ./ 040000 - "));
+
+        // Regenerating it from the treecas must come out exactly the same.
+        fs::remove_file(&idx_path).unwrap();
+        garidx::reindex(&r, &report.hash).expect("reindex to succeed");
+        assert_eq!(fs::read(&idx_path).unwrap(), written);
+
+        // And it can be turned off.
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let opts = AddOptions {
+            no_index: true,
+            ..Default::default()
+        };
+        let report = add(&r, "fixtures/alpha", &opts).expect("add to succeed");
+        assert!(!r.treeidx_path().join(report.hash.as_hex()).exists());
+    }
+
+    #[test]
+    fn test_add_git_order() {
+        // The same hash as `git write-tree` gives: see the gittree tests for where this came from.
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let report = add(&r, "fixtures/tricky", &faith(FaithMode::Copy)).expect("add to succeed");
+        assert_eq!(
+            report.hash.as_hex(),
+            "82a644301657f063a20ab5a725a799518bcd06dcf02889f7a211ac9c01dcaed8"
+        );
+    }
+
+    #[test]
+    fn test_add_sha1() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        repo::Repo::new(td.path())
+            .unwrap()
+            .with_object_format(gittree::ObjectFormat::Sha1)
+            .create_dir_all()
+            .expect("creating repo dirs");
+        // The format is remembered in the config, so a heap opened later is still sha1.
+        let r = repo::Repo::new(td.path()).unwrap();
+        assert_eq!(r.object_format(), gittree::ObjectFormat::Sha1);
+
+        // The same hash as `git write-tree` gives in a classic (sha1) repo.
+        let report = add(&r, "fixtures/alpha", &faith(FaithMode::Copy)).expect("add to succeed");
+        assert_eq!(
+            report.hash.as_hex(),
+            "f95738113efde9167cd35f089e854affcdaf67f0"
+        );
+        assert!(r
+            .blobcas_path()
+            .join("02f6335fc4f28cc4ea2d0846aacff267a149effb")
+            .exists());
+
+        // And the garidx can hold sha1 hashes just as well.
+        let idx_path = r.treeidx_path().join(report.hash.as_hex());
+        let written = fs::read(&idx_path).expect("garidx to exist");
+        let entries = garidx::read(&written[..]).expect("garidx to parse");
+        assert_eq!(entries[0].hash, report.hash);
+        fs::remove_file(&idx_path).unwrap();
+        garidx::reindex(&r, &report.hash).expect("reindex to succeed");
+        assert_eq!(fs::read(&idx_path).unwrap(), written);
+    }
+
+    #[test]
+    fn test_add_familiar_tree() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let report = add(&r, "fixtures/alpha", &faith(FaithMode::Copy)).expect("add to succeed");
+        assert!(report.new_tree);
+        let tree_path = r.treecas_path().join(report.hash.as_hex());
+        let tree_ino = fs::metadata(&tree_path).unwrap().ino();
+
+        // The second time around, the tree is already there, so nothing gets built, and the existing entry is left be.
+        let report2 = add(&r, "fixtures/alpha", &faith(FaithMode::Copy)).expect("add to succeed");
+        assert_eq!(report2.hash, report.hash);
+        assert!(!report2.new_tree);
+        assert_eq!(fs::metadata(&tree_path).unwrap().ino(), tree_ino);
+        let leftovers: Vec<_> = fs::read_dir(r.treecas_path())
+            .unwrap()
+            .map(|ent| ent.unwrap().file_name())
+            .collect();
+        assert_eq!(leftovers, vec![OsString::from(report.hash.as_hex())]);
+
+        // A single file added as a blob is never a new tree.
+        let report3 =
+            add(&r, "fixtures/alpha/a_file", &faith(FaithMode::Copy)).expect("add to succeed");
+        assert!(!report3.new_tree);
+    }
+
+    #[test]
+    fn test_add_stat_cache() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let scratch = td.path().join("scratch");
+        copy_fixture(Path::new("fixtures/alpha"), &scratch);
+        // Files fresh from the copy would be too new to trust (see `statcache` about racy mtimes), so backdate them.
+        let long_ago =
+            std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        let backdate = |path: &Path| {
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(long_ago)
+                .unwrap()
+        };
+        for file in [
+            "a_file",
+            "a_dir/other_file",
+            "a_dir/more_files",
+            "a_dir/deeper/samefile",
+        ] {
+            backdate(&scratch.join(file));
         }
-        return Ok(tha.finish());
+        let opts = |stat_cache| AddOptions {
+            faithmode: FaithMode::Copy,
+            stat_cache,
+            ..Default::default()
+        };
+
+        let report =
+            add(&r, &scratch, &opts(statcache::StatCacheMode::Trust)).expect("add to succeed");
+        assert_eq!(report.hash.as_hex(), ALPHA_HASH);
+        assert_eq!((report.stats.copied, report.stats.cached), (4, 0));
+        assert!(r.statcache_path().exists());
+
+        // Second time around, nothing needs reading.
+        let report =
+            add(&r, &scratch, &opts(statcache::StatCacheMode::Trust)).expect("add to succeed");
+        assert_eq!(report.hash.as_hex(), ALPHA_HASH);
+        assert_eq!((report.stats.copied, report.stats.cached), (0, 4));
+
+        // A sneaky change -- same size, mtime put back -- still moves the ctime, so it's noticed.
+        fs::write(scratch.join("a_file"), b"b file\n").unwrap();
+        backdate(&scratch.join("a_file"));
+        let report =
+            add(&r, &scratch, &opts(statcache::StatCacheMode::Trust)).expect("add to succeed");
+        assert_ne!(report.hash.as_hex(), ALPHA_HASH);
+        assert_eq!((report.stats.copied, report.stats.cached), (1, 3));
+
+        // Files that are gone are dropped from the cache.
+        fs::remove_file(scratch.join("a_dir/more_files")).unwrap();
+        add(&r, &scratch, &opts(statcache::StatCacheMode::Trust)).expect("add to succeed");
+        let cache = statcache::StatCache::load(r.statcache_path()).unwrap();
+        assert_eq!(cache.len(), 3);
+
+        // And rehashing reads everything, regardless.
+        let report =
+            add(&r, &scratch, &opts(statcache::StatCacheMode::Rehash)).expect("add to succeed");
+        assert_eq!((report.stats.copied, report.stats.cached), (3, 0));
     }
 }
@@ -1,3 +1,4 @@
+use std::ffi::OsString;
 use std::path::PathBuf;
 
 #[derive(clap::Parser, Debug)]
@@ -24,13 +25,377 @@ pub enum Subcommands {
 
     /// add local files and directories to Gar storage.
     Add(AddCmd),
+
+    /// report on (and optionally finish) adds that were interrupted.
+    Recover(RecoverCmd),
+
+    /// regenerate the garidx file for a tree that's already in the treecas.
+    Reindex(ReindexCmd),
+
+    /// compute the hash of local files and directories, without storing anything.  (No repo needed.)
+    Hash(HashCmd),
+
+    /// materialize a tree from the heap into a dir of its own, to use it without any risk of changing the heap.
+    Export(ExportCmd),
+
+    /// write a tree from the treecas into a git repo, as loose objects with the same hashes.
+    GitExport(GitExportCmd),
+
+    /// read a tree (and everything in it) out of a git repo, into the blobcas and treecas, without a checkout.
+    GitImport(GitImportCmd),
+
+    /// verify the heap: rehash every blob and tree, and check that they're all linked up as they should be.
+    Fsck(FsckCmd),
+
+    /// delete trees and blobs that aren't reachable from the given roots, or anything pinned.
+    Gc(GcCmd),
+
+    /// pin trees (or blobs) so gc keeps them; or, with no hashes, list what's pinned.
+    Pin(PinCmd),
+
+    /// manage labels: human-readable names for trees.
+    Label(LabelCmd),
 }
 
 #[derive(clap::Args, Debug)]
-pub struct InitCmd {}
+pub struct InitCmd {
+    /// what hash function the new heap uses, for both blobs and trees.
+    ///
+    /// sha256 hashes match repos made with `git init --object-format=sha256`; sha1 hashes match classic git repos.
+    /// This can't be changed later.
+    #[arg(long, value_enum, default_value_t = ObjectFormatArg::Sha256)]
+    pub object_format: ObjectFormatArg,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ObjectFormatArg {
+    Sha1,
+    Sha256,
+}
+
+impl From<ObjectFormatArg> for gar_rust::gittree::ObjectFormat {
+    fn from(arg: ObjectFormatArg) -> Self {
+        match arg {
+            ObjectFormatArg::Sha1 => Self::Sha1,
+            ObjectFormatArg::Sha256 => Self::Sha256,
+        }
+    }
+}
 
 #[derive(clap::Args, Debug)]
 pub struct AddCmd {
-    /// path to the directory to add to Gar's storage.
+    /// path to the directory (or file) to add to Gar's storage.
+    ///
+    /// A single file is added as just a blob, and its blobhash is printed; see --wrap to get a tree instead.
     pub path: PathBuf,
+
+    /// when adding a single file, wrap it in a tree, as the one entry, with this name.
+    #[arg(long, value_name = "NAME")]
+    pub wrap: Option<OsString>,
+
+    /// exclude paths matching this pattern (in .garignore syntax, relative to the path being added).
+    /// Can be given more than once.  (.garignore files in the tree are obeyed too.)
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<OsString>,
+
+    /// what to do about FIFOs, sockets, device nodes, and other things that can't be part of a tree.
+    #[arg(long, value_enum, default_value_t = SpecialFilesArg::Error)]
+    pub special_files: SpecialFilesArg,
+
+    /// don't write a garidx file for the tree into the treeidx.  (`gar reindex` can make one later.)
+    #[arg(long)]
+    pub no_index: bool,
+
+    /// point this label at the result.  (Moving it, if it's already pointing at something else.)
+    #[arg(long, value_name = "NAME")]
+    pub label: Option<String>,
+
+    /// skip hashing files that the stat cache says haven't changed since they were last added (and update it).
+    ///
+    /// Files are considered unchanged if they're the same inode, with the same size, mtime, and ctime.
+    /// See the README for the caveats.
+    #[arg(long, conflicts_with = "rehash")]
+    pub trust_stat_cache: bool,
+
+    /// hash every file, ignoring the stat cache, but update the stat cache with what's found.
+    #[arg(long)]
+    pub rehash: bool,
+
+    /// how many threads to hash and store files with.  Zero means one per CPU.
+    #[arg(short = 'j', long, default_value_t = 0)]
+    pub threads: usize,
+
+    /// how to get file contents into the blobcas.
+    #[arg(long, value_enum, default_value_t = FaithModeArg::LinkOriginals)]
+    pub faith: FaithModeArg,
+
+    /// what to do about original files with a mode other than 644 or 755,
+    /// when they'd be put in the blobcas as-is (i.e. when linking or moving them).
+    #[arg(long, value_enum, default_value_t = ModePolicyArg::Ignore)]
+    pub mode_policy: ModePolicyArg,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum FaithModeArg {
+    /// copy files into the blobcas.  Safe even if the originals are changed later.
+    Copy,
+    /// hardlink the original files into the blobcas.  The originals must never be changed afterwards!
+    LinkOriginals,
+    /// move the original files into the blobcas.  They'll be gone from where they were.
+    Move,
+    /// hardlink the original files if they're on the same filesystem as the heap, and copy them otherwise.
+    LinkOrCopy,
+    /// reflink (copy-on-write clone) the original files.  Fails if the filesystem can't do that.
+    Reflink,
+    /// reflink the original files if the filesystem can do that, and copy them otherwise.
+    ReflinkOrCopy,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ModePolicyArg {
+    /// leave them as they are.
+    Ignore,
+    /// change the original's mode to 644 (or 755, if it was executable at all).
+    Normalize,
+    /// copy those files instead.
+    Copy,
+    /// stop with an error.
+    Refuse,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum SpecialFilesArg {
+    /// stop with an error naming the file.
+    Error,
+    /// leave them out, and list them at the end.
+    Warn,
+    /// leave them out, quietly.
+    Skip,
+}
+
+impl From<SpecialFilesArg> for gar_rust::gittree::SpecialFilePolicy {
+    fn from(arg: SpecialFilesArg) -> Self {
+        match arg {
+            SpecialFilesArg::Error => Self::Error,
+            SpecialFilesArg::Warn => Self::Warn,
+            SpecialFilesArg::Skip => Self::Skip,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RecoverCmd {
+    /// finish each interrupted add, instead of only reporting on them.
+    #[arg(long)]
+    pub roll_forward: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct HashCmd {
+    /// path to the directory (or file) to hash.
+    ///
+    /// A directory gets a treehash; a single file gets a blobhash.
+    pub path: PathBuf,
+
+    /// how to print the result.
+    #[arg(long, value_enum, default_value_t = HashFormatArg::Text)]
+    pub format: HashFormatArg,
+
+    /// what to do about FIFOs, sockets, device nodes, and other things that can't be part of a tree.
+    #[arg(long, value_enum, default_value_t = SpecialFilesArg::Error)]
+    pub special_files: SpecialFilesArg,
+
+    /// what hash function to use.  Defaults to the one the gar heap uses, if there is one, and sha256 if not.
+    #[arg(long, value_enum)]
+    pub object_format: Option<ObjectFormatArg>,
+
+    /// leave out paths matching this pattern, as `gar add --exclude` would.  Can be given more than once.
+    /// (.garignore files in the tree are obeyed too, and the gar heap is left out, just as `gar add` does.)
+    #[arg(long, value_name = "PATTERN")]
+    pub exclude: Vec<OsString>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum HashFormatArg {
+    /// just the hash.
+    Text,
+    /// a JSON object with the hash, and the hash of every entry below it.
+    Json,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ReindexCmd {
+    /// the treehash (in hex) of the tree to index, or a label.
+    pub hash: String,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ExportCmd {
+    /// the treehash (in hex) of the tree to export, or a label.
+    pub hash: String,
+
+    /// where to put it.  This has to be an empty dir, or not exist yet (unless --overwrite).
+    pub dest: PathBuf,
+
+    /// how to make the exported files.
+    #[arg(long, value_enum, default_value_t = ExportModeArg::Copy)]
+    pub mode: ExportModeArg,
+
+    /// replace whatever's at the destination already.
+    #[arg(long)]
+    pub overwrite: bool,
+
+    /// hash the export before putting it in place, and fail if it doesn't match.
+    #[arg(long)]
+    pub verify: bool,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum ExportModeArg {
+    /// copy files out of the heap.  The export can be changed freely.
+    Copy,
+    /// hardlink files to the heap.  Takes no space, but the export must never be changed, or the heap is corrupted!
+    Hardlink,
+    /// reflink (copy-on-write clone) files out of the heap.  Fails if the filesystem can't do that.
+    Reflink,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct GitExportCmd {
+    /// the treehash (in hex) of the tree to export, or a label.
+    pub hash: String,
+
+    /// the git dir to write objects into (e.g. a repo's ".git" dir, or a bare repo).
+    ///
+    /// Its object format has to be the same as the gar heap's: sha256 (`git init --object-format=sha256`) by default.
+    #[arg(long)]
+    pub git_dir: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct GitImportCmd {
+    /// the tree to import: a tree or commit ID (in full hex), or a ref name like "main" or "v1.0".
+    ///
+    /// Commits and tags are peeled down to their tree.
+    pub treeish: String,
+
+    /// the git dir to read objects from (e.g. a repo's ".git" dir, or a bare repo).
+    ///
+    /// Loose objects and packfiles are both read.
+    /// Its object format has to be the same as the gar heap's: sha256 (`git init --object-format=sha256`) by default.
+    #[arg(long)]
+    pub git_dir: PathBuf,
+
+    /// don't write a garidx file for the tree into the treeidx.
+    #[arg(long)]
+    pub no_index: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct FsckCmd {
+    /// how to print the report.
+    #[arg(long, value_enum, default_value_t = FsckFormatArg::Text)]
+    pub format: FsckFormatArg,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum FsckFormatArg {
+    /// one problem per line: its kind ("corrupt", "orphaned", or "mismatched"), path, and details, separated by tabs.
+    Text,
+    /// a JSON object with counts of what was checked, and a list of problems.
+    Json,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct GcCmd {
+    /// treehashes (in hex) or labels of trees to keep, in addition to everything pinned or labelled.  (Blob hashes work too.)
+    pub roots: Vec<String>,
+
+    /// only report what would be deleted, and how many bytes that would free.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// keep what labels pointed at within this many days, instead of the heap's `label-history-days` setting (30 by default).
+    #[arg(long, value_name = "DAYS")]
+    pub label_history_days: Option<u64>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct PinCmd {
+    /// treehashes (in hex), or labels, to pin.
+    pub hashes: Vec<String>,
+
+    /// unpin them instead.
+    #[arg(long)]
+    pub remove: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct LabelCmd {
+    #[command(subcommand)]
+    pub action: LabelAction,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum LabelAction {
+    /// point a label at a tree, creating it or moving it.
+    Set(LabelSetCmd),
+
+    /// print the hash a label points at.
+    Get(LabelGetCmd),
+
+    /// list every label, with the hash it points at.
+    List,
+
+    /// remove a label.  (What it pointed at stays in the heap, until a gc.)
+    Rm(LabelRmCmd),
+
+    /// print a label's history, newest first: when (in seconds since the unix epoch), the old hash, the new hash, and the message.
+    ///
+    /// "-" stands for no hash: the label didn't exist before, or was removed.
+    Log(LabelLogCmd),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct LabelSetCmd {
+    /// the label.  Slashes are allowed, for grouping (e.g. "nightly/linux").
+    pub name: String,
+
+    /// the treehash (in hex) to point it at, or another label.
+    pub hash: String,
+
+    /// only change it if it points at this now (a treehash, or another label): a compare-and-swap.
+    #[arg(long, value_name = "OLD_HASH", conflicts_with = "create")]
+    pub expect: Option<String>,
+
+    /// a note for the label's history, saying why.
+    #[arg(long, short, default_value = "")]
+    pub message: String,
+
+    /// only create a new label; don't move an existing one.
+    #[arg(long)]
+    pub create: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct LabelGetCmd {
+    pub name: String,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct LabelRmCmd {
+    pub name: String,
+
+    /// only remove it if it points at this (a treehash, or another label).
+    #[arg(long, value_name = "OLD_HASH")]
+    pub expect: Option<String>,
+
+    /// a note for the label's history, saying why.
+    #[arg(long, short, default_value = "")]
+    pub message: String,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct LabelLogCmd {
+    pub name: String,
 }
@@ -0,0 +1,209 @@
+//! The error type for gar operations.
+//!
+//! A bare `io::Error` says what went wrong, but not where, or what we were in the middle of doing:
+//! "permission denied" could be about a source file, or about the heap, and those need very different fixes.
+//! So errors here carry the path involved and the stage of the operation,
+//! and the cases that callers might want to act on (like a cross-device link) get variants of their own.
+//!
+//! Each variant also maps to a process exit code (see `GarError::exit_code`), so scripts can tell them apart too.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What we were in the middle of doing when an IO error happened.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    /// Setting up, or finding, the gar heap itself.
+    Repo,
+    /// Walking the source: reading dirs and statting their entries.
+    Scan,
+    /// Reading (and hashing) the contents of a source file.
+    Hash,
+    /// Getting a file into the blobcas (including the staging dir, for modes that make copies).
+    Blobcas,
+    /// Linking entries into the wiptree (the treecas entry under construction).
+    Wiptree,
+    /// Moving a finished wiptree into its place in the treecas.
+    Commit,
+    /// Writing or reading garidx files in the treeidx.
+    Index,
+    /// Writing or reading tree objects in the treeobj store.
+    TreeObj,
+    /// Writing or reading the intent journal of a move-mode add.
+    Journal,
+    /// Loading or saving the stat cache.
+    StatCache,
+    /// Reading a git repo, or writing objects into it.
+    Git,
+    /// Reading the heap back to verify it (as `gar fsck` does).
+    Check,
+    /// Taking or releasing the heap's lock.
+    Lock,
+    /// Deleting things from the heap that nothing refers to any more.
+    Gc,
+    /// Making an export of a tree, outside the heap.
+    Export,
+    /// Reading or writing labels.
+    Label,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Repo => "setting up the gar heap",
+            Stage::Scan => "scanning",
+            Stage::Hash => "reading and hashing",
+            Stage::Blobcas => "storing into the blobcas",
+            Stage::Wiptree => "linking into the wiptree",
+            Stage::Commit => "committing to the treecas",
+            Stage::Index => "indexing",
+            Stage::TreeObj => "storing tree objects",
+            Stage::Journal => "journaling",
+            Stage::StatCache => "updating the stat cache",
+            Stage::Git => "working with git",
+            Stage::Check => "checking",
+            Stage::Lock => "locking",
+            Stage::Gc => "collecting garbage in",
+            Stage::Export => "exporting to",
+            Stage::Label => "handling the label",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum GarError {
+    /// An IO error, on a path, at some stage.  (The catch-all: anything more specific gets its own variant.)
+    Io {
+        stage: Stage,
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// A file needed to be hardlinked into (or out of) the heap, but it's on a different filesystem than the heap is.
+    CrossDevice { path: PathBuf, source: io::Error },
+    /// A reflink was needed, but the filesystem (or this pair of files) doesn't support them.
+    ReflinkUnsupported { path: PathBuf },
+    /// The final rename of a wiptree found that the treecas already has an entry of that name.
+    ///
+    /// Because the treecas is content-addressed, that means someone else already added the same tree,
+    /// and `add` treats this as success.  It's a variant so that that's a decision, not an accident.
+    TreeExists { path: PathBuf },
+    /// Something that's not a file, dir, or symlink, which can't be part of a tree.
+    /// (See `gittree::SpecialFilePolicy` for ways to skip these instead.)
+    SpecialFile { path: PathBuf },
+    /// A file with a mode that `ModePolicy::Refuse` refused.
+    OddMode { path: PathBuf, mode: u32 },
+    /// The request itself doesn't make sense (e.g. a bad option value, or a path of the wrong kind).
+    InvalidInput { reason: String },
+    /// Something in the heap isn't what it should be.
+    Corrupt { path: PathBuf, reason: String },
+    /// The heap is locked by another process, doing something that can't happen alongside this.
+    /// (Only `gc` gives up when the heap is busy; everything else waits its turn.  See `Repo::lock_exclusive`.)
+    Busy { path: PathBuf },
+    /// A compare-and-swap found something other than what it expected (e.g. a label that had been moved meanwhile).
+    Conflict { reason: String },
+}
+
+impl GarError {
+    /// The process exit code for this kind of error.
+    ///
+    /// (Codes below 7 are used by the CLI itself for other things: see `main.rs`.)
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            GarError::Io {
+                stage: Stage::Scan | Stage::Hash,
+                ..
+            } => 10,
+            GarError::Io { .. } => 11,
+            GarError::CrossDevice { .. } => 12,
+            GarError::ReflinkUnsupported { .. } => 13,
+            GarError::SpecialFile { .. }
+            | GarError::OddMode { .. }
+            | GarError::InvalidInput { .. } => 7,
+            GarError::Corrupt { .. } => 9,
+            GarError::Busy { .. } => 14,
+            GarError::Conflict { .. } => 15,
+            // (Adds treat this as success, so it shouldn't ever reach the CLI; but if it does, it's distinct.)
+            GarError::TreeExists { .. } => 16,
+        }
+    }
+
+    /// The underlying IO error, if there is one.
+    pub fn io_error(&self) -> Option<&io::Error> {
+        match self {
+            GarError::Io { source, .. } | GarError::CrossDevice { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for GarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GarError::Io {
+                stage,
+                path,
+                source,
+            } => write!(f, "error while {} {:?}: {}", stage, path, source),
+            GarError::CrossDevice { path, .. } => write!(
+                f,
+                "{:?} is on a different filesystem than the gar heap, so it can't be hardlinked (for an add, try --faith=link-or-copy; for an export, --mode=copy)",
+                path
+            ),
+            GarError::ReflinkUnsupported { path } => write!(
+                f,
+                "can't reflink {:?}: the filesystem holding the gar heap does not support reflinks (for an add, try --faith=reflink-or-copy; for an export, --mode=copy)",
+                path
+            ),
+            GarError::TreeExists { path } => write!(f, "{:?} already exists in the treecas", path),
+            GarError::SpecialFile { path } => write!(
+                f,
+                "{:?} is not a file, dir, or symlink, and can't be part of a tree",
+                path
+            ),
+            GarError::OddMode { path, mode } => write!(
+                f,
+                "{:?} has mode {:o}, but only 644 and 755 can be put in the blobcas as-is",
+                path, mode
+            ),
+            GarError::InvalidInput { reason } | GarError::Conflict { reason } => f.write_str(reason),
+            GarError::Corrupt { path, reason } => write!(f, "{:?}: {}", path, reason),
+            GarError::Busy { path } => write!(
+                f,
+                "the gar heap is in use by another process ({:?} is locked); try again once it's done",
+                path
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GarError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.io_error().map(|e| e as _)
+    }
+}
+
+/// Attach a stage and a path to an `io::Result`.
+pub trait Context<T> {
+    fn at(self, stage: Stage, path: &Path) -> Result<T, GarError>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn at(self, stage: Stage, path: &Path) -> Result<T, GarError> {
+        self.map_err(|source| {
+            // A cross-device link is the one IO error that's really about the setup rather than the file,
+            // so it gets picked out no matter where it happens.
+            if source.kind() == io::ErrorKind::CrossesDevices {
+                return GarError::CrossDevice {
+                    path: path.to_owned(),
+                    source,
+                };
+            }
+            GarError::Io {
+                stage,
+                path: path.to_owned(),
+                source,
+            }
+        })
+    }
+}
@@ -0,0 +1,478 @@
+//! Exporting trees out of the heap, into a plain dir anywhere: the way to actually use a snapshot.
+//!
+//! Reading `.gar/treecas/<hash>` directly works, but only until something writes there, which corrupts the heap.
+//! An export is a separate dir, made one of three ways (see `ExportMode`).
+//!
+//! What's in a tree comes from its tree object, so the exec bit of each file comes from the tree's entry for it,
+//! not from whatever mode a file in the heap happens to have now.  Files come from the blobcas.
+//! Symlinks aren't stored anywhere but in the tree itself, so those are read from the tree's entry in the treecas,
+//! which is why only trees at the treecas root can be exported.  Every mode recreates symlinks verbatim.
+//!
+//! The export is built in a temp dir beside the destination, then renamed into place,
+//! so the destination is never half-written: it's either as it was, or the whole tree.
+//! The destination has to be empty (or not exist yet), unless overwriting is asked for.
+
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt as _;
+use std::os::unix::fs::PermissionsExt as _;
+use std::path::{Path, PathBuf};
+
+use crate::add;
+use crate::error::{Context as _, GarError, Stage};
+use crate::gittree;
+use crate::gittree::EntryKind;
+use crate::reflink;
+use crate::repo;
+use crate::treeobj;
+
+/// How exported files are made.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum ExportMode {
+    /// Copy each file out of the blobcas, with its mode (644, or 755 if the tree says it's executable).
+    /// The export is independent of the heap: edit it all you like.
+    #[default]
+    Copy,
+    /// Hardlink each file to its blob in the blobcas.  Fast, and takes no space, but the export is part of the heap:
+    /// editing a file in it corrupts the heap, just like editing the treecas would.
+    /// (So it's for read-only use.)  The destination has to be on the same filesystem as the heap.
+    Hardlink,
+    /// Reflink (copy-on-write clone) each file from the blobcas.  As independent as a copy, and as cheap as a hardlink;
+    /// but only some filesystems can do it (see `reflink`), and this fails if the heap's can't.
+    Reflink,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ExportOptions {
+    pub mode: ExportMode,
+    /// Replace whatever's at the destination already.  Without this, the destination has to be empty, or not exist.
+    pub overwrite: bool,
+    /// Hash the export once it's made (with `gittree::hash_of_path`), and fail unless it matches the tree's hash.
+    /// A failed export leaves the destination alone.
+    pub verify: bool,
+}
+
+/// What an export made.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ExportReport {
+    pub files: u64,
+    pub symlinks: u64,
+    /// Dirs, not counting the destination itself.
+    pub dirs: u64,
+    /// The total size of the files.
+    pub bytes: u64,
+}
+
+/// Export the tree `hash` to `dest`.
+pub fn export(
+    repo: &repo::Repo,
+    hash: &gittree::Hash,
+    dest: &Path,
+    opts: &ExportOptions,
+) -> Result<ExportReport, GarError> {
+    // The lock keeps a gc from deleting what we're in the middle of exporting.
+    let _lock = repo.lock_shared()?;
+    let tree_path = repo.treecas_path().join(hash.as_hex());
+    if !tree_path.is_dir() {
+        return Err(GarError::InvalidInput {
+            reason: format!("there's no tree {} in the treecas", hash.as_hex()),
+        });
+    }
+    let parent = match dest.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    // Exporting into the heap would be a good way to corrupt it; and overwriting a dir that has the heap in it would delete the heap.
+    let canonical_dest = fs::canonicalize(parent)
+        .at(Stage::Export, parent)?
+        .join(dest.file_name().unwrap_or_default());
+    let canonical_heap = fs::canonicalize(repo.repo_path()).at(Stage::Repo, repo.repo_path())?;
+    if canonical_dest.starts_with(&canonical_heap) || canonical_heap.starts_with(&canonical_dest) {
+        return Err(GarError::InvalidInput {
+            reason: format!(
+                "{:?} is in the gar heap, or has it inside; export somewhere else",
+                dest
+            ),
+        });
+    }
+    let dest_has_contents = match fs::symlink_metadata(dest) {
+        Ok(meta) if meta.is_dir() => {
+            let empty = fs::read_dir(dest).at(Stage::Export, dest)?.next().is_none();
+            if !empty && !opts.overwrite {
+                return Err(GarError::InvalidInput {
+                    reason: format!(
+                        "{:?} isn't empty; export somewhere else, or ask to overwrite it",
+                        dest
+                    ),
+                });
+            }
+            !empty
+        }
+        Ok(_) => {
+            return Err(GarError::InvalidInput {
+                reason: format!("{:?} exists, and isn't a dir", dest),
+            })
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+        Err(e) => return Err(e).at(Stage::Export, dest),
+    };
+    let staging = tempdir::TempDir::new_in(parent, ".gar-export-").at(Stage::Export, parent)?;
+    if opts.mode == ExportMode::Reflink
+        && !reflink::probe(staging.path()).at(Stage::Export, parent)?
+    {
+        return Err(GarError::ReflinkUnsupported {
+            path: dest.to_owned(),
+        });
+    }
+
+    let wip_path = staging.path().join("tree");
+    fs::create_dir(&wip_path).at(Stage::Export, &wip_path)?;
+    let mut x = Exporter {
+        repo,
+        mode: opts.mode,
+        tree_path,
+        wip_path: wip_path.clone(),
+        report: ExportReport::default(),
+    };
+    x.export_tree(hash, Path::new(""))?;
+
+    if opts.verify {
+        let found = gittree::hash_of_path(repo.object_format(), &wip_path)?;
+        if found != *hash {
+            return Err(GarError::Corrupt {
+                path: x.tree_path,
+                reason: format!(
+                    "exporting it made a tree that hashes to {}, not {}; not putting it in place",
+                    found.as_hex(),
+                    hash.as_hex()
+                ),
+            });
+        }
+    }
+
+    // Into place.  What was there before (an empty dir, or something being overwritten) is moved aside into the staging dir first,
+    // and goes when that does.  (Unless the export can't be moved in after all: then it's put back.)
+    let old_path = staging.path().join("old");
+    let had_old = fs::symlink_metadata(dest).is_ok();
+    if had_old {
+        fs::rename(dest, &old_path).at(Stage::Export, dest)?;
+    }
+    if let Err(e) = fs::rename(&wip_path, dest) {
+        if had_old {
+            fs::rename(&old_path, dest).at(Stage::Export, dest)?;
+        }
+        return Err(e).at(Stage::Export, dest);
+    }
+    if dest_has_contents {
+        make_removable(&old_path)?;
+    }
+    Ok(x.report)
+}
+
+/// Make sure everything in a dir can be deleted, which needs every dir in it to be writable.
+/// (The tempdir that it's in only tries to clean up; so if that fails, it's quietly left behind.)
+fn make_removable(path: &Path) -> Result<(), GarError> {
+    let meta = fs::symlink_metadata(path).at(Stage::Export, path)?;
+    if !meta.is_dir() {
+        return Ok(());
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(0o700)).at(Stage::Export, path)?;
+    for ent in fs::read_dir(path).at(Stage::Export, path)? {
+        make_removable(&ent.at(Stage::Export, path)?.path())?;
+    }
+    Ok(())
+}
+
+struct Exporter<'a> {
+    repo: &'a repo::Repo,
+    mode: ExportMode,
+    /// The tree being exported, in the treecas.  (Where symlinks are read from.)
+    tree_path: PathBuf,
+    /// Where the export is being built.
+    wip_path: PathBuf,
+    report: ExportReport,
+}
+
+impl Exporter<'_> {
+    /// Export the contents of a tree, which is at `rel` in the tree being exported.  (The dir itself is already made.)
+    fn export_tree(&mut self, hash: &gittree::Hash, rel: &Path) -> Result<(), GarError> {
+        let tree = treeobj::load(self.repo, hash)?.ok_or_else(|| GarError::InvalidInput {
+            reason: format!(
+                "there's no tree object for {} (at {:?}) in the treeobj store; `gar reindex` can regenerate them",
+                hash.as_hex(),
+                rel
+            ),
+        })?;
+        for ent in &tree.entries {
+            let ent_rel = rel.join(std::ffi::OsStr::from_bytes(&ent.name));
+            let dest_path = self.wip_path.join(&ent_rel);
+            match ent.kind {
+                EntryKind::Dir => {
+                    fs::create_dir(&dest_path).at(Stage::Export, &dest_path)?;
+                    self.report.dirs += 1;
+                    self.export_tree(&ent.hash, &ent_rel)?;
+                }
+                EntryKind::File | EntryKind::Executable => {
+                    let executable = ent.kind == EntryKind::Executable;
+                    let blobcas_path = self
+                        .repo
+                        .blobcas_path()
+                        .join(add::blob_name(&ent.hash, executable));
+                    self.report.bytes += self.export_file(&blobcas_path, &dest_path, executable)?;
+                    self.report.files += 1;
+                }
+                EntryKind::Symlink => {
+                    let link_path = self.tree_path.join(&ent_rel);
+                    let target = fs::read_link(&link_path).at(Stage::Export, &link_path)?;
+                    std::os::unix::fs::symlink(target, &dest_path).at(Stage::Export, &dest_path)?;
+                    self.report.symlinks += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Make a file at `dest_path` from a blob, and return its size.
+    fn export_file(
+        &self,
+        blobcas_path: &Path,
+        dest_path: &Path,
+        executable: bool,
+    ) -> Result<u64, GarError> {
+        if self.mode == ExportMode::Hardlink {
+            // (The blob's name says whether it's executable, and its mode matches; so the link's does too.)
+            fs::hard_link(blobcas_path, dest_path).at(Stage::Export, dest_path)?;
+            return Ok(fs::metadata(dest_path).at(Stage::Export, dest_path)?.len());
+        }
+        let mut blob = fs::File::open(blobcas_path).at(Stage::Export, blobcas_path)?;
+        let mut dst = fs::File::create(dest_path).at(Stage::Export, dest_path)?;
+        let size = match self.mode {
+            ExportMode::Reflink => {
+                reflink::clone_file(&blob, &dst).map_err(|e| {
+                    match reflink::is_unsupported(&e) {
+                        true => GarError::ReflinkUnsupported {
+                            path: dest_path.to_owned(),
+                        },
+                        false => GarError::Io {
+                            stage: Stage::Export,
+                            path: dest_path.to_owned(),
+                            source: e,
+                        },
+                    }
+                })?;
+                dst.metadata().at(Stage::Export, dest_path)?.len()
+            }
+            _ => io::copy(&mut blob, &mut dst).at(Stage::Export, dest_path)?,
+        };
+        // The mode is the tree's say, not the blob's: the blobcas's modes are an implementation detail, and could have been tampered with.
+        let mode = if executable { 0o755 } else { 0o644 };
+        dst.set_permissions(fs::Permissions::from_mode(mode))
+            .at(Stage::Export, dest_path)?;
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt as _;
+
+    fn fresh_repo(td: &tempdir::TempDir) -> repo::Repo {
+        let r = repo::Repo::new(td.path()).unwrap();
+        r.create_dir_all().unwrap();
+        r
+    }
+
+    fn add_copy(r: &repo::Repo, path: &str) -> gittree::Hash {
+        let opts = add::AddOptions {
+            faithmode: add::FaithMode::Copy,
+            ..Default::default()
+        };
+        add::add(r, path, &opts).expect("add to succeed").hash
+    }
+
+    fn mode(path: &Path) -> u32 {
+        fs::symlink_metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn test_export_copy() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let tricky = add_copy(&r, "fixtures/tricky");
+        let dest = td.path().join("out");
+        let opts = ExportOptions {
+            verify: true,
+            ..Default::default()
+        };
+        let report = export(&r, &tricky, &dest, &opts).expect("export to succeed");
+        assert_eq!((report.files, report.symlinks, report.dirs), (8, 1, 2));
+        assert_eq!(
+            gittree::hash_of_path(r.object_format(), &dest).unwrap(),
+            tricky
+        );
+        assert_eq!(mode(&dest.join("foo.sh")), 0o755);
+        assert_eq!(mode(&dest.join("foo.txt")), 0o644);
+        assert_eq!(
+            fs::read_link(dest.join("foo=link")).unwrap(),
+            PathBuf::from("foo.txt")
+        );
+        // A copy is a copy.
+        assert_ne!(
+            fs::metadata(dest.join("foo.txt")).unwrap().ino(),
+            fs::metadata(r.treecas_path().join(tricky.as_hex()).join("foo.txt"))
+                .unwrap()
+                .ino()
+        );
+        // Edits to the blobcas's modes don't change what's exported: the tree says what's executable.
+        let sh_blob = fs::read_dir(r.blobcas_path())
+            .unwrap()
+            .map(|ent| ent.unwrap().path())
+            .find(|path| path.to_string_lossy().ends_with("-x"))
+            .unwrap();
+        fs::set_permissions(&sh_blob, fs::Permissions::from_mode(0o644)).unwrap();
+        let dest = td.path().join("out2");
+        export(&r, &tricky, &dest, &opts).expect("export to succeed");
+        assert_eq!(mode(&dest.join("foo.sh")), 0o755);
+    }
+
+    #[test]
+    fn test_export_hardlink() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let alpha = add_copy(&r, "fixtures/alpha");
+        let dest = td.path().join("out");
+        let opts = ExportOptions {
+            mode: ExportMode::Hardlink,
+            verify: true,
+            ..Default::default()
+        };
+        let report = export(&r, &alpha, &dest, &opts).expect("export to succeed");
+        assert_eq!((report.files, report.symlinks, report.dirs), (4, 1, 2));
+        assert_eq!(
+            fs::metadata(dest.join("a_file")).unwrap().ino(),
+            fs::metadata(r.treecas_path().join(alpha.as_hex()).join("a_file"))
+                .unwrap()
+                .ino()
+        );
+        // (A dangling symlink, which still has to come out exactly as it went in.)
+        assert_eq!(
+            fs::read_link(dest.join("a_symlink")).unwrap(),
+            PathBuf::from("target string")
+        );
+    }
+
+    #[test]
+    fn test_export_reflink() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let tricky = add_copy(&r, "fixtures/tricky");
+        let dest = td.path().join("out");
+        let opts = ExportOptions {
+            mode: ExportMode::Reflink,
+            verify: true,
+            ..Default::default()
+        };
+        // It works exactly when the filesystem can do reflinks; and when it can't, it stops before touching the destination.
+        let result = export(&r, &tricky, &dest, &opts);
+        if reflink::probe(td.path()).unwrap() {
+            result.expect("export to succeed");
+            assert_eq!(mode(&dest.join("foo.sh")), 0o755);
+        } else {
+            let err = result.expect_err("reflink mode to refuse");
+            assert!(matches!(err, GarError::ReflinkUnsupported { .. }));
+            assert!(!dest.exists());
+        }
+    }
+
+    #[test]
+    fn test_export_destination() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let alpha = add_copy(&r, "fixtures/alpha");
+        let tricky = add_copy(&r, "fixtures/tricky");
+        let dest = td.path().join("out");
+
+        // An empty dir is fine.
+        fs::create_dir(&dest).unwrap();
+        export(&r, &alpha, &dest, &ExportOptions::default()).expect("export to succeed");
+
+        // One with something in it isn't, unless asked; then it's replaced wholesale.
+        let err = export(&r, &tricky, &dest, &ExportOptions::default())
+            .expect_err("a full dir to be refused");
+        assert!(matches!(err, GarError::InvalidInput { .. }));
+        assert!(dest.join("a_file").exists());
+        let opts = ExportOptions {
+            overwrite: true,
+            ..Default::default()
+        };
+        export(&r, &tricky, &dest, &opts).expect("export to succeed");
+        assert!(!dest.join("a_file").exists());
+        assert_eq!(
+            gittree::hash_of_path(r.object_format(), &dest).unwrap(),
+            tricky
+        );
+        // Nothing's left behind beside it.
+        let names: Vec<_> = fs::read_dir(td.path())
+            .unwrap()
+            .map(|ent| ent.unwrap().file_name())
+            .collect();
+        assert!(!names
+            .iter()
+            .any(|name| name.as_bytes().starts_with(b".gar-export-")));
+
+        // Nor is the heap, or anywhere in it, or anywhere with it inside.
+        assert!(export(&r, &tricky, r.repo_path(), &opts).is_err());
+        let err =
+            export(&r, &tricky, td.path(), &opts).expect_err("the heap's parent to be refused");
+        assert!(matches!(err, GarError::InvalidInput { .. }));
+        assert!(r.treecas_path().join(tricky.as_hex()).exists());
+        assert!(dest.join("foo.sh").exists());
+        assert!(export(&r, &tricky, &r.treecas_path().join(alpha.as_hex()), &opts).is_err());
+        assert!(r
+            .treecas_path()
+            .join(alpha.as_hex())
+            .join("a_file")
+            .exists());
+
+        // A file isn't a destination, even with overwriting.
+        let file = td.path().join("file");
+        fs::write(&file, "hi").unwrap();
+        assert!(export(&r, &tricky, &file, &opts).is_err());
+        assert_eq!(fs::read(&file).unwrap(), b"hi");
+    }
+
+    #[test]
+    fn test_export_verify() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let alpha = add_copy(&r, "fixtures/alpha");
+        let blob = fs::read_dir(r.blobcas_path())
+            .unwrap()
+            .map(|ent| ent.unwrap().path())
+            .find(|path| path.is_file())
+            .unwrap();
+        let mut perms = fs::metadata(&blob).unwrap().permissions();
+        perms.set_mode(0o644);
+        fs::set_permissions(&blob, perms).unwrap();
+        fs::write(&blob, "not what it was").unwrap();
+
+        // Without checking, the damage goes straight through.
+        let dest = td.path().join("out");
+        export(&r, &alpha, &dest, &ExportOptions::default()).expect("export to succeed");
+        assert_ne!(
+            gittree::hash_of_path(r.object_format(), &dest).unwrap(),
+            alpha
+        );
+
+        // With it, the export fails, and leaves the destination alone.
+        let dest = td.path().join("out2");
+        let opts = ExportOptions {
+            verify: true,
+            ..Default::default()
+        };
+        let err = export(&r, &alpha, &dest, &opts).expect_err("verify to catch it");
+        assert!(matches!(err, GarError::Corrupt { .. }));
+        assert!(!dest.exists());
+    }
+}
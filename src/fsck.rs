@@ -0,0 +1,502 @@
+//! Verifying a heap from its contents, as `gar fsck` does.
+//!
+//! Everything in a heap is named for what it contains, so all of it can be checked without trusting anything else:
+//!
+//! - every blobcas file is rehashed, and has to hash to its name, and be executable exactly when its name says "-x";
+//! - every treecas root is rehashed, and has to hash to its name;
+//! - every file in a treecas entry has to be a hardlink to (i.e. the same inode as) the blob it hashes to;
+//! - every label has to point at something that's in the heap.
+//!
+//! When a treecas entry doesn't hash to its name, and there's a garidx for it, the entries are compared with the garidx,
+//! which says which dirs in it changed, and what in them; otherwise, all that can be said is that the tree as a whole is wrong.
+//!
+//! Things that shouldn't be in the heap at all (like the temp dirs of an interrupted add, or files with names that aren't hashes)
+//! are reported too, as orphans.  (So fsck a heap while nothing is adding to it, or it'll report the adds in progress as orphans.)
+//!
+//! Nothing is repaired: problems are only reported.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::os::unix::fs::MetadataExt as _;
+use std::path::{Path, PathBuf};
+
+use rayon::prelude::*;
+
+use crate::add;
+use crate::error::{Context as _, GarError, Stage};
+use crate::garidx;
+use crate::gittree;
+use crate::gittree::EntryKind;
+use crate::labels;
+use crate::repo;
+
+/// What's wrong with something in the heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ProblemKind {
+    /// Its content doesn't match its name: a blob or tree that doesn't hash to what it's called.
+    Corrupt,
+    /// It doesn't belong in the heap at all: leftover temp files, names that aren't hashes, indexes of trees that aren't there.
+    Orphaned,
+    /// Its content may be fine, but it doesn't agree with the rest of the heap:
+    /// a blob whose mode doesn't match its "-x" suffix, a treecas file that isn't a hardlink to its blob, or a label pointing at nothing.
+    Mismatched,
+}
+
+impl ProblemKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ProblemKind::Corrupt => "corrupt",
+            ProblemKind::Orphaned => "orphaned",
+            ProblemKind::Mismatched => "mismatched",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    pub path: PathBuf,
+    pub detail: String,
+}
+
+/// What fsck found.
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    pub blobs_checked: u64,
+    pub trees_checked: u64,
+    /// Everything wrong, sorted by path.  Empty if the heap is sound.
+    pub problems: Vec<Problem>,
+}
+
+impl FsckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Check the whole heap.  Errors are for when checking couldn't be done at all; what's wrong with the heap is in the report.
+pub fn fsck(repo: &repo::Repo) -> Result<FsckReport, GarError> {
+    let mut report = FsckReport::default();
+    check_blobcas(repo, &mut report)?;
+    check_treecas(repo, &mut report)?;
+    check_treeidx(repo, &mut report)?;
+    check_labels(repo, &mut report)?;
+    report
+        .problems
+        .sort_by(|a, b| a.path.cmp(&b.path).then(a.kind.cmp(&b.kind)));
+    Ok(report)
+}
+
+/// List a dir of the heap, as (name, path) pairs.
+fn list(dir: &Path) -> Result<Vec<(String, PathBuf)>, GarError> {
+    let mut names = Vec::new();
+    for ent in fs::read_dir(dir).at(Stage::Check, dir)? {
+        let ent = ent.at(Stage::Check, dir)?;
+        names.push((ent.file_name().to_string_lossy().into_owned(), ent.path()));
+    }
+    Ok(names)
+}
+
+/// Parse a blobcas name: the hash, and whether it says the blob is executable.
+fn parse_blob_name(format: gittree::ObjectFormat, name: &str) -> Option<(gittree::Hash, bool)> {
+    let (hex, executable) = match name.strip_suffix("-x") {
+        Some(hex) => (hex, true),
+        None => (name, false),
+    };
+    let hash = gittree::Hash::from_hex(hex).ok()?;
+    (hash.format() == format && add::blob_name(&hash, executable) == name)
+        .then_some((hash, executable))
+}
+
+fn parse_tree_name(format: gittree::ObjectFormat, name: &str) -> Option<gittree::Hash> {
+    let hash = gittree::Hash::from_hex(name).ok()?;
+    (hash.format() == format && hash.as_hex() == name).then_some(hash)
+}
+
+fn orphan(path: PathBuf, what: &str) -> Problem {
+    Problem {
+        kind: ProblemKind::Orphaned,
+        path,
+        detail: what.to_owned(),
+    }
+}
+
+fn check_blobcas(repo: &repo::Repo, report: &mut FsckReport) -> Result<(), GarError> {
+    let format = repo.object_format();
+    let mut blobs = Vec::new();
+    for (name, path) in list(repo.blobcas_path())? {
+        match parse_blob_name(format, &name) {
+            Some((hash, executable)) => blobs.push((hash, executable, path)),
+            None if name.starts_with(".wipblob-") => report.problems.push(orphan(
+                path,
+                "staging dir left behind by an interrupted add",
+            )),
+            None => report
+                .problems
+                .push(orphan(path, "not named for a blob hash")),
+        }
+    }
+    report.blobs_checked = blobs.len() as u64;
+
+    // Rehashing is the slow part, so it's done in parallel.
+    let found: Vec<Vec<Problem>> = blobs
+        .par_iter()
+        .map(|(hash, executable, path)| check_blob(format, hash, *executable, path))
+        .collect::<Result<_, _>>()?;
+    report.problems.extend(found.into_iter().flatten());
+    Ok(())
+}
+
+fn check_blob(
+    format: gittree::ObjectFormat,
+    hash: &gittree::Hash,
+    executable: bool,
+    path: &Path,
+) -> Result<Vec<Problem>, GarError> {
+    let mut problems = Vec::new();
+    let meta = fs::symlink_metadata(path).at(Stage::Check, path)?;
+    if !meta.is_file() {
+        problems.push(Problem {
+            kind: ProblemKind::Corrupt,
+            path: path.to_owned(),
+            detail: "not a regular file".to_owned(),
+        });
+        return Ok(problems);
+    }
+    let found_hash = fs::File::open(path)
+        .and_then(|mut f| gittree::hash_of_stream(format, &mut f, meta.len()))
+        .at(Stage::Check, path)?;
+    if found_hash != *hash {
+        problems.push(Problem {
+            kind: ProblemKind::Corrupt,
+            path: path.to_owned(),
+            detail: format!("actually hashes to {}", found_hash.as_hex()),
+        });
+    }
+    // (The same rule `add` uses to pick the suffix: any x bit at all.)
+    let mode = meta.mode() & 0o7777;
+    if (mode & 0o111 != 0) != executable {
+        problems.push(Problem {
+            kind: ProblemKind::Mismatched,
+            path: path.to_owned(),
+            detail: format!(
+                "mode is {:04o}, but its name says it's {}executable",
+                mode,
+                if executable { "" } else { "not " }
+            ),
+        });
+    }
+    Ok(problems)
+}
+
+fn check_treecas(repo: &repo::Repo, report: &mut FsckReport) -> Result<(), GarError> {
+    let format = repo.object_format();
+    for (name, path) in list(repo.treecas_path())? {
+        match parse_tree_name(format, &name) {
+            Some(hash) => {
+                report.trees_checked += 1;
+                check_tree(repo, &hash, &path, &mut report.problems)?;
+            }
+            None if name.starts_with(".wiptree-") => report
+                .problems
+                .push(orphan(path, "wiptree left behind by an interrupted add")),
+            None => report
+                .problems
+                .push(orphan(path, "not named for a tree hash")),
+        }
+    }
+    Ok(())
+}
+
+fn check_tree(
+    repo: &repo::Repo,
+    hash: &gittree::Hash,
+    tree_path: &Path,
+    problems: &mut Vec<Problem>,
+) -> Result<(), GarError> {
+    if !fs::symlink_metadata(tree_path)
+        .at(Stage::Check, tree_path)?
+        .is_dir()
+    {
+        problems.push(Problem {
+            kind: ProblemKind::Corrupt,
+            path: tree_path.to_owned(),
+            detail: "not a dir".to_owned(),
+        });
+        return Ok(());
+    }
+    // Anything that isn't a file, dir, or symlink can't be part of a tree, so it's left out of the hash, and reported.
+    let mut skipped = Vec::new();
+    let (found_hash, entries) = gittree::hash_of_path_with_entries(
+        repo.object_format(),
+        tree_path,
+        gittree::SpecialFilePolicy::Skip,
+        None,
+        &mut skipped,
+    )?;
+    for path in skipped {
+        problems.push(Problem {
+            kind: ProblemKind::Corrupt,
+            path,
+            detail: "a special file, which can't be part of a tree".to_owned(),
+        });
+    }
+    if found_hash != *hash {
+        problems.extend(diverged(repo, hash, tree_path, &found_hash, &entries)?);
+    }
+
+    // Every file should be the very same inode as its blob.  (Which also means the blob is there at all.)
+    for ent in &entries {
+        let executable = match ent.kind {
+            EntryKind::File => false,
+            EntryKind::Executable => true,
+            EntryKind::Symlink | EntryKind::Dir => continue,
+        };
+        let path = tree_path.join(&ent.path);
+        let blob_path = repo
+            .blobcas_path()
+            .join(add::blob_name(&ent.hash, executable));
+        let detail = match fs::metadata(&blob_path) {
+            Err(_) => format!("its blob {:?} is missing", blob_path),
+            Ok(blob_meta) => {
+                let meta = fs::symlink_metadata(&path).at(Stage::Check, &path)?;
+                if (meta.dev(), meta.ino()) == (blob_meta.dev(), blob_meta.ino()) {
+                    continue;
+                }
+                format!("not a hardlink to its blob {:?}", blob_path)
+            }
+        };
+        problems.push(Problem {
+            kind: ProblemKind::Mismatched,
+            path,
+            detail,
+        });
+    }
+    Ok(())
+}
+
+/// Explain how a tree that doesn't hash to its name went wrong, as best we can.
+///
+/// With a garidx, we know what every entry used to hash to, so the dirs whose contents changed can be named,
+/// along with what changed in them.  (Only the deepest ones: every dir above a change has changed too, which says nothing new.)
+fn diverged(
+    repo: &repo::Repo,
+    hash: &gittree::Hash,
+    tree_path: &Path,
+    found_hash: &gittree::Hash,
+    entries: &[gittree::HashedEntry],
+) -> Result<Vec<Problem>, GarError> {
+    let whole_tree = Problem {
+        kind: ProblemKind::Corrupt,
+        path: tree_path.to_owned(),
+        detail: format!("actually hashes to {}", found_hash.as_hex()),
+    };
+    let idx_path = repo.treeidx_path().join(hash.as_hex());
+    let indexed = match fs::File::open(&idx_path) {
+        Ok(f) => match garidx::read(std::io::BufReader::new(f)) {
+            Ok(indexed) => indexed,
+            // An index that doesn't parse isn't much help; that's its own problem.
+            Err(e) => {
+                return Ok(vec![
+                    whole_tree,
+                    Problem {
+                        kind: ProblemKind::Corrupt,
+                        path: idx_path,
+                        detail: e.to_string(),
+                    },
+                ])
+            }
+        },
+        Err(_) => return Ok(vec![whole_tree]),
+    };
+
+    let was: HashMap<&Path, &gittree::HashedEntry> = indexed
+        .iter()
+        .map(|ent| (ent.path.as_path(), ent))
+        .collect();
+    let now: HashMap<&Path, &gittree::HashedEntry> = entries
+        .iter()
+        .map(|ent| (ent.path.as_path(), ent))
+        .collect();
+    // Changes to non-dir entries (and dirs appearing or disappearing), grouped by the dir they're in.
+    let mut changes: BTreeMap<&Path, Vec<String>> = BTreeMap::new();
+    fn note<'a>(changes: &mut BTreeMap<&'a Path, Vec<String>>, what: &str, ent_path: &'a Path) {
+        let dir = ent_path.parent().unwrap_or(Path::new(""));
+        let name = ent_path.file_name().unwrap_or_default().to_string_lossy();
+        changes
+            .entry(dir)
+            .or_default()
+            .push(format!("{} {}", name, what));
+    }
+    for ent in entries {
+        match was.get(ent.path.as_path()) {
+            None => note(&mut changes, "added", &ent.path),
+            Some(old) if old.kind != ent.kind => note(&mut changes, "changed type", &ent.path),
+            Some(old) if old.kind != EntryKind::Dir && old.hash != ent.hash => {
+                note(&mut changes, "changed", &ent.path)
+            }
+            Some(_) => {}
+        }
+    }
+    for ent in &indexed {
+        if !ent.path.as_os_str().is_empty() && !now.contains_key(ent.path.as_path()) {
+            note(&mut changes, "removed", &ent.path);
+        }
+    }
+    if changes.is_empty() {
+        // The index agrees with what's there now, so it must've been made from the tree as it is, not as it was.
+        return Ok(vec![Problem {
+            detail: format!(
+                "{} (but its garidx agrees with what's there, so it can't say what changed)",
+                whole_tree.detail
+            ),
+            ..whole_tree
+        }]);
+    }
+    let mut problems = vec![whole_tree];
+    for (dir, mut what) in changes {
+        // A dir that was added or removed wholesale is already noted in its parent; there's no need to list everything under it too.
+        if dir
+            .ancestors()
+            .any(|up| !up.as_os_str().is_empty() && (was.contains_key(up) != now.contains_key(up)))
+        {
+            continue;
+        }
+        what.sort();
+        let detail = format!("differs from its garidx: {}", what.join(", "));
+        if dir.as_os_str().is_empty() {
+            // (Changes right in the root go with the root's own problem, rather than another for the same path.)
+            problems[0].detail = format!("{}; {}", problems[0].detail, detail);
+            continue;
+        }
+        problems.push(Problem {
+            kind: ProblemKind::Corrupt,
+            path: tree_path.join(dir),
+            detail,
+        });
+    }
+    Ok(problems)
+}
+
+fn check_labels(repo: &repo::Repo, report: &mut FsckReport) -> Result<(), GarError> {
+    for (name, hash) in labels::list(repo)? {
+        if !repo.contains(&hash) {
+            report.problems.push(Problem {
+                kind: ProblemKind::Mismatched,
+                path: repo.labels_path().join(name),
+                detail: format!("points at {}, which isn't in the heap", hash.as_hex()),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn check_treeidx(repo: &repo::Repo, report: &mut FsckReport) -> Result<(), GarError> {
+    let format = repo.object_format();
+    for (name, path) in list(repo.treeidx_path())? {
+        let detail = match parse_tree_name(format, &name) {
+            Some(_) if repo.treecas_path().join(&name).is_dir() => continue,
+            Some(_) => "an index of a tree that isn't in the treecas",
+            None => "not named for a tree hash",
+        };
+        report.problems.push(orphan(path, detail));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn added(td: &tempdir::TempDir) -> (repo::Repo, gittree::Hash) {
+        let r = repo::Repo::new(td.path()).unwrap();
+        r.create_dir_all().unwrap();
+        let opts = add::AddOptions {
+            faithmode: add::FaithMode::Copy,
+            ..Default::default()
+        };
+        let report = add::add(&r, "fixtures/alpha", &opts).expect("add to succeed");
+        (r, report.hash)
+    }
+
+    fn kinds(report: &FsckReport) -> Vec<(ProblemKind, String)> {
+        report
+            .problems
+            .iter()
+            .map(|p| {
+                (
+                    p.kind,
+                    p.path.file_name().unwrap().to_string_lossy().into_owned(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fsck_clean() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let (r, _) = added(&td);
+        let report = fsck(&r).expect("fsck to run");
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.trees_checked, 1);
+        assert!(report.blobs_checked > 0);
+    }
+
+    #[test]
+    fn test_fsck_finds_problems() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let (r, hash) = added(&td);
+        let tree_path = r.treecas_path().join(hash.as_hex());
+        let blob_of = |path: &Path| {
+            let ino = fs::metadata(path).unwrap().ino();
+            fs::read_dir(r.blobcas_path())
+                .unwrap()
+                .map(|ent| ent.unwrap())
+                .find(|ent| ent.metadata().unwrap().ino() == ino)
+                .expect("the blob")
+                .file_name()
+                .to_string_lossy()
+                .into_owned()
+        };
+
+        // Editing a file in the treecas edits its blob too (they're the same inode), so both are corrupt.
+        let edited = tree_path.join("a_dir/other_file");
+        let edited_blob = blob_of(&edited);
+        fs::write(&edited, "edited\n").unwrap();
+        // A file replaced with a copy is still the right content, but not linked to its blob any more.
+        let copied = tree_path.join("a_file");
+        let copied_blob = blob_of(&copied);
+        let content = fs::read(&copied).unwrap();
+        fs::remove_file(&copied).unwrap();
+        fs::write(&copied, content).unwrap();
+        fs::set_permissions(&copied, fs::Permissions::from_mode(0o644)).unwrap();
+        // And that blob's mode disagrees with its name, now.  Plus some leftovers.
+        let odd_blob = r.blobcas_path().join(&copied_blob);
+        fs::set_permissions(&odd_blob, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::create_dir(r.treecas_path().join(".wiptree-abc")).unwrap();
+        fs::write(r.blobcas_path().join("stray"), "").unwrap();
+
+        let report = fsck(&r).expect("fsck to run");
+        let mut expected = vec![
+            (ProblemKind::Corrupt, edited_blob),
+            (ProblemKind::Mismatched, copied_blob),
+            (ProblemKind::Orphaned, "stray".to_owned()),
+        ];
+        // (Blobs sort by their hashes.)
+        expected.sort_by(|a, b| a.1.cmp(&b.1));
+        expected.extend([
+            (ProblemKind::Orphaned, ".wiptree-abc".to_owned()),
+            (ProblemKind::Corrupt, hash.as_hex()),
+            (ProblemKind::Corrupt, "a_dir".to_owned()),
+            (ProblemKind::Mismatched, "other_file".to_owned()),
+            (ProblemKind::Mismatched, "a_file".to_owned()),
+        ]);
+        assert_eq!(kinds(&report), expected);
+        // The garidx narrowed it down to the one file in the one dir.
+        assert_eq!(
+            report.problems[5].detail,
+            "differs from its garidx: other_file changed"
+        );
+    }
+}
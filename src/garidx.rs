@@ -0,0 +1,582 @@
+//! Garidx files: a listing of every path in a tree, with its mode, size, and hash.
+//!
+//! There's one of these in the treeidx dir for each tree in the treecas, named by the same treehash.
+//! They're meant to be read by tools (and people!) that want to know what's in a tree without walking it.
+//! See README_formats.md for the format.  In brief:
+//!
+//! ```text
+//! # garidx v1
+//!     2 ./ 040000 - afe3845ba76ec209f86
+//!     5 ./foo 100644 99 9c4fba7ef811632cde
+//! ```
+//!
+//! `write` produces them, and `Reader` parses them.  The parser is strict: anything that isn't exactly
+//! what the format says is an error (with `io::ErrorKind::InvalidData`), rather than something to guess about.
+//! That goes for the file as a whole, too: the root comes first, every path after it comes in order (and only once),
+//! and every hash is of the same kind.
+
+use std::fs;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::os::unix::ffi::OsStringExt as _;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Context as _, GarError, Stage};
+use crate::gittree;
+use crate::gittree::EntryKind;
+use crate::repo;
+use crate::treeobj;
+
+const HEADER: &[u8] = b"# garidx v1\n";
+
+/// Paths get a fixed five digits of length prefix.  (See README_formats.md for why that's considered enough.)
+const MAX_PATH_LEN: usize = 99999;
+
+/// The mode as garidx writes it.  Unlike in git trees, it's always six digits.
+fn mode(kind: EntryKind) -> &'static str {
+    match kind {
+        EntryKind::File => "100644",
+        EntryKind::Executable => "100755",
+        EntryKind::Symlink => "120000",
+        EntryKind::Dir => "040000",
+    }
+}
+
+fn parse_mode(b: &[u8]) -> Option<EntryKind> {
+    match b {
+        b"100644" => Some(EntryKind::File),
+        b"100755" => Some(EntryKind::Executable),
+        b"120000" => Some(EntryKind::Symlink),
+        b"040000" => Some(EntryKind::Dir),
+        _ => None,
+    }
+}
+
+/// Write a whole garidx: the header, and then the entries.
+///
+/// Entries should include the root itself (with an empty path), and be in order (see `gittree::hash_of_path_with_entries`).
+pub fn write(w: &mut impl Write, entries: &[gittree::HashedEntry]) -> io::Result<()> {
+    w.write_all(HEADER)?;
+    for ent in entries {
+        write_entry(w, ent)?;
+    }
+    Ok(())
+}
+
+pub fn write_entry(w: &mut impl Write, ent: &gittree::HashedEntry) -> io::Result<()> {
+    // Paths always start with a dot, and dirs get a trailing slash.  (The root is just "./".)
+    let mut path = b"./".to_vec();
+    path.extend_from_slice(ent.path.as_os_str().as_encoded_bytes());
+    if ent.kind == EntryKind::Dir && !ent.path.as_os_str().is_empty() {
+        path.push(b'/');
+    }
+    if path.len() > MAX_PATH_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{:?} is too long a path for a garidx", ent.path),
+        ));
+    }
+    let mut buf = Vec::with_capacity(path.len() + 6 + 8 + 20 + 46);
+    write!(buf, "{:5} ", path.len())?;
+    buf.extend_from_slice(&path);
+    write!(buf, " {} ", mode(ent.kind))?;
+    match ent.size {
+        Some(size) if ent.kind != EntryKind::Dir => write!(buf, "{}", size)?,
+        _ => buf.push(b'-'),
+    }
+    buf.push(b' ');
+    buf.extend_from_slice(bs58::encode(ent.hash.as_bytes()).into_string().as_bytes());
+    buf.push(b'\n');
+    w.write_all(&buf)
+}
+
+/// Write a garidx file into place, atomically: it's written aside first, and then renamed.
+pub fn write_file(path: &Path, entries: &[gittree::HashedEntry]) -> Result<(), GarError> {
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let td = tempdir::TempDir::new_in(dir, ".wipidx-").at(Stage::Index, dir)?;
+    let wip_path = td.path().join("idx");
+    (|| {
+        let mut w = io::BufWriter::new(fs::File::create(&wip_path)?);
+        write(&mut w, entries)?;
+        w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&wip_path, path)
+    })()
+    .at(Stage::Index, path)
+}
+
+/// Reads the entries of a garidx, one at a time.
+///
+/// Entries come out just as `write` takes them: paths relative to the root of the tree (the root itself being the empty path),
+/// without the leading dot or the trailing slash on dirs.
+///
+/// The header is checked on the first call to `next`.  After any error, the iterator is done.
+pub struct Reader<R> {
+    r: R,
+    /// How far into the file we are, for error messages.
+    offset: u64,
+    started: bool,
+    done: bool,
+    /// The path of the last entry read, which the next must come after.  (None until the root's been read.)
+    prev: Option<PathBuf>,
+    /// The kind of hash the first entry had, which the rest must have too.
+    format: Option<gittree::ObjectFormat>,
+}
+
+impl<R: BufRead> Reader<R> {
+    pub fn new(r: R) -> Self {
+        Self {
+            r,
+            offset: 0,
+            started: false,
+            done: false,
+            prev: None,
+            format: None,
+        }
+    }
+
+    fn invalid(&self, msg: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("garidx: {} (at byte {})", msg, self.offset),
+        )
+    }
+
+    /// Read exactly `n` bytes.  Running out of file partway is an error: an entry can't just stop.
+    fn take(&mut self, n: usize, what: &str) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        (&mut self.r).take(n as u64).read_to_end(&mut buf)?;
+        if buf.len() != n {
+            return Err(self.invalid(&format!("file ends in the middle of the {}", what)));
+        }
+        self.offset += n as u64;
+        Ok(buf)
+    }
+
+    /// Read up to (and consume) the delimiter, returning what came before it.
+    /// At most `max` bytes may precede it.
+    fn take_until(&mut self, delim: u8, max: usize, what: &str) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        (&mut self.r)
+            .take(max as u64 + 1)
+            .read_until(delim, &mut buf)?;
+        self.offset += buf.len() as u64;
+        if buf.pop() != Some(delim) {
+            return Err(self.invalid(&format!("{} is missing its terminator", what)));
+        }
+        Ok(buf)
+    }
+
+    fn expect(&mut self, want: u8, what: &str) -> io::Result<()> {
+        if self.take(1, what)?[0] != want {
+            return Err(self.invalid(&format!("expected {:?} after the {}", want as char, what)));
+        }
+        Ok(())
+    }
+
+    fn read_header(&mut self) -> io::Result<()> {
+        let header = self.take_until(b'\n', HEADER.len(), "header")?;
+        if header != HEADER[..HEADER.len() - 1] {
+            return Err(self.invalid("not a garidx v1 header"));
+        }
+        Ok(())
+    }
+
+    fn read_entry(&mut self) -> io::Result<Option<gittree::HashedEntry>> {
+        if self.r.fill_buf()?.is_empty() {
+            if self.prev.is_none() {
+                return Err(self.invalid("there's no entry for the root"));
+            }
+            return Ok(None);
+        }
+
+        // Length prefix: five bytes, a base-10 number right-aligned with spaces; then a space.
+        let len_field = self.take(6, "length prefix")?;
+        let digits = len_field[..5].trim_ascii_start();
+        if len_field[5] != b' ' || digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+            return Err(self.invalid("bad length prefix"));
+        }
+        let len: usize = std::str::from_utf8(digits).unwrap().parse().unwrap();
+
+        // The path, by that length.  (Never by looking for a delimiter: paths can contain spaces and linebreaks.)
+        let path = self.take(len, "path")?;
+        self.expect(b' ', "path")?;
+
+        let mode = self.take(6, "mode")?;
+        let kind = parse_mode(&mode).ok_or_else(|| self.invalid("unknown mode"))?;
+        self.expect(b' ', "mode")?;
+
+        // Size: decimal, or a dash for dirs.  (Twenty digits is enough for any u64.)
+        let size_field = self.take_until(b' ', 20, "size")?;
+        let size = match (kind, size_field.as_slice()) {
+            (EntryKind::Dir, b"-") => None,
+            (EntryKind::Dir, _) => return Err(self.invalid("dirs must have a dash for their size")),
+            (_, b) if !b.is_empty() && b.iter().all(u8::is_ascii_digit) => Some(
+                std::str::from_utf8(b)
+                    .unwrap()
+                    .parse()
+                    .map_err(|_| self.invalid("size is too large"))?,
+            ),
+            _ => return Err(self.invalid("bad size")),
+        };
+
+        // Base58 of 32 bytes is at most 44 characters.  (And it's a sha1 hash if it decodes to 20 bytes.)
+        let hash_field = self.take_until(b'\n', 44, "hash")?;
+        let mut hash = [0u8; 32];
+        let hash = match bs58::decode(&hash_field).onto(&mut hash) {
+            Ok(len) => gittree::Hash::from_bytes(&hash[..len]),
+            Err(_) => None,
+        }
+        .ok_or_else(|| self.invalid("bad hash"))?;
+
+        let path = self.decode_path(path, kind)?;
+        self.check_order(&path, &hash)?;
+        Ok(Some(gittree::HashedEntry {
+            path,
+            kind,
+            size,
+            hash,
+        }))
+    }
+
+    /// The root has to come first, and then the rest in order of path, with no repeats.
+    /// (That's the order `write` is given them in: see `gittree::hash_of_path_with_entries`.)
+    /// And a heap only has one kind of hash, so a file with both kinds in it is no garidx of any heap.
+    fn check_order(&mut self, path: &Path, hash: &gittree::Hash) -> io::Result<()> {
+        match &self.prev {
+            None if !path.as_os_str().is_empty() => {
+                return Err(self.invalid("the first entry must be the root"))
+            }
+            None => {}
+            Some(prev) if path <= prev.as_path() => {
+                return Err(self.invalid("entries must be in order of path, with no repeats"))
+            }
+            Some(_) => {}
+        }
+        if let Some(format) = self.format {
+            if hash.format() != format {
+                return Err(self.invalid("sha1 and sha256 hashes are mixed"));
+            }
+        }
+        self.format = Some(hash.format());
+        self.prev = Some(path.to_owned());
+        Ok(())
+    }
+
+    /// Turn "./foo/bar" (or "./foo/" for a dir) back into a relative path.
+    fn decode_path(&self, path: Vec<u8>, kind: EntryKind) -> io::Result<PathBuf> {
+        let rel = path
+            .strip_prefix(b"./")
+            .ok_or_else(|| self.invalid("paths must start with \"./\""))?;
+        let rel = match (kind, rel) {
+            (EntryKind::Dir, b"") => rel,
+            (EntryKind::Dir, _) => rel
+                .strip_suffix(b"/")
+                .ok_or_else(|| self.invalid("dir paths must end with a slash"))?,
+            (_, b"") => return Err(self.invalid("only a dir can be the root")),
+            (_, _) if rel.ends_with(b"/") => {
+                return Err(self.invalid("only dir paths may end with a slash"))
+            }
+            (_, _) => rel,
+        };
+        if rel
+            .split(|b| *b == b'/')
+            .any(|seg| matches!(seg, b"" | b"." | b".."))
+            && !rel.is_empty()
+        {
+            return Err(self.invalid("paths must be normal, relative paths"));
+        }
+        Ok(PathBuf::from(std::ffi::OsString::from_vec(rel.to_vec())))
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = io::Result<gittree::HashedEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = match self.started {
+            true => self.read_entry(),
+            false => {
+                self.started = true;
+                self.read_header().and_then(|_| self.read_entry())
+            }
+        };
+        match result {
+            Ok(Some(ent)) => Some(Ok(ent)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Parse a whole garidx.
+pub fn read(r: impl BufRead) -> io::Result<Vec<gittree::HashedEntry>> {
+    Reader::new(r).collect()
+}
+
+/// Regenerate the garidx for a tree that's already in the treecas, by walking it.
+///
+/// The tree is rehashed along the way, so this also checks that the treecas entry is what its name says it is.
+/// Any of its tree objects missing from the treeobj store are stored too.
+pub fn reindex(repo: &repo::Repo, hash: &gittree::Hash) -> Result<(), GarError> {
+    let _lock = repo.lock_shared()?;
+    let tree_path = repo.treecas_path().join(hash.as_hex());
+    if !tree_path.is_dir() {
+        return Err(GarError::InvalidInput {
+            reason: format!("there's no tree {} in the treecas", hash.as_hex()),
+        });
+    }
+    let (found_hash, entries) = gittree::hash_of_path_with_entries(
+        repo.object_format(),
+        &tree_path,
+        gittree::SpecialFilePolicy::Error,
+        None,
+        &mut Vec::new(),
+    )?;
+    if found_hash != *hash {
+        return Err(GarError::Corrupt {
+            path: tree_path,
+            reason: format!(
+                "actually hashes to {}; not indexing it",
+                found_hash.as_hex()
+            ),
+        });
+    }
+    // Fill in the tree objects, too, while we have everything needed for them.
+    let entries = with_root(found_hash, entries);
+    treeobj::store_from_entries(repo, &entries)?;
+    write_file(&repo.treeidx_path().join(hash.as_hex()), &entries)
+}
+
+/// Put the entry for the root of a tree on the front of the list of everything in it.
+pub fn with_root(
+    hash: gittree::Hash,
+    entries: Vec<gittree::HashedEntry>,
+) -> Vec<gittree::HashedEntry> {
+    let mut all = Vec::with_capacity(entries.len() + 1);
+    all.push(gittree::HashedEntry {
+        path: Default::default(),
+        kind: EntryKind::Dir,
+        size: None,
+        hash,
+    });
+    all.extend(entries);
+    all
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[test]
+    fn test_write_entries() {
+        let hash = gittree::Hash::from_hex(
+            "2909489adcb095aa795a9a7e6d92db735d0a0ced0782c43496675bdb7beec3ce",
+        )
+        .unwrap();
+        let entries = with_root(
+            hash.clone(),
+            vec![
+                gittree::HashedEntry {
+                    path: PathBuf::from("some"),
+                    kind: EntryKind::Dir,
+                    size: None,
+                    hash: hash.clone(),
+                },
+                gittree::HashedEntry {
+                    path: PathBuf::from("some/has a\nlinebreak"),
+                    kind: EntryKind::Executable,
+                    size: Some(7),
+                    hash: hash.clone(),
+                },
+            ],
+        );
+        let mut buf = Vec::new();
+        write(&mut buf, &entries).unwrap();
+        let b58 = bs58::encode(hash.as_bytes()).into_string();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            format!(
+                "# garidx v1\n    2 ./ 040000 - {b58}\n    7 ./some/ 040000 - {b58}\n   22 ./some/has a\nlinebreak 100755 7 {b58}\n"
+            )
+        );
+    }
+
+    #[rstest]
+    #[case::bad_header(b"# garidx v2\n")]
+    #[case::no_header(b"    2 ./ 040000 - 11111111111111111111111111111111\n")]
+    #[case::unknown_mode(b"# garidx v1\n    2 ./ 040001 - 11111111111111111111111111111111\n")]
+    #[case::short_mode(b"# garidx v1\n    5 ./foo 644 7 11111111111111111111111111111111\n")]
+    #[case::length_overruns(
+        b"# garidx v1\n   99 ./foo 100644 7 11111111111111111111111111111111\n"
+    )]
+    #[case::length_underruns(
+        b"# garidx v1\n    4 ./foo 100644 7 11111111111111111111111111111111\n"
+    )]
+    #[case::length_unpadded(
+        b"# garidx v1\n5     ./foo 100644 7 11111111111111111111111111111111\n"
+    )]
+    #[case::no_dot(b"# garidx v1\n    3 foo 100644 7 11111111111111111111111111111111\n")]
+    #[case::dir_without_slash(
+        b"# garidx v1\n    5 ./foo 040000 - 11111111111111111111111111111111\n"
+    )]
+    #[case::file_with_slash(
+        b"# garidx v1\n    6 ./foo/ 100644 7 11111111111111111111111111111111\n"
+    )]
+    #[case::dotdot(b"# garidx v1\n    9 ./../foo 100644 7 11111111111111111111111111111111\n")]
+    #[case::dir_with_size(b"# garidx v1\n    2 ./ 040000 7 11111111111111111111111111111111\n")]
+    #[case::file_without_size(
+        b"# garidx v1\n    5 ./foo 100644 - 11111111111111111111111111111111\n"
+    )]
+    #[case::short_hash(b"# garidx v1\n    5 ./foo 100644 7 1111\n")]
+    #[case::hash_not_base58(
+        b"# garidx v1\n    5 ./foo 100644 7 0OIl1111111111111111111111111111\n"
+    )]
+    #[case::torn(b"# garidx v1\n    5 ./foo 100644 7 1111111111111111")]
+    #[case::empty(b"# garidx v1\n")]
+    #[case::no_root(b"# garidx v1\n    5 ./foo 100644 7 11111111111111111111111111111111\n")]
+    #[case::root_twice(
+        b"# garidx v1\n    2 ./ 040000 - 11111111111111111111111111111111\n    2 ./ 040000 - 11111111111111111111111111111111\n"
+    )]
+    #[case::out_of_order(
+        b"# garidx v1\n    2 ./ 040000 - 11111111111111111111111111111111\n    5 ./foo 100644 7 11111111111111111111111111111111\n    5 ./bar 100644 7 11111111111111111111111111111111\n"
+    )]
+    #[case::repeated(
+        b"# garidx v1\n    2 ./ 040000 - 11111111111111111111111111111111\n    5 ./foo 100644 7 11111111111111111111111111111111\n    5 ./foo 100644 7 11111111111111111111111111111111\n"
+    )]
+    #[case::mixed_hashes(
+        b"# garidx v1\n    2 ./ 040000 - 11111111111111111111111111111111\n    5 ./foo 100644 7 11111111111111111111\n"
+    )]
+    fn test_read_rejects(#[case] body: &[u8]) {
+        let err = read(body).expect_err("parse to fail");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", err);
+    }
+
+    /// A tiny xorshift, so the fuzzing is repeatable without pulling in a whole crate for it.
+    struct Rng(u64);
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    fn random_entries(rng: &mut Rng) -> Vec<gittree::HashedEntry> {
+        // Names are drawn from an alphabet that's heavy on the troublemakers.
+        const ALPHABET: &[u8] = b"ab. -\n\r\t\\\xff";
+        let mut entries = Vec::new();
+        for _ in 0..rng.below(20) {
+            let depth = 1 + rng.below(3);
+            let mut path = Vec::new();
+            for d in 0..depth {
+                if d > 0 {
+                    path.push(b'/');
+                }
+                let mut name = Vec::new();
+                for _ in 0..1 + rng.below(8) {
+                    name.push(ALPHABET[rng.below(ALPHABET.len() as u64) as usize]);
+                }
+                // ("." and ".." aren't names anything can have.)
+                if name == b"." || name == b".." {
+                    name.push(b'a');
+                }
+                path.extend(name);
+            }
+            let kind = [
+                EntryKind::File,
+                EntryKind::Executable,
+                EntryKind::Symlink,
+                EntryKind::Dir,
+            ][rng.below(4) as usize];
+            let mut hash = [0u8; 32];
+            for b in hash.iter_mut() {
+                // Zero bytes are worth having plenty of: base58 treats leading ones specially.
+                *b = if rng.below(4) == 0 {
+                    0
+                } else {
+                    rng.next() as u8
+                };
+            }
+            entries.push(gittree::HashedEntry {
+                path: PathBuf::from(std::ffi::OsString::from_vec(path)),
+                kind,
+                size: match kind {
+                    EntryKind::Dir => None,
+                    _ => Some(rng.next() >> rng.below(64)),
+                },
+                hash: hash.into(),
+            });
+        }
+        // In order, and each path just once, as `write` is always given them.
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        entries.dedup_by(|a, b| a.path == b.path);
+        entries
+    }
+
+    #[test]
+    fn test_round_trip_fuzz() {
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for _ in 0..500 {
+            let entries = with_root([7u8; 32].into(), random_entries(&mut rng));
+            let mut buf = Vec::new();
+            write(&mut buf, &entries).unwrap();
+            let parsed = read(buf.as_slice()).expect("round trip to parse");
+            assert_eq!(parsed, entries);
+
+            // Every proper prefix of a valid file that doesn't end on an entry boundary must be rejected, not misread.
+            let cut = rng.below(buf.len() as u64) as usize;
+            match read(&buf[..cut]) {
+                Ok(parsed) => assert_eq!(parsed[..], entries[..parsed.len()]),
+                Err(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            }
+
+            // Flipping a byte must never panic.  (It might still parse, e.g. if it landed in a path.)
+            let mut mangled = buf.clone();
+            let i = rng.below(mangled.len() as u64) as usize;
+            mangled[i] ^= 1 << rng.below(8);
+            let _ = read(mangled.as_slice());
+
+            // Well-formed entries that don't make a whole garidx must be rejected too:
+            // without the root first, out of order, repeated, or with a sha1 hash among the sha256 ones.
+            let rejects = |entries: &[gittree::HashedEntry]| {
+                let mut buf = Vec::new();
+                write(&mut buf, entries).unwrap();
+                let err = read(buf.as_slice()).expect_err("parse to fail");
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", err);
+            };
+            rejects(&entries[1..]);
+            let i = rng.below(entries.len() as u64) as usize;
+            let mut repeated = entries.clone();
+            repeated.insert(i, entries[i].clone());
+            rejects(&repeated);
+            if entries.len() > 1 {
+                let mut rotated = entries.clone();
+                rotated.rotate_left(1);
+                rejects(&rotated);
+                let mut mixed = entries.clone();
+                mixed[i].hash = [7u8; 20].into();
+                rejects(&mixed);
+            }
+            if entries.len() > 2 {
+                let j = 1 + rng.below(entries.len() as u64 - 2) as usize;
+                let mut swapped = entries.clone();
+                swapped.swap(j, j + 1);
+                rejects(&swapped);
+            }
+        }
+    }
+}
@@ -0,0 +1,302 @@
+//! Exclusion rules for add, in the style of gitignore.
+//!
+//! Rules come from `.garignore` files in the tree being added (each applying to its own dir and everything below it),
+//! and from patterns given directly (e.g. `--exclude` flags), which apply from the root of the add.
+//!
+//! The pattern syntax is the familiar gitignore one:
+//!
+//! - blank lines, and lines starting with `#`, are ignored.
+//! - a leading `!` re-includes whatever the pattern matches.  (The last matching rule wins.)
+//! - a trailing `/` means the pattern only matches directories.
+//! - a pattern with a `/` anywhere else is anchored: it's matched against the whole path (relative to the dir of the `.garignore`).
+//!   Otherwise it's matched against just the name of each entry, at any depth.
+//! - `*` and `?` match anything except `/`; `[...]` is a character class; `**` matches across dirs
+//!   (as in `**/foo`, `foo/**`, or `a/**/b`); and `\` escapes the next character.
+//!
+//! As in git, once a dir is excluded, nothing within it can be re-included: we don't even look inside.
+//!
+//! `Exclusions` puts all of that together with the one other thing a walk of a source tree always leaves out, the gar heap itself.
+//! `gar add` and `gar hash` both walk with it, so they always agree on what a tree is.
+
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt as _;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Context as _, GarError, Stage};
+
+/// The name of the files in a tree that contain rules.
+pub const FILENAME: &str = ".garignore";
+
+#[derive(Debug, Clone)]
+struct Pattern {
+    /// The dir these rules came from, relative to the root of the add.
+    base: PathBuf,
+    glob: Vec<u8>,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    patterns: Vec<Pattern>,
+}
+
+impl Rules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add rules from the contents of a `.garignore` file (or anything else in that format).
+    /// `base` is the dir they apply to, relative to the root of the add.
+    pub fn add_lines(&mut self, base: &Path, body: &[u8]) {
+        for line in body.split(|b| *b == b'\n') {
+            self.add_line(base, line);
+        }
+    }
+
+    /// Add a single rule.  `base` is the dir it applies to, relative to the root of the add.
+    pub fn add_line(&mut self, base: &Path, line: &[u8]) {
+        let mut line = line.strip_suffix(b"\r").unwrap_or(line);
+        // Trailing spaces are dropped, unless they're escaped.
+        while line.ends_with(b" ") && !line.ends_with(b"\\ ") {
+            line = &line[..line.len() - 1];
+        }
+        if line.is_empty() || line[0] == b'#' {
+            return;
+        }
+        let negated = line[0] == b'!';
+        if negated {
+            line = &line[1..];
+        }
+        let dir_only = line.ends_with(b"/");
+        if dir_only {
+            line = &line[..line.len() - 1];
+        }
+        let anchored = line.contains(&b'/');
+        if let Some(rest) = line.strip_prefix(b"/") {
+            line = rest;
+        }
+        if line.is_empty() {
+            return;
+        }
+        self.patterns.push(Pattern {
+            base: base.to_owned(),
+            glob: line.to_owned(),
+            negated,
+            dir_only,
+            anchored,
+        });
+    }
+
+    /// Check a path (relative to the root of the add) against the rules.
+    /// Returns Some(true) if it's excluded, Some(false) if it's explicitly re-included, or None if no rule says anything about it.
+    pub fn matched(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        self.patterns
+            .iter()
+            .rev()
+            .find(|pat| pat.matches(path, is_dir))
+            .map(|pat| !pat.negated)
+    }
+
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        self.matched(path, is_dir).unwrap_or(false)
+    }
+}
+
+/// Everything a walk of a source tree leaves out: paths matched by patterns given directly (e.g. `--exclude` flags),
+/// paths matched by the `.garignore` files it finds (which the walk passes in as it goes: see `rules_in`), and the gar heap.
+#[derive(Debug, Clone, Default)]
+pub struct Exclusions {
+    /// Patterns given directly.  These take precedence over `.garignore` files.
+    patterns: Rules,
+    /// The (dev, ino) of the gar heap's dir, if there's one to stay out of.
+    /// (It's found by inode, not by name, since `gar add .` in the dir containing `.gar` is common, but so are other paths to it.)
+    heap: Option<(u64, u64)>,
+}
+
+impl Exclusions {
+    /// `patterns` apply from the root of the walk.  `heap` is the stat of the gar heap's dir, if any.
+    pub fn new(patterns: &[OsString], heap: Option<&fs::Metadata>) -> Self {
+        let mut rules = Rules::new();
+        for pattern in patterns {
+            rules.add_line(Path::new(""), pattern.as_encoded_bytes());
+        }
+        Self {
+            patterns: rules,
+            heap: heap.map(|meta| (meta.dev(), meta.ino())),
+        }
+    }
+
+    /// Whether to leave out the entry at `path` (relative to the root of the walk), which has the stat `meta`.
+    /// `rules` are those from the `.garignore` files of the dirs it's in.
+    pub fn excludes(&self, rules: &Rules, path: &Path, meta: &fs::Metadata) -> bool {
+        let is_dir = meta.is_dir();
+        if is_dir && self.heap == Some((meta.dev(), meta.ino())) {
+            return true;
+        }
+        self.patterns
+            .matched(path, is_dir)
+            .or_else(|| rules.matched(path, is_dir))
+            .unwrap_or(false)
+    }
+}
+
+/// The rules that apply inside the dir at `scan_path` (which is at `path`, relative to the root of the walk):
+/// `rules` from the dirs above it, plus those of its own `.garignore`, if it has one.
+pub fn rules_in<'a>(
+    rules: &'a Rules,
+    scan_path: &Path,
+    path: &Path,
+) -> Result<Cow<'a, Rules>, GarError> {
+    let file_path = scan_path.join(FILENAME);
+    match fs::read(&file_path) {
+        Ok(body) => {
+            let mut r = rules.clone();
+            r.add_lines(path, &body);
+            Ok(Cow::Owned(r))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Cow::Borrowed(rules)),
+        Err(e) => Err(e).at(Stage::Scan, &file_path),
+    }
+}
+
+impl Pattern {
+    fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let rel = match path.strip_prefix(&self.base) {
+            Ok(rel) => rel,
+            Err(_) => return false,
+        };
+        if self.anchored {
+            glob_match(&self.glob, rel.as_os_str().as_encoded_bytes())
+        } else {
+            match rel.file_name() {
+                Some(name) => glob_match(&self.glob, name.as_encoded_bytes()),
+                None => false,
+            }
+        }
+    }
+}
+
+fn glob_match(p: &[u8], s: &[u8]) -> bool {
+    if p.is_empty() {
+        return s.is_empty();
+    }
+    if let Some(rest) = p.strip_prefix(b"**") {
+        if rest.is_empty() {
+            return true;
+        }
+        if let Some(rest) = rest.strip_prefix(b"/") {
+            // Zero or more whole dirs.
+            return glob_match(rest, s)
+                || s.iter()
+                    .enumerate()
+                    .any(|(i, b)| *b == b'/' && glob_match(rest, &s[i + 1..]));
+        }
+        // A "**" that isn't a whole path segment is just a "*".
+        return glob_match(&p[1..], s);
+    }
+    match p[0] {
+        b'*' => {
+            for i in 0..=s.len() {
+                if glob_match(&p[1..], &s[i..]) {
+                    return true;
+                }
+                if i < s.len() && s[i] == b'/' {
+                    break;
+                }
+            }
+            false
+        }
+        b'?' => !s.is_empty() && s[0] != b'/' && glob_match(&p[1..], &s[1..]),
+        b'[' => match class_match(&p[1..], s.first().copied()) {
+            Some((matched, rest)) => matched && glob_match(rest, &s[1..]),
+            // No closing bracket: it's just a bracket.
+            None => s.first() == Some(&b'[') && glob_match(&p[1..], &s[1..]),
+        },
+        b'\\' if p.len() > 1 => s.first() == Some(&p[1]) && glob_match(&p[2..], &s[1..]),
+        c => s.first() == Some(&c) && glob_match(&p[1..], &s[1..]),
+    }
+}
+
+/// Match a character class (starting just after the opening bracket) against one byte.
+/// Returns whether it matched and the rest of the pattern after the class; or None if the class isn't closed.
+fn class_match(p: &[u8], c: Option<u8>) -> Option<(bool, &[u8])> {
+    let (negated, mut i) = match p.first() {
+        Some(b'!') | Some(b'^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let lo = *p.get(i)?;
+        if lo == b']' && !first {
+            break;
+        }
+        first = false;
+        if p.get(i + 1) == Some(&b'-') && p.get(i + 2).is_some_and(|b| *b != b']') {
+            let hi = p[i + 2];
+            matched |= c.is_some_and(|c| lo <= c && c <= hi);
+            i += 3;
+        } else {
+            matched |= c == Some(lo);
+            i += 1;
+        }
+    }
+    let matched = c.is_some_and(|c| c != b'/') && matched != negated;
+    Some((matched, &p[i + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest]
+    #[case("*.swp", "a/b/.foo.swp", false, true)]
+    #[case("*.swp", "a/b/foo.swpx", false, false)]
+    #[case("target/", "target", true, true)]
+    #[case("target/", "target", false, false)]
+    #[case("target/", "sub/target", true, true)]
+    #[case("/target", "sub/target", true, false)]
+    #[case("sub/target", "sub/target", true, true)]
+    #[case("**/deep", "a/b/c/deep", false, true)]
+    #[case("**/deep", "deep", false, true)]
+    #[case("a/**/b", "a/b", false, true)]
+    #[case("a/**/b", "a/x/y/b", false, true)]
+    #[case("a/**", "a/x/y", false, true)]
+    #[case("a/*", "a/x/y", false, false)]
+    #[case("f?o", "foo", false, true)]
+    #[case("f[a-o]o", "foo", false, true)]
+    #[case("f[!a-o]o", "foo", false, false)]
+    #[case("\\#hash", "#hash", false, true)]
+    fn test_match(
+        #[case] line: &str,
+        #[case] path: &str,
+        #[case] is_dir: bool,
+        #[case] expected: bool,
+    ) {
+        let mut rules = Rules::new();
+        rules.add_line(Path::new(""), line.as_bytes());
+        assert_eq!(rules.is_excluded(Path::new(path), is_dir), expected);
+    }
+
+    #[test]
+    fn test_negation_and_base() {
+        let mut rules = Rules::new();
+        rules.add_lines(Path::new(""), b"# logs\n*.log\n\n!keep.log\n");
+        rules.add_lines(Path::new("sub"), b"/only-here\n");
+        assert!(rules.is_excluded(Path::new("x/a.log"), false));
+        assert!(!rules.is_excluded(Path::new("x/keep.log"), false));
+        assert_eq!(rules.matched(Path::new("x/keep.log"), false), Some(false));
+        assert!(rules.is_excluded(Path::new("sub/only-here"), false));
+        assert!(!rules.is_excluded(Path::new("only-here"), false));
+        assert!(!rules.is_excluded(Path::new("sub/deeper/only-here"), false));
+    }
+}
@@ -0,0 +1,276 @@
+//! Exporting trees into a git repository, as loose objects.
+//!
+//! Gar's hashes are git's object IDs, so a tree exported this way has the same hash in git as it does in the heap,
+//! and `git ls-tree <hash>` (or `git read-tree`, or `git commit-tree`...) works on it straight away.
+//!
+//! Trees come from the treeobj store, and file blobs from the blobcas.  Symlinks aren't stored anywhere but in the tree itself,
+//! so those are read from the tree's entry in the treecas, which is why only trees at the treecas root can be exported.
+//! Everything written is rehashed on the way out, so nothing corrupt makes it into the git repo.
+//!
+//! Objects git already has are skipped.  Children are always written before their parents,
+//! so finding a tree already there means everything under it is too, and it's skipped entirely.
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt as _;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::add;
+use crate::error::{Context as _, GarError, Stage};
+use crate::gitrepo;
+use crate::gittree;
+use crate::gittree::EntryKind;
+use crate::repo;
+use crate::treeobj;
+
+/// Counts of objects, by whether they had to be written.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ExportReport {
+    pub written: u64,
+    /// Objects that were already in the git repo.  (Not counting what's under a tree that was already there.)
+    pub existing: u64,
+}
+
+/// Export the tree `hash`, and everything in it, into the git repo whose git dir (e.g. its `.git`) is `git_dir`.
+pub fn export(
+    repo: &repo::Repo,
+    hash: &gittree::Hash,
+    git_dir: &Path,
+) -> Result<ExportReport, GarError> {
+    let objects_path = git_dir.join("objects");
+    if !objects_path.is_dir() {
+        return Err(GarError::InvalidInput {
+            reason: format!(
+                "{:?} doesn't look like a git dir (it has no objects dir)",
+                git_dir
+            ),
+        });
+    }
+    let git_format = gitrepo::object_format(git_dir)?;
+    if git_format != repo.object_format() {
+        return Err(GarError::InvalidInput {
+            reason: format!(
+                "the git repo at {:?} uses {} object IDs, but this gar heap uses {}",
+                git_dir,
+                git_format.name(),
+                repo.object_format().name()
+            ),
+        });
+    }
+    let _lock = repo.lock_shared()?;
+    let tree_path = repo.treecas_path().join(hash.as_hex());
+    if !tree_path.is_dir() {
+        return Err(GarError::InvalidInput {
+            reason: format!("there's no tree {} in the treecas", hash.as_hex()),
+        });
+    }
+
+    // Objects are written aside first, then renamed into place, so git never sees half of one.
+    // (If we're interrupted, this is cleaned up when it drops; or, failing that, git's own gc would clear it away eventually.)
+    let staging =
+        tempdir::TempDir::new_in(&objects_path, "tmp_gar_export_").at(Stage::Git, &objects_path)?;
+    let mut x = Exporter {
+        repo,
+        objects_path,
+        staging_path: staging.path().to_owned(),
+        tree_path,
+        report: ExportReport::default(),
+    };
+    x.export_tree(hash, Path::new(""))?;
+    Ok(x.report)
+}
+
+struct Exporter<'a> {
+    repo: &'a repo::Repo,
+    objects_path: PathBuf,
+    staging_path: PathBuf,
+    /// The tree being exported, in the treecas.  (Where symlinks are read from.)
+    tree_path: PathBuf,
+    report: ExportReport,
+}
+
+impl Exporter<'_> {
+    /// Export a tree, at `rel` in the tree being exported, after everything in it.
+    fn export_tree(&mut self, hash: &gittree::Hash, rel: &Path) -> Result<(), GarError> {
+        if self.object_path(hash).exists() {
+            self.report.existing += 1;
+            return Ok(());
+        }
+        let tree = treeobj::load(self.repo, hash)?.ok_or_else(|| GarError::InvalidInput {
+            reason: format!(
+                "there's no tree object for {} (at {:?}) in the treeobj store; `gar reindex` can regenerate them",
+                hash.as_hex(),
+                rel
+            ),
+        })?;
+        for ent in &tree.entries {
+            let ent_rel = rel.join(std::ffi::OsStr::from_bytes(&ent.name));
+            match ent.kind {
+                EntryKind::Dir => self.export_tree(&ent.hash, &ent_rel)?,
+                EntryKind::File | EntryKind::Executable => {
+                    let executable = ent.kind == EntryKind::Executable;
+                    let blobcas_path = self
+                        .repo
+                        .blobcas_path()
+                        .join(add::blob_name(&ent.hash, executable));
+                    self.export_blob_file(&ent.hash, &blobcas_path)?;
+                }
+                EntryKind::Symlink => {
+                    let link_path = self.tree_path.join(&ent_rel);
+                    let target = fs::read_link(&link_path).at(Stage::Git, &link_path)?;
+                    let body = target.into_os_string().into_encoded_bytes();
+                    self.write_object(
+                        &ent.hash,
+                        "blob",
+                        &link_path,
+                        &mut &body[..],
+                        body.len() as u64,
+                    )?;
+                }
+            }
+        }
+        let (_, payload) = tree.encode(hash.format());
+        let tree_obj_path = self.repo.treeobj_path().join(hash.as_hex());
+        self.write_object(
+            hash,
+            "tree",
+            &tree_obj_path,
+            &mut &payload[..],
+            payload.len() as u64,
+        )
+    }
+
+    fn export_blob_file(&mut self, hash: &gittree::Hash, path: &Path) -> Result<(), GarError> {
+        if self.object_path(hash).exists() {
+            self.report.existing += 1;
+            return Ok(());
+        }
+        let mut f = fs::File::open(path).at(Stage::Git, path)?;
+        let size = f.metadata().at(Stage::Git, path)?.len();
+        self.write_object(hash, "blob", path, &mut f, size)
+    }
+
+    /// Where git keeps a loose object.
+    fn object_path(&self, hash: &gittree::Hash) -> PathBuf {
+        let hex = hash.as_hex();
+        self.objects_path.join(&hex[..2]).join(&hex[2..])
+    }
+
+    /// Write a loose object (unless it's there already), checking that its contents really do hash to `hash`.
+    /// `source` is where the contents came from, for error messages.
+    fn write_object(
+        &mut self,
+        hash: &gittree::Hash,
+        object_type: &str,
+        source: &Path,
+        body: &mut dyn io::Read,
+        size: u64,
+    ) -> Result<(), GarError> {
+        let dest_path = self.object_path(hash);
+        if dest_path.exists() {
+            self.report.existing += 1;
+            return Ok(());
+        }
+        // A loose object is the zlib'd whole of what gets hashed: header and all.
+        let staged_path = self.staging_path.join(hash.as_hex());
+        let f = fs::File::create(&staged_path).at(Stage::Git, &staged_path)?;
+        let mut z = flate2::write::ZlibEncoder::new(f, flate2::Compression::default());
+        write!(z, "{} {}\0", object_type, size).at(Stage::Git, &staged_path)?;
+        let found_hash = gittree::hash_of_object_stream(
+            hash.format(),
+            object_type,
+            &mut io_tee::TeeReader::new(body, &mut z),
+            size,
+        )
+        .at(Stage::Git, source)?;
+        if found_hash != *hash {
+            return Err(GarError::Corrupt {
+                path: source.to_owned(),
+                reason: format!(
+                    "should hash to {}, but actually hashes to {}; not exporting it",
+                    hash.as_hex(),
+                    found_hash.as_hex()
+                ),
+            });
+        }
+        z.finish()
+            .and_then(|f| f.sync_all())
+            // (Git makes its objects read-only, so we do too.)
+            .and_then(|_| fs::set_permissions(&staged_path, fs::Permissions::from_mode(0o444)))
+            .at(Stage::Git, &staged_path)?;
+        let dir = dest_path.parent().expect("objects are in a dir");
+        fs::create_dir_all(dir)
+            .and_then(|_| fs::rename(&staged_path, &dest_path))
+            .at(Stage::Git, &dest_path)?;
+        self.report.written += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn git(git_dir: &Path, args: &[&str]) -> String {
+        let out = Command::new("git")
+            .arg("--git-dir")
+            .arg(git_dir)
+            .args(args)
+            .output()
+            .expect("running git");
+        assert!(out.status.success(), "git {:?} failed: {:?}", args, out);
+        String::from_utf8(out.stdout).unwrap()
+    }
+
+    #[test]
+    fn test_export() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = repo::Repo::new(td.path().join("heap")).unwrap();
+        r.create_dir_all().unwrap();
+        let opts = crate::add::AddOptions {
+            faithmode: crate::add::FaithMode::Copy,
+            ..Default::default()
+        };
+        let report = crate::add::add(&r, "fixtures/tricky", &opts).expect("add to succeed");
+        let hex = report.hash.as_hex();
+
+        // A sha1 repo is the wrong kind.
+        let sha1_dir = td.path().join("sha1.git");
+        git(&sha1_dir, &["init", "--quiet", "--bare"]);
+        let err = export(&r, &report.hash, &sha1_dir).expect_err("formats differ");
+        assert!(matches!(err, GarError::InvalidInput { .. }));
+
+        let git_dir = td.path().join("sha256.git");
+        git(
+            &git_dir,
+            &["init", "--quiet", "--bare", "--object-format=sha256"],
+        );
+        let first = export(&r, &report.hash, &git_dir).expect("export to succeed");
+        // Trees: the root, foo, and foo.d.  Blobs: eight files, and a symlink.
+        assert_eq!(
+            first,
+            ExportReport {
+                written: 12,
+                existing: 0
+            }
+        );
+        git(&git_dir, &["fsck", "--strict", "--no-dangling"]);
+        let ls = git(&git_dir, &["ls-tree", "-r", &hex]);
+        assert!(ls.contains("120000 blob "), "{}", ls);
+        assert!(ls.contains("100755 blob "), "{}", ls);
+        assert!(ls.contains("\tfoo/x\n"), "{}", ls);
+
+        // Again: the root is there already, so that's all there is to it.
+        let again = export(&r, &report.hash, &git_dir).expect("export to succeed");
+        assert_eq!(
+            again,
+            ExportReport {
+                written: 0,
+                existing: 1
+            }
+        );
+    }
+}
@@ -0,0 +1,399 @@
+//! Importing trees from a git repository: the other direction from `gitexport`.
+//!
+//! Trees and blobs are read straight out of the git repo's object store (see `gitrepo`), with no checkout to a scratch dir.
+//! Blobs go into the blobcas (with the "-x" suffix when an entry is executable), tree objects into the treeobj store,
+//! and then the treecas entry is made from those, just as `add` makes one.
+//!
+//! Every object is rehashed as it's read, and every tree is re-encoded and rehashed from its entries,
+//! so the result is only ever a tree whose hash is the git tree ID we were asked for.
+//!
+//! Blobs are streamed: inflated, hashed, and written to a staging file all in one pass, so they needn't fit in memory.
+//! (Except for blobs stored as deltas, which `gitrepo` has to build in memory.)
+
+use std::ffi::OsString;
+use std::fs;
+use std::os::unix::ffi::{OsStrExt as _, OsStringExt as _};
+use std::os::unix::fs::OpenOptionsExt as _;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::add::{self, Found, FoundContent};
+use crate::error::{Context as _, GarError, Stage};
+use crate::garidx;
+use crate::gitrepo;
+use crate::gittree;
+use crate::gittree::EntryKind;
+use crate::repo;
+use crate::treeobj;
+
+/// What came of an import.
+#[derive(Debug)]
+pub struct ImportReport {
+    pub hash: gittree::Hash,
+    /// Whether the tree was new to the treecas, and so had to be built there.
+    pub new_tree: bool,
+    /// Blobs that had to be put in the blobcas.
+    pub blobs_written: u64,
+    /// Blobs that were already in the blobcas.
+    pub blobs_existing: u64,
+}
+
+/// Import the tree that `treeish` names (see `gitrepo::GitRepo::resolve_tree`) from the git repo at `git_dir`.
+pub fn import(
+    repo: &repo::Repo,
+    git_dir: &Path,
+    treeish: &str,
+    no_index: bool,
+) -> Result<ImportReport, GarError> {
+    let _lock = repo.lock_shared()?;
+    let git = gitrepo::GitRepo::open(git_dir)?;
+    if git.format() != repo.object_format() {
+        return Err(GarError::InvalidInput {
+            reason: format!(
+                "the git repo at {:?} uses {} object IDs, but this gar heap uses {}",
+                git_dir,
+                git.format().name(),
+                repo.object_format().name()
+            ),
+        });
+    }
+    let hash = git.resolve_tree(treeish)?;
+
+    // Blobs are written here first, and only linked into their CAS-named place once they're whole.
+    let staging = tempdir::TempDir::new_in(repo.blobcas_path(), ".wipblob-")
+        .at(Stage::Blobcas, repo.blobcas_path())?;
+    let mut im = Importer {
+        repo,
+        git: &git,
+        staging_path: staging.path().to_owned(),
+        index: Vec::new(),
+        blobs_written: 0,
+        blobs_existing: 0,
+    };
+    let found = im.import_tree(&hash, Path::new(""))?;
+
+    // Then the treecas entry, if it's new, just like at the end of an add.
+    let dest_path = repo.treecas_path().join(hash.as_hex());
+    let new_tree = !dest_path.exists();
+    if new_tree {
+        let td = tempdir::TempDir::new_in(repo.treecas_path(), ".wiptree-")
+            .at(Stage::Wiptree, repo.treecas_path())?;
+        add::materialize(repo, td.path(), &found)?;
+        match add::commit_wiptree(td, &dest_path) {
+            Ok(()) | Err(GarError::TreeExists { .. }) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let idx_path = repo.treeidx_path().join(hash.as_hex());
+    if !no_index && !idx_path.exists() {
+        let mut entries = im.index;
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        garidx::write_file(&idx_path, &garidx::with_root(hash.clone(), entries))?;
+    }
+    Ok(ImportReport {
+        hash,
+        new_tree,
+        blobs_written: im.blobs_written,
+        blobs_existing: im.blobs_existing,
+    })
+}
+
+struct Importer<'a> {
+    repo: &'a repo::Repo,
+    git: &'a gitrepo::GitRepo,
+    staging_path: PathBuf,
+    /// Entries for the garidx.
+    index: Vec<gittree::HashedEntry>,
+    blobs_written: u64,
+    blobs_existing: u64,
+}
+
+impl Importer<'_> {
+    /// Import a tree (at `rel` in the tree being imported), and everything in it.  Returns what's in it.
+    fn import_tree(&mut self, hash: &gittree::Hash, rel: &Path) -> Result<Vec<Found>, GarError> {
+        let body = self.read(hash, gitrepo::ObjectType::Tree)?;
+        // Decoding is strict, so anything gar couldn't have made itself (like a submodule) stops us here.
+        let tree = gittree::TreeObject::decode(hash.format(), &body).map_err(|e| {
+            GarError::InvalidInput {
+                reason: format!("can't import tree {} (at {:?}): {}", hash.as_hex(), rel, e),
+            }
+        })?;
+        // The self-test: the tree, rebuilt from its entries by our own code, has to hash to what git calls it.
+        let (rebuilt_hash, payload) = tree.encode(hash.format());
+        if rebuilt_hash != *hash {
+            return Err(self.corrupt(hash, format!("rebuilds to {}", rebuilt_hash.as_hex())));
+        }
+        treeobj::store(self.repo, hash, &payload)?;
+
+        let mut found = Vec::with_capacity(tree.entries.len());
+        for ent in tree.entries {
+            let name = OsString::from_vec(ent.name);
+            let ent_rel = rel.join(&name);
+            let (size, content) = match ent.kind {
+                EntryKind::Dir => {
+                    let children = self.import_tree(&ent.hash, &ent_rel)?;
+                    (None, FoundContent::Dir(children))
+                }
+                EntryKind::File | EntryKind::Executable => {
+                    let size = self.import_blob(&ent.hash, ent.kind == EntryKind::Executable)?;
+                    (Some(size), FoundContent::Blob)
+                }
+                EntryKind::Symlink => {
+                    let body = self.read(&ent.hash, gitrepo::ObjectType::Blob)?;
+                    let target = PathBuf::from(OsString::from_vec(body));
+                    (
+                        Some(target.as_os_str().as_bytes().len() as u64),
+                        FoundContent::Symlink(target),
+                    )
+                }
+            };
+            self.index.push(gittree::HashedEntry {
+                path: ent_rel,
+                kind: ent.kind,
+                size,
+                hash: ent.hash.clone(),
+            });
+            found.push(Found {
+                name,
+                kind: ent.kind,
+                hash: ent.hash,
+                content,
+            });
+        }
+        Ok(found)
+    }
+
+    /// Get a blob into the blobcas, unless it's already there.  Returns its size.
+    fn import_blob(&mut self, hash: &gittree::Hash, executable: bool) -> Result<u64, GarError> {
+        let blobcas_path = self
+            .repo
+            .blobcas_path()
+            .join(add::blob_name(hash, executable));
+        if let Ok(meta) = fs::metadata(&blobcas_path) {
+            self.blobs_existing += 1;
+            return Ok(meta.len());
+        }
+        let mut stream = self.git.open_object(hash)?;
+        self.check_type(hash, stream.object_type, gitrepo::ObjectType::Blob)?;
+        let staged_path = self.staging_path.join(hash.as_hex());
+        let mode = if executable { 0o755 } else { 0o644 };
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(mode)
+            .open(&staged_path)
+            .and_then(|f| {
+                // (Set explicitly, since the mode given at open time is subject to umask.)
+                f.set_permissions(fs::Permissions::from_mode(mode))?;
+                Ok(f)
+            })
+            .at(Stage::Blobcas, &staged_path)?;
+        // The body goes to the staging file as it's hashed.  Nothing's linked into the blobcas until the hash checks out.
+        let size = stream.size;
+        let found_hash = gittree::hash_of_object_stream(
+            hash.format(),
+            "blob",
+            &mut io_tee::TeeReader::new(&mut stream, &mut f),
+            size,
+        )
+        .at(Stage::Git, &stream.path)?;
+        if found_hash != *hash {
+            return Err(self.corrupt(hash, format!("actually hashes to {}", found_hash.as_hex())));
+        }
+        // Hardlink, not rename, so as never to replace a blob that's already there: see `add` for why.
+        add::ok_if_exists(fs::hard_link(&staged_path, &blobcas_path))
+            .and_then(|_| fs::remove_file(&staged_path))
+            .at(Stage::Blobcas, &blobcas_path)?;
+        self.blobs_written += 1;
+        Ok(size)
+    }
+
+    /// Read an object of a type we expect, and check that it hashes to what it's called.
+    fn read(
+        &self,
+        hash: &gittree::Hash,
+        expected: gitrepo::ObjectType,
+    ) -> Result<Vec<u8>, GarError> {
+        let (object_type, body) = self.git.read_object(hash)?;
+        self.check_type(hash, object_type, expected)?;
+        let found_hash = gittree::hash_of_object_stream(
+            hash.format(),
+            expected.name(),
+            &mut &body[..],
+            body.len() as u64,
+        )
+        .expect("reading from memory can't fail");
+        if found_hash != *hash {
+            return Err(self.corrupt(hash, format!("actually hashes to {}", found_hash.as_hex())));
+        }
+        Ok(body)
+    }
+
+    fn check_type(
+        &self,
+        hash: &gittree::Hash,
+        object_type: gitrepo::ObjectType,
+        expected: gitrepo::ObjectType,
+    ) -> Result<(), GarError> {
+        match object_type == expected {
+            true => Ok(()),
+            false => Err(self.corrupt(
+                hash,
+                format!("is a {}, not a {}", object_type.name(), expected.name()),
+            )),
+        }
+    }
+
+    fn corrupt(&self, hash: &gittree::Hash, what: String) -> GarError {
+        GarError::Corrupt {
+            path: PathBuf::from(hash.as_hex()),
+            reason: format!("object in the git repo {}", what),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write as _;
+    use std::process::Command;
+
+    fn git(git_dir: &Path, args: &[&str]) -> String {
+        let out = Command::new("git")
+            .arg("--git-dir")
+            .arg(git_dir)
+            .args(["-c", "user.name=gar", "-c", "user.email=gar@example.com"])
+            .args(args)
+            .output()
+            .expect("running git");
+        assert!(out.status.success(), "git {:?} failed: {:?}", args, out);
+        String::from_utf8(out.stdout).unwrap()
+    }
+
+    fn fresh_repo(path: &Path) -> repo::Repo {
+        let r = repo::Repo::new(path).unwrap();
+        r.create_dir_all().unwrap();
+        r
+    }
+
+    #[test]
+    fn test_import() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let git_dir = td.path().join("src.git");
+        git(
+            &git_dir,
+            &["init", "--quiet", "--bare", "--object-format=sha256"],
+        );
+        let work_tree = std::path::absolute("fixtures/tricky").unwrap();
+        let work_tree = work_tree.to_str().unwrap();
+        git(&git_dir, &["--work-tree", work_tree, "add", "--all"]);
+        let tree_hex = git(&git_dir, &["write-tree"]);
+        let commit_hex = git(&git_dir, &["commit-tree", "-m", "tricky", tree_hex.trim()]);
+        git(
+            &git_dir,
+            &["update-ref", "refs/heads/main", commit_hex.trim()],
+        );
+        git(&git_dir, &["tag", "-a", "-m", "a tag", "v1", "main"]);
+        let expected = gittree::hash_of_path(Default::default(), "fixtures/tricky").unwrap();
+        assert_eq!(tree_hex.trim(), expected.as_hex());
+
+        // Loose objects, to start with.  (And a tag, which gets peeled down to the tree.)
+        let r = fresh_repo(&td.path().join("heap"));
+        let report = import(&r, &git_dir, "v1", false).expect("import to succeed");
+        assert_eq!(report.hash, expected);
+        assert!(report.new_tree);
+        assert_eq!((report.blobs_written, report.blobs_existing), (8, 0));
+        let tree_path = r.treecas_path().join(expected.as_hex());
+        assert_eq!(
+            gittree::hash_of_path(r.object_format(), &tree_path).unwrap(),
+            expected
+        );
+        let exe_meta = fs::metadata(tree_path.join("foo.sh")).unwrap();
+        assert_eq!(exe_meta.permissions().mode() & 0o777, 0o755);
+        assert_eq!(
+            fs::read_link(tree_path.join("foo=link")).unwrap(),
+            Path::new("foo.txt")
+        );
+        assert!(r.treeidx_path().join(expected.as_hex()).exists());
+
+        // It's the same thing an add would've made: adding the same files finds the tree, and every blob, already there.
+        let opts = add::AddOptions {
+            faithmode: add::FaithMode::Copy,
+            ..Default::default()
+        };
+        let added = add::add(&r, "fixtures/tricky", &opts).expect("add to succeed");
+        assert_eq!(added.hash, expected);
+        assert!(!added.new_tree);
+
+        // Now from packs, with deltas: into a fresh heap, by branch name.
+        git(&git_dir, &["repack", "--quiet", "-a", "-d", "-f"]);
+        git(&git_dir, &["prune-packed"]);
+        let r = fresh_repo(&td.path().join("heap2"));
+        assert!(fs::read_dir(git_dir.join("objects/pack")).unwrap().count() > 0);
+        let report = import(&r, &git_dir, "main", false).expect("import to succeed");
+        assert_eq!(report.hash, expected);
+
+        // Importing again has nothing to do.
+        let again = import(&r, &git_dir, &expected.as_hex(), false).expect("import to succeed");
+        assert!(!again.new_tree);
+        assert_eq!((again.blobs_written, again.blobs_existing), (0, 8));
+
+        // Trees that don't exist, and repos of the wrong format, are refused.
+        let err = import(&r, &git_dir, "no-such-ref", false).expect_err("no such tree");
+        assert!(matches!(err, GarError::InvalidInput { .. }));
+        let sha1_dir = td.path().join("sha1.git");
+        git(&sha1_dir, &["init", "--quiet", "--bare"]);
+        let err = import(&r, &sha1_dir, "HEAD", false).expect_err("formats differ");
+        assert!(matches!(err, GarError::InvalidInput { .. }));
+    }
+
+    #[test]
+    fn test_import_corrupt_blob() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let git_dir = td.path().join("src.git");
+        git(
+            &git_dir,
+            &["init", "--quiet", "--bare", "--object-format=sha256"],
+        );
+        let work_tree = td.path().join("work");
+        fs::create_dir(&work_tree).unwrap();
+        fs::write(work_tree.join("a.txt"), "one\n").unwrap();
+        fs::write(work_tree.join("b.txt"), "two\n").unwrap();
+        let work_tree = work_tree.to_str().unwrap();
+        git(&git_dir, &["--work-tree", work_tree, "add", "--all"]);
+        let tree_hex = git(&git_dir, &["write-tree"]);
+        let loose_path = |name: &str| {
+            let hex = git(
+                &git_dir,
+                &["rev-parse", &format!("{}:{}", tree_hex.trim(), name)],
+            );
+            git_dir
+                .join("objects")
+                .join(&hex[..2])
+                .join(hex[2..].trim())
+        };
+
+        // One blob's object swapped for another's: it's streamed into staging, but never makes it into the blobcas.
+        // (a.txt comes first, so nothing else has been imported by the time that's found.)
+        let (a_path, b_path) = (loose_path("a.txt"), loose_path("b.txt"));
+        fs::remove_file(&a_path).unwrap();
+        fs::copy(&b_path, &a_path).unwrap();
+        let r = fresh_repo(&td.path().join("heap"));
+        let err = import(&r, &git_dir, tree_hex.trim(), false).expect_err("import to fail");
+        assert!(matches!(err, GarError::Corrupt { .. }), "{}", err);
+        let blobs: Vec<_> = fs::read_dir(r.blobcas_path())
+            .unwrap()
+            .map(|ent| ent.unwrap().file_name())
+            .filter(|name| !name.to_string_lossy().starts_with('.'))
+            .collect();
+        assert!(blobs.is_empty(), "{:?}", blobs);
+
+        // One whose body runs past the size its header says is an error too.
+        let mut z = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        z.write_all(b"blob 4\0one\nand more").unwrap();
+        fs::remove_file(&a_path).unwrap();
+        fs::write(&a_path, z.finish().unwrap()).unwrap();
+        let err = import(&r, &git_dir, tree_hex.trim(), false).expect_err("import to fail");
+        assert!(err.to_string().contains("not the size"), "{}", err);
+    }
+}
@@ -0,0 +1,847 @@
+//! Reading objects out of a local git repository: loose objects, and packfiles (deltas and all).
+//!
+//! This is just enough of git to get trees and blobs out, and to turn a ref or a commit into the tree it names.
+//! It doesn't shell out to git, and it doesn't need git installed.
+//!
+//! What's not covered: alternates (`objects/info/alternates`), version 1 pack indexes (git hasn't written those since 2007),
+//! and anything to do with writing packs.
+//!
+//! Objects can be read whole into memory, or streamed (see `GitRepo::open_object`), so a big blob never has to fit in memory.
+//! The exception is an object stored as a delta: that's built up in memory from its base, so it comes out of memory too.
+
+use std::fs;
+use std::io;
+use std::io::{BufRead, Read, Seek};
+use std::path::{Path, PathBuf};
+
+use crate::error::{Context as _, GarError, Stage};
+use crate::gittree;
+
+/// The types of git object.  (Deltas, in packs, are just a way of storing one of these.)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ObjectType {
+    Commit,
+    Tree,
+    Blob,
+    Tag,
+}
+
+impl ObjectType {
+    pub fn name(self) -> &'static str {
+        match self {
+            ObjectType::Commit => "commit",
+            ObjectType::Tree => "tree",
+            ObjectType::Blob => "blob",
+            ObjectType::Tag => "tag",
+        }
+    }
+
+    fn from_name(name: &[u8]) -> Option<Self> {
+        match name {
+            b"commit" => Some(ObjectType::Commit),
+            b"tree" => Some(ObjectType::Tree),
+            b"blob" => Some(ObjectType::Blob),
+            b"tag" => Some(ObjectType::Tag),
+            _ => None,
+        }
+    }
+}
+
+/// What object format a git repo uses, per its config.  (Repos without `extensions.objectformat` are sha1.)
+pub fn object_format(git_dir: &Path) -> Result<gittree::ObjectFormat, GarError> {
+    let config_path = git_dir.join("config");
+    let config = match fs::read_to_string(&config_path) {
+        Ok(config) => config,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).at(Stage::Git, &config_path),
+    };
+    // This isn't a real git config parser: it only needs to find the one key, in the one section.
+    let mut section = String::new();
+    for line in config.lines() {
+        let line = line.trim();
+        if let Some(header) = line.strip_prefix('[') {
+            section = header.trim_end_matches(']').trim().to_ascii_lowercase();
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        if section == "extensions" && key.trim().eq_ignore_ascii_case("objectformat") {
+            let value = value.trim();
+            return gittree::ObjectFormat::from_name(value).ok_or_else(|| GarError::InvalidInput {
+                reason: format!(
+                    "the git repo at {:?} uses an unknown object format {:?}",
+                    git_dir, value
+                ),
+            });
+        }
+    }
+    Ok(gittree::ObjectFormat::Sha1)
+}
+
+/// A git repo, opened for reading objects.
+pub struct GitRepo {
+    git_dir: PathBuf,
+    format: gittree::ObjectFormat,
+    packs: Vec<Pack>,
+}
+
+impl GitRepo {
+    /// Open the git repo whose git dir (e.g. its `.git`, or a bare repo) is `git_dir`.
+    pub fn open(git_dir: &Path) -> Result<Self, GarError> {
+        let objects_path = git_dir.join("objects");
+        if !objects_path.is_dir() {
+            return Err(GarError::InvalidInput {
+                reason: format!(
+                    "{:?} doesn't look like a git dir (it has no objects dir)",
+                    git_dir
+                ),
+            });
+        }
+        let format = object_format(git_dir)?;
+        let pack_dir = objects_path.join("pack");
+        let mut packs = Vec::new();
+        match fs::read_dir(&pack_dir) {
+            Ok(rd) => {
+                for ent in rd {
+                    let idx_path = ent.at(Stage::Git, &pack_dir)?.path();
+                    if idx_path.extension().is_some_and(|ext| ext == "idx") {
+                        packs.push(Pack::open(&idx_path, format)?);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).at(Stage::Git, &pack_dir),
+        }
+        Ok(GitRepo {
+            git_dir: git_dir.to_owned(),
+            format,
+            packs,
+        })
+    }
+
+    pub fn format(&self) -> gittree::ObjectFormat {
+        self.format
+    }
+
+    /// Read an object, whole.  Its absence is an error.
+    ///
+    /// (This doesn't check the object's hash: callers that care about that can rehash it, with `gittree::hash_of_object_stream`.)
+    pub fn read_object(&self, hash: &gittree::Hash) -> Result<(ObjectType, Vec<u8>), GarError> {
+        let mut stream = self.open_object(hash)?;
+        let mut body = Vec::new();
+        stream.read_to_end(&mut body).at(Stage::Git, &stream.path)?;
+        Ok((stream.object_type, body))
+    }
+
+    /// Open an object, to read its body as it's inflated.  Its absence is an error.
+    ///
+    /// Reading fails if the body turns out not to be the size its header claimed.
+    /// (As with `read_object`, the hash isn't checked.)
+    pub fn open_object(&self, hash: &gittree::Hash) -> Result<ObjectStream, GarError> {
+        let hex = hash.as_hex();
+        let loose_path = self.loose_path(hash);
+        match fs::File::open(&loose_path) {
+            Ok(f) => {
+                let (object_type, size, body) = open_loose(f).at(Stage::Git, &loose_path)?;
+                return Ok(ObjectStream {
+                    object_type,
+                    size,
+                    path: loose_path,
+                    body,
+                });
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).at(Stage::Git, &loose_path),
+        }
+        if let Some((pack, offset)) = self.find_packed(hash) {
+            let (object_type, size, body) =
+                pack.open_at(offset, self).at(Stage::Git, &pack.pack_path)?;
+            return Ok(ObjectStream {
+                object_type,
+                size,
+                path: pack.pack_path.clone(),
+                body,
+            });
+        }
+        Err(GarError::InvalidInput {
+            reason: format!(
+                "there's no object {} in the git repo at {:?}",
+                hex, self.git_dir
+            ),
+        })
+    }
+
+    fn loose_path(&self, hash: &gittree::Hash) -> PathBuf {
+        let hex = hash.as_hex();
+        self.git_dir.join("objects").join(&hex[..2]).join(&hex[2..])
+    }
+
+    /// Which pack an object's in, and where, if it's in one.
+    fn find_packed(&self, hash: &gittree::Hash) -> Option<(&Pack, u64)> {
+        self.packs
+            .iter()
+            .find_map(|pack| Some((pack, pack.find(hash)?)))
+    }
+
+    /// Turn a "tree-ish" into the hash of a tree.
+    ///
+    /// That can be a full hex object ID, or a ref name (like "HEAD", "main", "v1.0", or "refs/heads/main",
+    /// looked up the same way git does), naming a tree, or a commit or tag that leads to one.
+    pub fn resolve_tree(&self, treeish: &str) -> Result<gittree::Hash, GarError> {
+        let mut hash = match self.parse_hex(treeish) {
+            Some(hash) => hash,
+            None => self
+                .resolve_ref(treeish)?
+                .ok_or_else(|| GarError::InvalidInput {
+                    reason: format!(
+                        "{:?} is neither an object ID nor a ref in the git repo at {:?}",
+                        treeish, self.git_dir
+                    ),
+                })?,
+        };
+        // Peel: tags to what they tag, and commits to their tree.
+        // (Only so many times, in case of a loop, which would take a hash collision but never mind.)
+        for _ in 0..100 {
+            let (object_type, body) = self.read_object(&hash)?;
+            let peel_field = match object_type {
+                ObjectType::Tree => return Ok(hash),
+                ObjectType::Commit => "tree ",
+                ObjectType::Tag => "object ",
+                ObjectType::Blob => {
+                    return Err(GarError::InvalidInput {
+                        reason: format!("{:?} is a blob, not a tree", treeish),
+                    })
+                }
+            };
+            hash = body
+                .lines()
+                .map_while(Result::ok)
+                .take_while(|line| !line.is_empty())
+                .find_map(|line| {
+                    line.strip_prefix(peel_field)
+                        .and_then(|hex| self.parse_hex(hex))
+                })
+                .ok_or_else(|| GarError::Corrupt {
+                    path: self.git_dir.clone(),
+                    reason: format!(
+                        "{} {} has no {}line",
+                        object_type.name(),
+                        hash.as_hex(),
+                        peel_field
+                    ),
+                })?;
+        }
+        Err(GarError::InvalidInput {
+            reason: format!("{:?} doesn't lead to a tree", treeish),
+        })
+    }
+
+    /// A hex hash, if it's one of the right length for this repo.
+    fn parse_hex(&self, hex: &str) -> Option<gittree::Hash> {
+        gittree::Hash::from_hex(hex)
+            .ok()
+            .filter(|hash| hash.format() == self.format)
+    }
+
+    /// Look up a ref, trying the same places git does, in the same order.
+    fn resolve_ref(&self, name: &str) -> Result<Option<gittree::Hash>, GarError> {
+        if name.is_empty()
+            || name
+                .split('/')
+                .any(|part| part.is_empty() || part.starts_with('.'))
+        {
+            return Ok(None);
+        }
+        for candidate in [
+            name.to_owned(),
+            format!("refs/{}", name),
+            format!("refs/tags/{}", name),
+            format!("refs/heads/{}", name),
+            format!("refs/remotes/{}", name),
+            format!("refs/remotes/{}/HEAD", name),
+        ] {
+            if let Some(hash) = self.read_ref(&candidate, 0)? {
+                return Ok(Some(hash));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read one ref by its full name: as a file (maybe a symbolic one), or else from packed-refs.
+    fn read_ref(&self, full_name: &str, depth: usize) -> Result<Option<gittree::Hash>, GarError> {
+        if depth > 10 {
+            return Ok(None);
+        }
+        let ref_path = self.git_dir.join(full_name);
+        match fs::read_to_string(&ref_path) {
+            Ok(body) => {
+                let body = body.trim();
+                if let Some(target) = body.strip_prefix("ref: ") {
+                    return self.read_ref(target.trim(), depth + 1);
+                }
+                return Ok(self.parse_hex(body));
+            }
+            // (A dir, like "refs/heads" itself, isn't a ref.)
+            Err(e) if e.kind() == io::ErrorKind::NotFound || ref_path.is_dir() => {}
+            Err(e) => return Err(e).at(Stage::Git, &ref_path),
+        }
+        let packed_path = self.git_dir.join("packed-refs");
+        let packed = match fs::read_to_string(&packed_path) {
+            Ok(packed) => packed,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).at(Stage::Git, &packed_path),
+        };
+        Ok(packed
+            .lines()
+            .filter(|line| !line.starts_with('#') && !line.starts_with('^'))
+            .filter_map(|line| line.split_once(' '))
+            .find(|(_, name)| *name == full_name)
+            .and_then(|(hex, _)| self.parse_hex(hex)))
+    }
+}
+
+/// An object being read, from `GitRepo::open_object`.  Its type and size are known up front; its body is what's read.
+pub struct ObjectStream {
+    pub object_type: ObjectType,
+    pub size: u64,
+    /// Where it's being read from (a loose object, or a pack), for reporting errors.
+    pub path: PathBuf,
+    body: Box<dyn Read>,
+}
+
+impl Read for ObjectStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.body.read(buf)
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Reads exactly `remaining` bytes, and fails if there turn out to be fewer, or more.
+/// (`what` is the complaint either way.)
+struct Exact<R> {
+    inner: R,
+    remaining: u64,
+    what: &'static str,
+}
+
+impl<R: Read> Read for Exact<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            return match self.inner.read(&mut [0])? {
+                0 => Ok(0),
+                _ => Err(invalid(self.what)),
+            };
+        }
+        let max = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..max])?;
+        if n == 0 {
+            return Err(invalid(self.what));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// A loose object is zlib'd: "{type} {size}\0" and then the body.
+fn read_loose(f: fs::File) -> io::Result<(ObjectType, Vec<u8>)> {
+    let (object_type, _, mut body) = open_loose(f)?;
+    // (No preallocating from the header's size: it's just a claim, until we've read that much.)
+    let mut out = Vec::new();
+    body.read_to_end(&mut out)?;
+    Ok((object_type, out))
+}
+
+/// Read a loose object's header, leaving its body to be read from what's returned.
+fn open_loose(f: fs::File) -> io::Result<(ObjectType, u64, Box<dyn Read>)> {
+    let mut z = io::BufReader::new(flate2::read::ZlibDecoder::new(f));
+    let mut object_type = Vec::new();
+    z.read_until(b' ', &mut object_type)?;
+    let object_type = object_type
+        .strip_suffix(b" ")
+        .and_then(ObjectType::from_name)
+        .ok_or_else(|| invalid("loose object has a bad type"))?;
+    let mut size = Vec::new();
+    z.read_until(0, &mut size)?;
+    let size: u64 = size
+        .strip_suffix(b"\0")
+        .and_then(|s| std::str::from_utf8(s).ok())
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid("loose object has a bad size"))?;
+    let body = Exact {
+        inner: z,
+        remaining: size,
+        what: "loose object is not the size its header says",
+    };
+    Ok((object_type, size, Box::new(body)))
+}
+
+/// The longest chain of deltas we'll follow.  (Git itself won't make one longer than this.)
+const MAX_DELTA_DEPTH: usize = 4095;
+
+/// A packfile, and its index.
+///
+/// The index (version 2) is read whole when the pack is opened: it's a fanout table, then all the hashes in order,
+/// then their CRCs (which we don't need), then their offsets in the pack (with an extra table for offsets over 2GiB).
+struct Pack {
+    pack_path: PathBuf,
+    hash_len: usize,
+    fanout: Vec<u32>,
+    hashes: Vec<u8>,
+    offsets: Vec<u32>,
+    large_offsets: Vec<u64>,
+}
+
+impl Pack {
+    fn open(idx_path: &Path, format: gittree::ObjectFormat) -> Result<Self, GarError> {
+        let pack_path = idx_path.with_extension("pack");
+        let idx = fs::read(idx_path).at(Stage::Git, idx_path)?;
+        Self::parse_idx(&idx, pack_path, format.hash_len()).map_err(|reason| GarError::Corrupt {
+            path: idx_path.to_owned(),
+            reason: reason.to_owned(),
+        })
+    }
+
+    fn parse_idx(idx: &[u8], pack_path: PathBuf, hash_len: usize) -> Result<Self, &'static str> {
+        let u32_at = |pos: usize| -> Result<u32, &'static str> {
+            idx.get(pos..pos + 4)
+                .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+                .ok_or("pack index is truncated")
+        };
+        if idx.get(..4) != Some(b"\xfftOc") || u32_at(4)? != 2 {
+            return Err("only version 2 pack indexes are supported");
+        }
+        let fanout = (0..256)
+            .map(|i| u32_at(8 + i * 4))
+            .collect::<Result<Vec<_>, _>>()?;
+        // Each fanout entry counts the hashes starting with a byte up to its own, so they can't go down;
+        // `find` relies on that to stay within the tables.
+        if fanout.windows(2).any(|w| w[0] > w[1]) {
+            return Err("pack index fanout table isn't in order");
+        }
+        let n = fanout[255] as usize;
+        let hashes_at = 8 + 256 * 4;
+        let offsets_at = hashes_at + n * hash_len + n * 4;
+        let large_at = offsets_at + n * 4;
+        let hashes = idx
+            .get(hashes_at..hashes_at + n * hash_len)
+            .ok_or("pack index is truncated")?
+            .to_vec();
+        let offsets = (0..n)
+            .map(|i| u32_at(offsets_at + i * 4))
+            .collect::<Result<Vec<_>, _>>()?;
+        let n_large = offsets.iter().filter(|&&o| o & 0x8000_0000 != 0).count();
+        let large_offsets = (0..n_large)
+            .map(|i| {
+                Ok(((u32_at(large_at + i * 8)? as u64) << 32)
+                    | u32_at(large_at + i * 8 + 4)? as u64)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Pack {
+            pack_path,
+            hash_len,
+            fanout,
+            hashes,
+            offsets,
+            large_offsets,
+        })
+    }
+
+    /// Where an object is in the pack, if it's in it at all.
+    fn find(&self, hash: &gittree::Hash) -> Option<u64> {
+        let want = hash.as_bytes();
+        let first = want[0] as usize;
+        // The fanout says how many hashes start with a byte up to and including this one, so they bracket the search.
+        let lo = if first == 0 {
+            0
+        } else {
+            self.fanout[first - 1] as usize
+        };
+        let hi = self.fanout[first] as usize;
+        let (mut lo, mut hi) = (lo, hi);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            match self.hashes[mid * self.hash_len..(mid + 1) * self.hash_len].cmp(want) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return self.offset(mid),
+            }
+        }
+        None
+    }
+
+    /// The offset in the pack of the `i`th object in the index.
+    fn offset(&self, i: usize) -> Option<u64> {
+        let offset = self.offsets[i];
+        Some(match offset & 0x8000_0000 {
+            0 => offset as u64,
+            _ => *self.large_offsets.get((offset & 0x7fff_ffff) as usize)?,
+        })
+    }
+
+    /// Read the object at an offset in the pack, resolving deltas against their bases.
+    /// (Bases named by hash, rather than offset, can be anywhere in the repo, so this needs it.)
+    ///
+    /// The chain of deltas is followed down to its base first, and then applied back up, in a loop rather than by recursion,
+    /// so that a long chain can't overflow the stack (and one that loops is stopped, by `MAX_DELTA_DEPTH`).
+    fn read_at(&self, offset: u64, repo: &GitRepo) -> io::Result<(ObjectType, Vec<u8>)> {
+        let mut deltas = Vec::new();
+        let (mut pack, mut offset) = (self, offset);
+        let (object_type, mut body) = loop {
+            if deltas.len() > MAX_DELTA_DEPTH {
+                return Err(invalid("delta chain is too long (or loops)"));
+            }
+            match pack.read_entry(offset)? {
+                Entry::Whole(object_type, body) => break (object_type, body),
+                Entry::OffsetDelta(base_offset, delta) => {
+                    deltas.push(delta);
+                    offset = base_offset;
+                }
+                Entry::RefDelta(base_hash, delta) => {
+                    deltas.push(delta);
+                    if let Some(found) = repo.find_packed(&base_hash) {
+                        (pack, offset) = found;
+                        continue;
+                    }
+                    // A base that isn't packed is loose (if it's anywhere), and so isn't a delta itself.
+                    let base = fs::File::open(repo.loose_path(&base_hash)).and_then(read_loose);
+                    break base.map_err(|e| {
+                        invalid(format!("delta base {}: {}", base_hash.as_hex(), e))
+                    })?;
+                }
+            }
+        };
+        for delta in deltas.iter().rev() {
+            body = apply_delta(&body, delta)?;
+        }
+        Ok((object_type, body))
+    }
+
+    /// Open the object at an offset in the pack, to be read as it's inflated: see `GitRepo::open_object`.
+    /// (A delta can't be read that way: it's resolved in memory, by `read_at`, and read from there.)
+    fn open_at(&self, offset: u64, repo: &GitRepo) -> io::Result<(ObjectType, u64, Box<dyn Read>)> {
+        let (f, type_code, size) = self.open_entry(offset)?;
+        if let Some(object_type) = whole_type(type_code) {
+            let body = Exact {
+                inner: flate2::bufread::ZlibDecoder::new(f),
+                remaining: size,
+                what: "pack entry is not the size its header says",
+            };
+            return Ok((object_type, size, Box::new(body)));
+        }
+        drop(f);
+        let (object_type, body) = self.read_at(offset, repo)?;
+        Ok((
+            object_type,
+            body.len() as u64,
+            Box::new(io::Cursor::new(body)),
+        ))
+    }
+
+    /// Read the header of an entry of the pack, at an offset: its type code, and its (inflated) size.
+    /// Returns those, and the pack, ready to read what follows.
+    fn open_entry(&self, offset: u64) -> io::Result<(io::BufReader<fs::File>, u8, u64)> {
+        let mut f = io::BufReader::new(fs::File::open(&self.pack_path)?);
+        f.seek(io::SeekFrom::Start(offset))?;
+
+        // The entry header: a type, and the (inflated) size, as a little-endian varint of sorts.
+        let mut byte = read_u8(&mut f)?;
+        let type_code = (byte >> 4) & 0x7;
+        let mut size = (byte & 0x0f) as u64;
+        let mut shift = 4;
+        while byte & 0x80 != 0 {
+            if shift > 63 {
+                return Err(invalid("pack entry size is too big"));
+            }
+            byte = read_u8(&mut f)?;
+            size |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+        }
+        Ok((f, type_code, size))
+    }
+
+    /// Read one entry of the pack, at an offset: either a whole object, or a delta and where to find its base.
+    fn read_entry(&self, offset: u64) -> io::Result<Entry> {
+        let (mut f, type_code, size) = self.open_entry(offset)?;
+        if let Some(object_type) = whole_type(type_code) {
+            return Ok(Entry::Whole(object_type, inflate(&mut f, size)?));
+        }
+        Ok(match type_code {
+            // An offset delta: the base is earlier in this pack, by a big-endian varint (with a twist: each continuation adds one).
+            6 => {
+                let mut byte = read_u8(&mut f)?;
+                let mut back = (byte & 0x7f) as u64;
+                while byte & 0x80 != 0 {
+                    if back >= 1 << 56 {
+                        return Err(invalid("delta base offset is too big"));
+                    }
+                    byte = read_u8(&mut f)?;
+                    back = ((back + 1) << 7) | (byte & 0x7f) as u64;
+                }
+                // (A base always comes before its delta; one that doesn't would have us going round in circles.)
+                if back == 0 {
+                    return Err(invalid("delta is its own base"));
+                }
+                let base_offset = offset
+                    .checked_sub(back)
+                    .ok_or_else(|| invalid("delta base is before the start of the pack"))?;
+                Entry::OffsetDelta(base_offset, inflate(&mut f, size)?)
+            }
+            // A ref delta: the base is named by hash.
+            7 => {
+                let mut base_hash = vec![0; self.hash_len];
+                f.read_exact(&mut base_hash)?;
+                let base_hash =
+                    gittree::Hash::from_bytes(&base_hash).expect("hash_len is a length we know");
+                Entry::RefDelta(base_hash, inflate(&mut f, size)?)
+            }
+            _ => return Err(invalid("unknown pack entry type")),
+        })
+    }
+}
+
+/// The type of a pack entry that's a whole object (rather than a delta), by its type code.
+fn whole_type(type_code: u8) -> Option<ObjectType> {
+    match type_code {
+        1 => Some(ObjectType::Commit),
+        2 => Some(ObjectType::Tree),
+        3 => Some(ObjectType::Blob),
+        4 => Some(ObjectType::Tag),
+        _ => None,
+    }
+}
+
+/// One entry of a pack.
+enum Entry {
+    Whole(ObjectType, Vec<u8>),
+    /// A delta, and the offset of its base in the same pack.
+    OffsetDelta(u64, Vec<u8>),
+    /// A delta, and the hash of its base.
+    RefDelta(gittree::Hash, Vec<u8>),
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut b = [0];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+/// Inflate a zlib stream that should come out to exactly `size` bytes.
+fn inflate(r: &mut impl BufRead, size: u64) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    Exact {
+        inner: flate2::bufread::ZlibDecoder::new(r),
+        remaining: size,
+        what: "pack entry is not the size its header says",
+    }
+    .read_to_end(&mut out)?;
+    Ok(out)
+}
+
+/// Apply a git delta: a source size and a result size (as little-endian varints),
+/// then instructions to either copy a range of the base, or insert new bytes.
+fn apply_delta(base: &[u8], delta: &[u8]) -> io::Result<Vec<u8>> {
+    let mut d = delta.iter().copied();
+    let mut next = move || d.next().ok_or_else(|| invalid("delta is truncated"));
+    let varint = |next: &mut dyn FnMut() -> io::Result<u8>| -> io::Result<u64> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            if shift > 63 {
+                return Err(invalid("delta size is too big"));
+            }
+            let b = next()?;
+            value |= ((b & 0x7f) as u64) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    };
+    let base_size = varint(&mut next)?;
+    let result_size = varint(&mut next)?;
+    if base_size != base.len() as u64 {
+        return Err(invalid("delta base is the wrong size"));
+    }
+    let mut out = Vec::new();
+    while (out.len() as u64) < result_size {
+        let cmd = next()?;
+        if cmd & 0x80 != 0 {
+            // Copy: which of the offset and size bytes are present is in the low seven bits.
+            let mut offset = 0usize;
+            for i in 0..4 {
+                if cmd & (1 << i) != 0 {
+                    offset |= (next()? as usize) << (8 * i);
+                }
+            }
+            let mut size = 0usize;
+            for i in 0..3 {
+                if cmd & (0x10 << i) != 0 {
+                    size |= (next()? as usize) << (8 * i);
+                }
+            }
+            if size == 0 {
+                size = 0x10000;
+            }
+            let src = offset
+                .checked_add(size)
+                .and_then(|end| base.get(offset..end))
+                .ok_or_else(|| invalid("delta copies from outside its base"))?;
+            out.extend_from_slice(src);
+        } else if cmd != 0 {
+            // Insert: the command is the count of literal bytes that follow.
+            for _ in 0..cmd {
+                out.push(next()?);
+            }
+        } else {
+            return Err(invalid("delta has a reserved instruction"));
+        }
+    }
+    if out.len() as u64 != result_size || next().is_ok() {
+        return Err(invalid("delta result is the wrong size"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write as _;
+    use std::process::{Command, Stdio};
+
+    fn git(git_dir: &Path, args: &[&str], stdin: &[u8]) -> String {
+        let mut child = Command::new("git")
+            .arg("--git-dir")
+            .arg(git_dir)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("running git");
+        child.stdin.take().unwrap().write_all(stdin).unwrap();
+        let out = child.wait_with_output().unwrap();
+        assert!(out.status.success(), "git {:?} failed: {:?}", args, out);
+        String::from_utf8(out.stdout).unwrap()
+    }
+
+    #[test]
+    fn test_read_packed_deltas() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let git_dir = td.path().join("repo.git");
+        git(&git_dir, &["init", "--quiet", "--bare"], b"");
+
+        // A few versions of a file that differ a little each time, which a repack will store as deltas of one another.
+        let mut versions = Vec::new();
+        let mut body: Vec<u8> = (0..2000)
+            .flat_map(|i| format!("line {}\n", i).into_bytes())
+            .collect();
+        for v in 0..4 {
+            body.extend_from_slice(format!("and version {}\n", v).as_bytes());
+            let hex = git(&git_dir, &["hash-object", "-w", "--stdin"], &body);
+            versions.push((gittree::Hash::from_hex(hex.trim()).unwrap(), body.clone()));
+        }
+        let listing: String = versions
+            .iter()
+            .enumerate()
+            .map(|(v, (hash, _))| format!("100644 blob {}\tf{}\n", hash.as_hex(), v))
+            .collect();
+        let tree_hex = git(&git_dir, &["mktree"], listing.as_bytes());
+        // (Repacking only keeps what's reachable from a ref.)
+        git(
+            &git_dir,
+            &["update-ref", "refs/tags/t", tree_hex.trim()],
+            b"",
+        );
+
+        // Loose, first.
+        let repo = GitRepo::open(&git_dir).unwrap();
+        for (hash, body) in &versions {
+            assert_eq!(
+                repo.read_object(hash).unwrap(),
+                (ObjectType::Blob, body.clone())
+            );
+        }
+
+        git(&git_dir, &["repack", "--quiet", "-a", "-d", "-f"], b"");
+        git(&git_dir, &["prune-packed"], b"");
+        let pack = fs::read_dir(git_dir.join("objects/pack"))
+            .unwrap()
+            .map(|ent| ent.unwrap().path())
+            .find(|path| path.extension() == Some("pack".as_ref()))
+            .expect("a pack");
+        let verify = git(
+            &git_dir,
+            &["verify-pack", "-v", pack.to_str().unwrap()],
+            b"",
+        );
+        assert!(verify.contains("chain length = "), "no deltas: {}", verify);
+
+        let repo = GitRepo::open(&git_dir).unwrap();
+        for (hash, body) in &versions {
+            assert_eq!(
+                repo.read_object(hash).unwrap(),
+                (ObjectType::Blob, body.clone())
+            );
+        }
+        assert_eq!(repo.resolve_tree("t").unwrap().as_hex(), tree_hex.trim());
+        let missing = gittree::Hash::from([7u8; 20]);
+        assert!(repo.read_object(&missing).is_err());
+    }
+
+    #[test]
+    fn test_malformed_packs() {
+        // A fanout table that goes down would send `find` outside the tables.
+        let mut idx = b"\xfftOc\0\0\0\x02".to_vec();
+        for i in 0..256u32 {
+            idx.extend_from_slice(&(if i == 7 { 1u32 } else { 0 }).to_be_bytes());
+        }
+        assert!(Pack::parse_idx(&idx, PathBuf::new(), 20).is_err());
+
+        // Deltas whose sizes are nonsense are errors, not panics (or enormous allocations).
+        assert!(apply_delta(b"", &[0x80; 12]).is_err());
+        let huge = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f];
+        assert!(apply_delta(b"", &huge).is_err());
+
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let git_dir = td.path().join("repo.git");
+        git(&git_dir, &["init", "--quiet", "--bare"], b"");
+        let pack_path = td.path().join("bogus.pack");
+        let hash = gittree::Hash::from([7u8; 20]);
+        let mut fanout = vec![0; 256];
+        fanout[7..].fill(1);
+        let repo = GitRepo {
+            git_dir,
+            format: gittree::ObjectFormat::Sha1,
+            packs: vec![Pack {
+                pack_path: pack_path.clone(),
+                hash_len: 20,
+                fanout,
+                hashes: hash.as_bytes().to_vec(),
+                offsets: vec![0],
+                large_offsets: Vec::new(),
+            }],
+        };
+        let read = |entry: &[u8]| {
+            fs::write(&pack_path, entry).unwrap();
+            repo.read_object(&hash)
+        };
+        // An offset delta that's its own base.
+        assert!(matches!(read(&[0x60, 0x00]), Err(GarError::Io { .. })));
+        // A ref delta that's its own base.  (With an empty delta, which is at least well-formed zlib.)
+        let mut z = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        z.write_all(b"").unwrap();
+        let empty = z.finish().unwrap();
+        let err = read(&[&[0x70][..], hash.as_bytes(), &empty].concat()).unwrap_err();
+        assert!(err.to_string().contains("too long"), "{}", err);
+        // A size that runs on and on.
+        assert!(matches!(read(&[0xb0; 16]), Err(GarError::Io { .. })));
+    }
+}
@@ -1,4 +1,5 @@
 use bytes::BufMut;
+use bytes::Bytes;
 use bytes::BytesMut;
 use sha2::Digest;
 use std::fmt::Debug;
@@ -6,35 +7,172 @@ use std::fs;
 use std::io;
 use std::os::unix::fs::MetadataExt;
 use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-#[derive(PartialEq)]
-pub struct Hash([u8; 32]);
+use crate::error::{Context as _, GarError, Stage};
+use crate::garignore;
+
+/// Which hash function object IDs are made with.
+///
+/// Git itself has two: repos made with `git init --object-format=sha256` use SHA-256, and classic ones use SHA-1.
+/// A gar heap uses one or the other throughout, as its config says (see `repo::Repo::object_format`).
+/// Either way, hashes are exactly the object IDs git would compute, for the same content, in a repo of the same format.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectFormat {
+    Sha1,
+    #[default]
+    Sha256,
+}
+
+impl ObjectFormat {
+    /// The name, as git spells it (e.g. in `extensions.objectformat`).
+    pub fn name(self) -> &'static str {
+        match self {
+            ObjectFormat::Sha1 => "sha1",
+            ObjectFormat::Sha256 => "sha256",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha1" => Some(ObjectFormat::Sha1),
+            "sha256" => Some(ObjectFormat::Sha256),
+            _ => None,
+        }
+    }
+
+    /// How many bytes a hash is.
+    pub fn hash_len(self) -> usize {
+        match self {
+            ObjectFormat::Sha1 => 20,
+            ObjectFormat::Sha256 => 32,
+        }
+    }
+
+    fn hasher(self) -> Hasher {
+        match self {
+            ObjectFormat::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            ObjectFormat::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+}
+
+/// A hash, and which hash function it came from.
+#[derive(PartialEq, Eq, Hash, Clone)]
+pub enum Hash {
+    Sha1([u8; 20]),
+    Sha256([u8; 32]),
+}
 
 impl Debug for Hash {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_tuple("Hash").field(&hex::encode(&self.0)).finish()
+        f.debug_tuple("Hash").field(&self.as_hex()).finish()
     }
 }
 
 impl Hash {
+    /// Parse a hash from hex.  Its length says what format it's in.
     pub fn from_hex<S: AsRef<[u8]>>(hex: S) -> Result<Self, hex::FromHexError> {
-        let mut out = [0u8; 32];
-        hex::decode_to_slice(hex, &mut out)?;
-        Ok(Self(out))
+        let bytes = hex::decode(hex)?;
+        Self::from_bytes(&bytes).ok_or(hex::FromHexError::InvalidStringLength)
+    }
+
+    /// A hash from its raw bytes.  Their length says what format it's in; any length but the ones we know is None.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes.len() {
+            20 => Some(Hash::Sha1(bytes.try_into().unwrap())),
+            32 => Some(Hash::Sha256(bytes.try_into().unwrap())),
+            _ => None,
+        }
     }
 
     pub fn as_hex(&self) -> String {
-        return hex::encode(self.0);
+        hex::encode(self.as_bytes())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Hash::Sha1(bytes) => bytes,
+            Hash::Sha256(bytes) => bytes,
+        }
+    }
+
+    pub fn format(&self) -> ObjectFormat {
+        match self {
+            Hash::Sha1(_) => ObjectFormat::Sha1,
+            Hash::Sha256(_) => ObjectFormat::Sha256,
+        }
     }
 }
 
-pub fn hash_of_stream<R>(reader: &mut R, claimed_size: u64) -> Result<Hash, io::Error>
+impl From<[u8; 32]> for Hash {
+    fn from(bytes: [u8; 32]) -> Self {
+        Hash::Sha256(bytes)
+    }
+}
+
+impl From<[u8; 20]> for Hash {
+    fn from(bytes: [u8; 20]) -> Self {
+        Hash::Sha1(bytes)
+    }
+}
+
+/// Either of the hash functions, behind one face.
+enum Hasher {
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    fn update(&mut self, data: impl AsRef<[u8]>) {
+        match self {
+            Hasher::Sha1(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+        }
+    }
+
+    fn finish(self) -> Hash {
+        match self {
+            Hasher::Sha1(h) => Hash::Sha1(h.finalize().into()),
+            Hasher::Sha256(h) => Hash::Sha256(h.finalize().into()),
+        }
+    }
+}
+
+impl io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn hash_of_stream<R>(
+    format: ObjectFormat,
+    reader: &mut R,
+    claimed_size: u64,
+) -> Result<Hash, io::Error>
 where
     R: io::Read + ?Sized,
 {
-    let mut hasher = sha2::Sha256::new();
-    hasher.update(b"blob ");
+    hash_of_object_stream(format, "blob", reader, claimed_size)
+}
+
+/// Like `hash_of_stream`, but for any type of git object (e.g. "tree"), not just blobs.
+pub fn hash_of_object_stream<R>(
+    format: ObjectFormat,
+    object_type: &str,
+    reader: &mut R,
+    claimed_size: u64,
+) -> Result<Hash, io::Error>
+where
+    R: io::Read + ?Sized,
+{
+    let mut hasher = format.hasher();
+    hasher.update(object_type);
+    hasher.update(b" ");
     hasher.update(format!("{}", claimed_size));
     hasher.update([0]);
 
@@ -48,71 +186,440 @@ where
             ),
         ));
     }
-    let hash_bytes = hasher.finalize();
-    Ok(Hash(hash_bytes.into()))
+    Ok(hasher.finish())
 }
 
-pub fn hash_of_symlink<P: AsRef<Path>>(path: P) -> Result<Hash, io::Error> {
+pub fn hash_of_symlink<P: AsRef<Path>>(format: ObjectFormat, path: P) -> Result<Hash, GarError> {
     // Surprising number of SLOC needed here for lifetime reasons.
-    let target = fs::read_link(path)?;
-    let mut foo = target.as_os_str().as_encoded_bytes();
-    let size = foo.len().try_into().expect("int size nonsense");
-    return Ok(hash_of_stream(&mut foo, size)?);
+    let path = path.as_ref();
+    let target = fs::read_link(path).at(Stage::Hash, path)?;
+    let mut body = target.as_os_str().as_encoded_bytes();
+    let size = body.len().try_into().expect("int size nonsense");
+    hash_of_stream(format, &mut body, size).at(Stage::Hash, path)
+}
+
+/// The kinds of entry a tree can have.  (These are the ones git has, and no more.)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryKind {
+    File,
+    Executable,
+    Symlink,
+    Dir,
 }
 
+impl EntryKind {
+    /// The mode, as git writes it in a tree.
+    pub fn git_mode(self) -> &'static str {
+        match self {
+            EntryKind::File => "100644",
+            EntryKind::Executable => "100755",
+            EntryKind::Symlink => "120000",
+            // This certainly looks like a typo, doesn't it!  But, indeed... this is exactly how git encodes this.
+            EntryKind::Dir => "40000",
+        }
+    }
+}
+
+/// Builds up a tree object from its entries, and hashes it.
+///
+/// Entries can be appended in any order: `finish` puts them in the order git does (see `git_entry_order`)
+/// before hashing.  So callers don't need to know anything about git's idea of sorting, and can't get it wrong.
 pub struct TreeHashAccumulator {
-    buf: BytesMut,
+    format: ObjectFormat,
+    entries: Vec<(EntryKind, Vec<u8>, Hash)>,
 }
 
 impl TreeHashAccumulator {
-    pub fn new(expected_entries: usize) -> Self {
-        // We have to buffer descriptions of all children, because the git format writes the serial size of that in a header.
-        // Start accumulating the buffer.
-        // We can make a pretty good guess how big it'll need to be, at most:
-        let size_per_ent = 7 + 255 + 1 + 32;
+    pub fn new(format: ObjectFormat, expected_entries: usize) -> Self {
+        // We have to buffer all the children, both because they need sorting,
+        // and because the git format writes the serial size of all of them in a header.
         Self {
-            buf: BytesMut::with_capacity(expected_entries * size_per_ent),
+            format,
+            entries: Vec::with_capacity(expected_entries),
         }
     }
 
+    /// Add an entry.  Its hash must be in the same format as the tree's.
+    pub fn append(&mut self, kind: EntryKind, entry_name: &[u8], hash: &Hash) {
+        assert_eq!(
+            hash.format(),
+            self.format,
+            "tree entries must be hashed the same way as the tree"
+        );
+        self.entries.push((kind, entry_name.to_vec(), hash.clone()));
+    }
+
     pub fn append_file(&mut self, entry_name: &[u8], hash: &Hash) {
-        self.buf.put(&b"100644 "[..]);
-        self.append_common(entry_name, hash);
+        self.append(EntryKind::File, entry_name, hash);
     }
     pub fn append_executable(&mut self, entry_name: &[u8], hash: &Hash) {
-        self.buf.put(&b"100755 "[..]);
-        self.append_common(entry_name, hash);
+        self.append(EntryKind::Executable, entry_name, hash);
     }
     pub fn append_symlink(&mut self, entry_name: &[u8], hash: &Hash) {
-        self.buf.put(&b"120000 "[..]);
-        self.append_common(entry_name, hash);
+        self.append(EntryKind::Symlink, entry_name, hash);
     }
     pub fn append_dir(&mut self, entry_name: &[u8], hash: &Hash) {
-        self.buf.put(&b"40000 "[..]); // This certainly looks like a typo, doesn't it!  But, indeed... this is exactly how git encodes this.
-        self.append_common(entry_name, hash);
-    }
-    fn append_common(&mut self, entry_name: &[u8], hash: &Hash) {
-        // The type indicator should've just been written by one of the public functions before calling this.
-        // Now the name (and a terminating delimiter).
-        self.buf.put(entry_name);
-        self.buf.put_u8(0);
-        // Now its hash.
-        self.buf.put(&hash.0[..])
-        // Somewhat shockingly, there's no further delimiter here.  The hash length is necessarily hardcoded by this absence.
-        // Not how I would've designed it.  But it's what git did and still does do.
+        self.append(EntryKind::Dir, entry_name, hash);
     }
 
     pub fn finish(self) -> Hash {
+        self.finish_with_payload().0
+    }
+
+    /// Like `finish`, but also returns the tree object itself: the exact bytes that were hashed, minus git's header.
+    /// (`TreeObject::decode` can turn it back into entries.)
+    pub fn finish_with_payload(mut self) -> (Hash, Bytes) {
+        self.entries
+            .sort_by(|(a_kind, a_name, _), (b_kind, b_name, _)| {
+                git_entry_order(
+                    a_name,
+                    *a_kind == EntryKind::Dir,
+                    b_name,
+                    *b_kind == EntryKind::Dir,
+                )
+            });
+
+        // Serialize.  We can make a pretty good guess how big the buffer will need to be, at most:
+        let size_per_ent = 7 + 255 + 1 + self.format.hash_len();
+        let mut buf = BytesMut::with_capacity(self.entries.len() * size_per_ent);
+        for (kind, name, hash) in &self.entries {
+            // The mode, and a space.
+            buf.put(kind.git_mode().as_bytes());
+            buf.put_u8(b' ');
+            // Now the name (and a terminating delimiter).
+            buf.put(&name[..]);
+            buf.put_u8(0);
+            // Now its hash.
+            buf.put(hash.as_bytes());
+            // Somewhat shockingly, there's no further delimiter here.  The hash length is necessarily implied by the object format, by this absence.
+            // (Which is why a tree can't mix formats: see `append`.)
+            // Not how I would've designed it.  But it's what git did and still does do.
+        }
+
         // To produce the treehash:
         // first compute the preamble and size header, and feed that to the hasher;
         // then feed whole rest of the buffer to the hasher, and then and finalize.
-        let mut hasher = sha2::Sha256::new();
+        let mut hasher = self.format.hasher();
         hasher.update(b"tree ");
-        hasher.update(format!("{}", self.buf.len()));
+        hasher.update(format!("{}", buf.len()));
         hasher.update([0]);
-        hasher.update(self.buf);
-        let hash_bytes = hasher.finalize();
-        return Hash(hash_bytes.into());
+        hasher.update(&buf);
+        (hasher.finish(), buf.freeze())
+    }
+}
+
+/// The order git puts tree entries in.
+///
+/// It's bytewise, except that a dir sorts as if its name ended in a `/`.
+/// That's *not* the same as sorting paths (where "foo" comes before "foo.txt" whatever "foo" is):
+/// a dir "foo" sorts as "foo/", which is after "foo.txt" and "foo-bar", but before "foo0" --
+/// because '-' and '.' are less than '/', and '0' is more.
+/// (This is git's `base_name_compare`.)
+pub fn git_entry_order(a: &[u8], a_is_dir: bool, b: &[u8], b_is_dir: bool) -> std::cmp::Ordering {
+    let common = a.len().min(b.len());
+    a[..common].cmp(&b[..common]).then_with(|| {
+        // One name is a prefix of the other (or they're the same): compare whatever comes next,
+        // which for a name that's run out is the implied '/' of a dir, or else nothing at all.
+        let next = |name: &[u8], is_dir: bool| match name.get(common) {
+            Some(&c) => c,
+            None if is_dir => b'/',
+            None => 0,
+        };
+        next(a, a_is_dir).cmp(&next(b, b_is_dir))
+    })
+}
+
+/// A tree object, decoded: its entries, in the order git keeps them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeObject {
+    pub entries: Vec<TreeEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeEntry {
+    pub kind: EntryKind,
+    pub name: Vec<u8>,
+    pub hash: Hash,
+}
+
+impl TreeObject {
+    /// Parse a tree object payload (what `TreeHashAccumulator::finish_with_payload` gives, without git's "tree {size}" header).
+    ///
+    /// This is strict: anything `TreeHashAccumulator` wouldn't have produced is an error (of kind `InvalidData`).
+    /// That includes modes other than the four gar knows (so no submodules), names that couldn't be a dir entry,
+    /// and entries that are out of git's order, or repeated.
+    pub fn decode(format: ObjectFormat, payload: &[u8]) -> io::Result<Self> {
+        let invalid = |pos: usize, msg: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("tree object: {} (at byte {})", msg, pos),
+            )
+        };
+        let mut entries: Vec<TreeEntry> = Vec::new();
+        let mut pos = 0;
+        while pos < payload.len() {
+            let rest = &payload[pos..];
+            let space = rest
+                .iter()
+                .position(|&b| b == b' ')
+                .ok_or_else(|| invalid(pos, "no space after the mode"))?;
+            let kind = match &rest[..space] {
+                b"100644" => EntryKind::File,
+                b"100755" => EntryKind::Executable,
+                b"120000" => EntryKind::Symlink,
+                b"40000" => EntryKind::Dir,
+                _ => return Err(invalid(pos, "unknown mode")),
+            };
+            let rest = &rest[space + 1..];
+            let nul = rest
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or_else(|| invalid(pos, "no NUL after the name"))?;
+            let name = &rest[..nul];
+            if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') {
+                return Err(invalid(pos, "not usable as a name"));
+            }
+            let hash_bytes = rest
+                .get(nul + 1..nul + 1 + format.hash_len())
+                .ok_or_else(|| invalid(pos, "truncated hash"))?;
+            let hash = Hash::from_bytes(hash_bytes).expect("hash_len is a length we know");
+            if let Some(prev) = entries.last() {
+                let order = git_entry_order(
+                    &prev.name,
+                    prev.kind == EntryKind::Dir,
+                    name,
+                    kind == EntryKind::Dir,
+                );
+                // (Git's order can call a file and a dir of the same name unequal, so check for repeats separately.)
+                if order != std::cmp::Ordering::Less || prev.name == name {
+                    return Err(invalid(pos, "entries out of order"));
+                }
+            }
+            entries.push(TreeEntry {
+                kind,
+                name: name.to_vec(),
+                hash,
+            });
+            pos += space + 1 + nul + 1 + format.hash_len();
+        }
+        Ok(TreeObject { entries })
+    }
+
+    /// Hash the tree.  (If it came from `decode`, this is the hash of the payload it was decoded from.)
+    pub fn hash(&self, format: ObjectFormat) -> Hash {
+        self.encode(format).0
+    }
+
+    /// Turn the tree back into a payload, and hash it.  (The inverse of `decode`.)
+    pub fn encode(&self, format: ObjectFormat) -> (Hash, Bytes) {
+        let mut tha = TreeHashAccumulator::new(format, self.entries.len());
+        for ent in &self.entries {
+            tha.append(ent.kind, &ent.name, &ent.hash);
+        }
+        tha.finish_with_payload()
+    }
+}
+
+/// What to do on meeting something that's not a file, dir, or symlink (a FIFO, a socket, a device node...)
+/// while walking a filesystem.  Git trees have no way to represent those.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum SpecialFilePolicy {
+    /// Stop with an error naming the offending path.
+    #[default]
+    Error,
+    /// Leave it out, and let the caller warn about it.
+    Warn,
+    /// Leave it out, quietly.
+    Skip,
+}
+
+impl SpecialFilePolicy {
+    /// The name, as the `--special-files` flag spells it.
+    pub fn name(self) -> &'static str {
+        match self {
+            SpecialFilePolicy::Error => "error",
+            SpecialFilePolicy::Warn => "warn",
+            SpecialFilePolicy::Skip => "skip",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "error" => Some(SpecialFilePolicy::Error),
+            "warn" => Some(SpecialFilePolicy::Warn),
+            "skip" => Some(SpecialFilePolicy::Skip),
+            _ => None,
+        }
+    }
+
+    /// Apply the policy to a special file at `path`:
+    /// either return the error, or remember it in `skipped` so it can be reported.
+    pub fn handle(self, path: &Path, skipped: &mut Vec<PathBuf>) -> Result<(), GarError> {
+        match self {
+            SpecialFilePolicy::Error => Err(GarError::SpecialFile {
+                path: path.to_owned(),
+            }),
+            SpecialFilePolicy::Warn | SpecialFilePolicy::Skip => {
+                skipped.push(path.to_owned());
+                Ok(())
+            }
+        }
+    }
+}
+
+pub fn hash_of_path<P: AsRef<Path>>(format: ObjectFormat, path: P) -> Result<Hash, GarError> {
+    hash_of_path_with_policy(format, path, SpecialFilePolicy::Error, &mut Vec::new())
+}
+
+/// Like `hash_of_path`, but with a choice about special files.  Any that are skipped are appended to `skipped`.
+pub fn hash_of_path_with_policy<P: AsRef<Path>>(
+    format: ObjectFormat,
+    path: P,
+    special_files: SpecialFilePolicy,
+    skipped: &mut Vec<PathBuf>,
+) -> Result<Hash, GarError> {
+    let mut walk = HashWalk {
+        format,
+        special_files,
+        skipped,
+        exclusions: None,
+        entries: None,
+    };
+    walk.hash(path.as_ref(), Path::new(""), &garignore::Rules::new())
+        .map(|(hash, _)| hash)
+}
+
+/// One entry of a tree, as found by `hash_of_path_with_entries`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashedEntry {
+    /// The path of the entry, relative to the root of the walk.
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    /// The size of the blob, for files and symlinks.  (None for dirs.)
+    pub size: Option<u64>,
+    pub hash: Hash,
+}
+
+/// Like `hash_of_path_with_policy`, but also returns the hash of every entry below the root, sorted by path.
+///
+/// If `exclusions` are given, what they exclude (including anything the `.garignore` files the walk finds exclude)
+/// is left out, just as `gar add` would leave it out.  (Pass None for a tree that's already in a heap: those are hashed as they are.)
+pub fn hash_of_path_with_entries<P: AsRef<Path>>(
+    format: ObjectFormat,
+    path: P,
+    special_files: SpecialFilePolicy,
+    exclusions: Option<&garignore::Exclusions>,
+    skipped: &mut Vec<PathBuf>,
+) -> Result<(Hash, Vec<HashedEntry>), GarError> {
+    let mut walk = HashWalk {
+        format,
+        special_files,
+        skipped,
+        exclusions,
+        entries: Some(Vec::new()),
+    };
+    let (hash, _) = walk.hash(path.as_ref(), Path::new(""), &garignore::Rules::new())?;
+    let mut entries = walk.entries.unwrap_or_default();
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok((hash, entries))
+}
+
+struct HashWalk<'a> {
+    format: ObjectFormat,
+    special_files: SpecialFilePolicy,
+    skipped: &'a mut Vec<PathBuf>,
+    /// What to leave out, if anything.
+    exclusions: Option<&'a garignore::Exclusions>,
+    /// Only collected if someone asked for them.
+    entries: Option<Vec<HashedEntry>>,
+}
+
+impl HashWalk<'_> {
+    /// Hash `path`, which is at `rel` relative to the root of the walk.
+    /// `rules` are the exclusion rules from `.garignore` files in parent dirs (only consulted if we have `exclusions`).
+    /// Returns the hash, and the size of the blob if it's not a tree.
+    fn hash(
+        &mut self,
+        path: &Path,
+        rel: &Path,
+        rules: &garignore::Rules,
+    ) -> Result<(Hash, Option<u64>), GarError> {
+        let metadata = path.symlink_metadata().at(Stage::Scan, path)?;
+        // FileType isn't an enum (imagine: its membership size would vary per platform if it was!)
+        // so working with it ends up being a series of unappealing "if" blocks rather than a nice clean exhaustive match.
+        if metadata.is_file() {
+            let hash = fs::File::open(path)
+                .and_then(|mut f| hash_of_stream(self.format, &mut f, metadata.size()))
+                .at(Stage::Hash, path)?;
+            return Ok((hash, Some(metadata.size())));
+        }
+        if metadata.is_symlink() {
+            // (The size of a symlink is the length of its target, which is exactly its blob's body.)
+            return Ok((hash_of_symlink(self.format, path)?, Some(metadata.size())));
+        }
+        if metadata.is_dir() {
+            let entries = fs::read_dir(path)
+                .and_then(|rd| rd.collect::<Result<Vec<_>, io::Error>>())
+                .at(Stage::Scan, path)?;
+            // (No need to sort these: the accumulator puts them in git's order.)
+            let mut tha = TreeHashAccumulator::new(self.format, entries.len());
+            let rules = match self.exclusions {
+                Some(_) => garignore::rules_in(rules, path, rel)?,
+                None => std::borrow::Cow::Borrowed(rules),
+            };
+
+            // For each entry: recurse on hashing; append buffer.
+            for ent in entries.iter() {
+                let ft = ent.file_type().at(Stage::Scan, &ent.path())?;
+                let file_name = ent.file_name(); // for lifetime purposes.
+                let fnb = file_name.as_os_str().as_encoded_bytes();
+                let ent_rel = rel.join(&file_name);
+                if let Some(exclusions) = self.exclusions {
+                    let meta = ent.metadata().at(Stage::Scan, &ent.path())?;
+                    if exclusions.excludes(&rules, &ent_rel, &meta) {
+                        continue;
+                    }
+                }
+
+                let kind = if ft.is_file() {
+                    // Asking if it's executable is rather graceful in Rust...
+                    if ent
+                        .metadata()
+                        .at(Stage::Scan, &ent.path())?
+                        .permissions()
+                        .mode()
+                        & 0o111
+                        > 0
+                    {
+                        EntryKind::Executable
+                    } else {
+                        EntryKind::File
+                    }
+                } else if ft.is_symlink() {
+                    EntryKind::Symlink
+                } else if ft.is_dir() {
+                    EntryKind::Dir
+                } else {
+                    self.special_files.handle(&ent.path(), self.skipped)?;
+                    continue;
+                };
+                let (hash, size) = self.hash(&ent.path(), &ent_rel, &rules)?;
+                tha.append(kind, fnb, &hash);
+                if let Some(found) = &mut self.entries {
+                    found.push(HashedEntry {
+                        path: ent_rel,
+                        kind,
+                        size,
+                        hash,
+                    });
+                }
+            }
+            return Ok((tha.finish(), None));
+        }
+        // A special file as the root: there's nothing to skip *to*, so this is an error whatever the policy.
+        Err(GarError::SpecialFile {
+            path: path.to_owned(),
+        })
     }
 }
 
@@ -123,9 +630,10 @@ mod tests {
 
     #[test]
     fn test_hash_blob_fixture() {
-        let hash = hash_of_stream(&mut io::Cursor::new(b"a file\n"), 7).expect("hash to succeed");
+        let hash = hash_of_stream(ObjectFormat::Sha256, &mut io::Cursor::new(b"a file\n"), 7)
+            .expect("hash to succeed");
         assert_eq!(
-            hex::encode(&hash.0),
+            hash.as_hex(),
             "2909489adcb095aa795a9a7e6d92db735d0a0ced0782c43496675bdb7beec3ce"
         );
     }
@@ -144,51 +652,172 @@ mod tests {
     #[case("fixtures/alpha/a_symlink", Hash::from_hex("45a01848912e900ef582a23ef763c77ddb2d955bea7756072fb056f43534fca8").expect(""))]
     // Dir with multiple files (sorting matters), symlinks, and subdirs (including recursively).
     #[case("fixtures/alpha", Hash::from_hex("9024a7f8afa43db06ff2b50d9ac9c21b791bee49d8092d3f14f1e433bfd927fa").expect(""))]
+    // Names where git's order differs from path order: the dir "foo" sorts as "foo/", after "foo bar", "foo!", "foo-bar", "foo.d/", "foo.sh", and "foo.txt", but before "foo0" and "foo=link".
+    // (Both checked against `git write-tree`, and `git ls-tree | git mktree`, in a repo made with `git init --object-format=sha256`.)
+    #[case("fixtures/tricky", Hash::from_hex("82a644301657f063a20ab5a725a799518bcd06dcf02889f7a211ac9c01dcaed8").expect(""))]
+    #[case("fixtures/tricky/foo", Hash::from_hex("511c73ec5d3680c3c5397da418f153ae28d4d58df7bcf121f336f78880939575").expect(""))]
+    // The same, in sha1, as `git write-tree` gives in a classic repo.
+    #[case("fixtures/alpha/a_file", Hash::from_hex("02f6335fc4f28cc4ea2d0846aacff267a149effb").expect(""))]
+    #[case("fixtures/alpha", Hash::from_hex("f95738113efde9167cd35f089e854affcdaf67f0").expect(""))]
+    #[case("fixtures/tricky", Hash::from_hex("868e94e50f3844ce828d1dbc1ea1a631f995ea11").expect(""))]
     fn test_hash_of_path(#[case] path: String, #[case] expected: Hash) {
-        assert_eq!(expected, hash_of_path(path).expect("no io errors"))
+        // (The length of the expected hash says which format to hash in.)
+        assert_eq!(
+            expected,
+            hash_of_path(expected.format(), path).expect("no io errors")
+        )
     }
-}
 
-pub fn hash_of_path<P: AsRef<Path>>(path: P) -> Result<Hash, io::Error> {
-    let metadata = path.as_ref().symlink_metadata()?;
-    // FileType isn't an enum (imagine: its membership size would vary per platform if it was!)
-    // so working with it ends up being a series of unappealing "if" blocks rather than a nice clean exhaustive match.
-    if metadata.is_file() {
-        return Ok(hash_of_stream(&mut fs::File::open(path)?, metadata.size())?);
+    #[rstest]
+    #[case(b"foo", true, b"foo.txt", false, std::cmp::Ordering::Greater)]
+    #[case(b"foo", true, b"foo-bar", false, std::cmp::Ordering::Greater)]
+    #[case(b"foo", true, b"foo0", false, std::cmp::Ordering::Less)]
+    #[case(b"foo", false, b"foo.txt", false, std::cmp::Ordering::Less)]
+    #[case(b"foo", true, b"foo.d", true, std::cmp::Ordering::Greater)]
+    #[case(b"foo", true, b"foo", true, std::cmp::Ordering::Equal)]
+    #[case(b"a\x01", false, b"a", true, std::cmp::Ordering::Less)]
+    fn test_git_entry_order(
+        #[case] a: &[u8],
+        #[case] a_is_dir: bool,
+        #[case] b: &[u8],
+        #[case] b_is_dir: bool,
+        #[case] expected: std::cmp::Ordering,
+    ) {
+        assert_eq!(git_entry_order(a, a_is_dir, b, b_is_dir), expected);
+        assert_eq!(
+            git_entry_order(b, b_is_dir, a, a_is_dir),
+            expected.reverse()
+        );
     }
-    if metadata.is_symlink() {
-        return hash_of_symlink(path);
+
+    #[test]
+    fn test_tree_object_round_trip() {
+        for format in [ObjectFormat::Sha1, ObjectFormat::Sha256] {
+            let blob = hash_of_stream(format, &mut io::Cursor::new(b"x"), 1).unwrap();
+            let mut tha = TreeHashAccumulator::new(format, 4);
+            tha.append(EntryKind::Dir, b"foo", &blob);
+            tha.append(EntryKind::File, b"foo.txt", &blob);
+            tha.append(EntryKind::Executable, b"foo0", &blob);
+            tha.append(EntryKind::Symlink, b"a\nb", &blob);
+            let (hash, payload) = tha.finish_with_payload();
+            let tree = TreeObject::decode(format, &payload).expect("decode");
+            let names: Vec<_> = tree.entries.iter().map(|ent| &ent.name[..]).collect();
+            assert_eq!(names, [&b"a\nb"[..], b"foo.txt", b"foo", b"foo0"]);
+            assert_eq!(tree.entries[2].kind, EntryKind::Dir);
+            assert_eq!(tree.hash(format), hash);
+        }
     }
-    if metadata.is_dir() {
-        let mut entries = fs::read_dir(path)?.collect::<Result<Vec<_>, io::Error>>()?;
-        entries.sort_by(|a, b| a.path().partial_cmp(&b.path()).unwrap());
 
-        // We have to buffer descriptions of all children, because the git format writes the serial size of that in a header.
-        let mut tha = TreeHashAccumulator::new(entries.len());
+    #[rstest]
+    #[case::bad_mode(b"100664 a\0")]
+    #[case::submodule(b"160000 a\0")]
+    #[case::no_space(b"100644")]
+    #[case::no_nul(b"100644 a")]
+    #[case::empty_name(b"100644 \0")]
+    #[case::dot_name(b"40000 .\0")]
+    #[case::slash_name(b"100644 a/b\0")]
+    #[case::short_hash(b"100644 a\0\x01\x02")]
+    fn test_tree_object_rejects(#[case] head: &[u8]) {
+        // Pad out with a hash's worth of bytes, unless the case is about the hash being short.
+        let mut payload = head.to_vec();
+        if head.ends_with(b"\0") {
+            payload.extend_from_slice(&[7; 32]);
+        }
+        let err = TreeObject::decode(ObjectFormat::Sha256, &payload).expect_err("should reject");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
 
-        // For each entry: recurse on hashing; append buffer.
-        for ent in entries.iter() {
-            let hash = hash_of_path(ent.path())?;
-            let ft = ent.file_type()?;
-            let file_name = ent.file_name(); // for lifetime purposes.
-            let fnb = file_name.as_os_str().as_encoded_bytes();
+    #[test]
+    fn test_tree_object_rejects_misordered() {
+        let ent = |mode: &[u8], name: &[u8]| [mode, b" ", name, b"\0", &[7; 32]].concat();
+        // A dir "foo" goes after "foo.txt".
+        let payload = [ent(b"40000", b"foo"), ent(b"100644", b"foo.txt")].concat();
+        assert!(TreeObject::decode(ObjectFormat::Sha256, &payload).is_err());
+        let payload = [ent(b"100644", b"foo.txt"), ent(b"40000", b"foo")].concat();
+        assert!(TreeObject::decode(ObjectFormat::Sha256, &payload).is_ok());
+        // And no repeats, even as different kinds.
+        let payload = [ent(b"100644", b"foo"), ent(b"40000", b"foo")].concat();
+        assert!(TreeObject::decode(ObjectFormat::Sha256, &payload).is_err());
+    }
 
-            if ft.is_file() {
-                // Asking if it's executable is rather graceful in Rust...
-                if ent.metadata()?.permissions().mode() & 0o111 > 0 {
-                    tha.append_executable(fnb, &hash);
-                } else {
-                    tha.append_file(fnb, &hash);
-                }
-            } else if ft.is_symlink() {
-                tha.append_symlink(fnb, &hash);
-            } else if ft.is_dir() {
-                tha.append_dir(fnb, &hash);
-            } else {
-                panic!("unknown file type")
-            }
-        }
-        return Ok(tha.finish());
+    #[test]
+    fn test_accumulator_order_independent() {
+        let h1 = Hash::Sha256([1; 32]);
+        let h2 = Hash::Sha256([2; 32]);
+        let h3 = Hash::Sha256([3; 32]);
+        let mut forward = TreeHashAccumulator::new(ObjectFormat::Sha256, 3);
+        forward.append(EntryKind::File, b"foo.txt", &h1);
+        forward.append(EntryKind::Dir, b"foo", &h2);
+        forward.append(EntryKind::Executable, b"foo0", &h3);
+        let mut backward = TreeHashAccumulator::new(ObjectFormat::Sha256, 3);
+        backward.append(EntryKind::Executable, b"foo0", &h3);
+        backward.append(EntryKind::Dir, b"foo", &h2);
+        backward.append(EntryKind::File, b"foo.txt", &h1);
+        assert_eq!(forward.finish(), backward.finish());
+    }
+
+    #[test]
+    fn test_special_files() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let sock_path = td.path().join("a.sock");
+        let _sock = std::os::unix::net::UnixListener::bind(&sock_path).expect("bind");
+
+        let err = hash_of_path(ObjectFormat::Sha256, td.path())
+            .expect_err("sockets are an error by default");
+        assert!(matches!(err, GarError::SpecialFile { path } if path == sock_path));
+
+        let mut skipped = Vec::new();
+        let hash = hash_of_path_with_policy(
+            ObjectFormat::Sha256,
+            td.path(),
+            SpecialFilePolicy::Skip,
+            &mut skipped,
+        )
+        .expect("skipping to work");
+        assert_eq!(skipped, vec![sock_path]);
+        // An empty tree.
+        assert_eq!(
+            hash,
+            TreeHashAccumulator::new(ObjectFormat::Sha256, 0).finish()
+        );
+    }
+
+    #[test]
+    fn test_hash_of_path_with_entries() {
+        let (hash, entries) = hash_of_path_with_entries(
+            ObjectFormat::Sha256,
+            "fixtures/alpha/a_dir",
+            SpecialFilePolicy::Error,
+            None,
+            &mut Vec::new(),
+        )
+        .expect("no io errors");
+        assert_eq!(
+            hash,
+            hash_of_path(ObjectFormat::Sha256, "fixtures/alpha/a_dir").unwrap()
+        );
+        let deeper = entries
+            .iter()
+            .find(|ent| ent.path == Path::new("deeper"))
+            .expect("subdir to be listed");
+        assert_eq!(deeper.kind, EntryKind::Dir);
+        assert_eq!(
+            deeper.hash,
+            hash_of_path(ObjectFormat::Sha256, "fixtures/alpha/a_dir/deeper").unwrap()
+        );
+        let samefile = entries
+            .iter()
+            .find(|ent| ent.path == Path::new("deeper/samefile"))
+            .expect("files in subdirs to be listed");
+        assert_eq!(samefile.kind, EntryKind::File);
+        assert_eq!(
+            samefile.size,
+            Some(
+                fs::metadata("fixtures/alpha/a_dir/deeper/samefile")
+                    .unwrap()
+                    .len()
+            )
+        );
+        assert!(entries.windows(2).all(|w| w[0].path < w[1].path));
     }
-    panic!("unknown file type")
 }
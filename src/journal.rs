@@ -0,0 +1,248 @@
+//! Intent journals for adds that are destructive to their source.
+//!
+//! `FaithMode::Move` takes files away from their original locations one at a time,
+//! and the treecas entry for them only appears at the very end.
+//! If we're interrupted in the middle, the originals are half-gone and there's no treecas to show for it.
+//! So before each file is moved, we write down where it came from and which blob it became,
+//! and only once the whole add is committed do we throw that record away.
+//! A journal that's still around later means an add was interrupted, and it has enough info to finish the job.
+//!
+//! The format is line-oriented, but like garidx files, paths are length-prefixed,
+//! because paths can contain linebreaks and we have to handle that correctly:
+//!
+//! ```text
+//! # gar move journal v2
+//!    17 /abs/path/to/root
+//! mode-policy     9 normalize
+//! exclude     6 target
+//!     5 ./foo 9c4fba7ef811632cde...
+//!    14 ./some/script.sh fe9fbc1da76325ef8e...-x
+//! ```
+//!
+//! The first entry is the (absolute) root of the scan.
+//! Then come the options the add was run with, one per line: a name, and a length-prefixed value.
+//! (Finishing the add has to do it the same way -- leaving out the same excluded paths, above all.)
+//! Option names start with a letter, and entries with their length prefix, so the two can't be confused.
+//! Every further entry is a path relative to that root, and the blobcas name it was moved to.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::io::{BufRead, Read, Write};
+use std::os::unix::ffi::OsStringExt as _;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::repo;
+
+const HEADER: &[u8] = b"# gar move journal v2\n";
+
+pub struct MoveJournal {
+    path: PathBuf,
+    file: Mutex<fs::File>,
+}
+
+impl MoveJournal {
+    /// Start a new journal in the repo, for a move-mode add of the given scan root, with the given options.
+    /// (What the options mean is up to the caller; the journal just keeps them.)
+    pub fn create(
+        repo: &repo::Repo,
+        scan_root: &Path,
+        options: &[(&str, &OsStr)],
+    ) -> io::Result<Self> {
+        let scan_root = fs::canonicalize(scan_root)?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .expect("clock before 1970");
+        let path =
+            repo.journal_path()
+                .join(format!("move-{}-{}", now.as_nanos(), std::process::id()));
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        file.write_all(HEADER)?;
+        write_path(&mut file, &scan_root)?;
+        file.write_all(b"\n")?;
+        for (name, value) in options {
+            write!(file, "{} ", name)?;
+            write_path(&mut file, Path::new(value))?;
+            file.write_all(b"\n")?;
+        }
+        file.sync_all()?;
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Record that we're about to move the file at `path` (relative to the scan root) into the blobcas as `blob_name`.
+    ///
+    /// This is synced to disk before returning: the whole point is that it's already there if we crash during the move.
+    pub fn record(&self, path: &Path, blob_name: &str) -> io::Result<()> {
+        let mut buf = Vec::new();
+        write_path(&mut buf, &Path::new(".").join(path))?;
+        buf.push(b' ');
+        buf.extend_from_slice(blob_name.as_bytes());
+        buf.push(b'\n');
+        // One write per entry (and only one writer at a time), so a torn journal can only ever be missing a tail.
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&buf)?;
+        file.sync_data()
+    }
+
+    /// The add this journal covered is committed; forget it.
+    pub fn finish(self) -> io::Result<()> {
+        fs::remove_file(&self.path)
+    }
+}
+
+/// The parsed contents of a journal left behind by an interrupted add.
+#[derive(Debug)]
+pub struct JournalContents {
+    pub scan_root: PathBuf,
+    /// Pairs of (name, value), in the order they were given.
+    pub options: Vec<(String, OsString)>,
+    /// Pairs of (path relative to the scan root, blobcas name).
+    pub moves: Vec<(PathBuf, String)>,
+}
+
+/// List the journals in a repo.  Any that exist are from adds that did not finish.
+pub fn list(repo: &repo::Repo) -> io::Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    for ent in fs::read_dir(repo.journal_path())? {
+        let ent = ent?;
+        if ent.file_name().as_encoded_bytes().starts_with(b"move-") {
+            found.push(ent.path());
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+pub fn read(path: &Path) -> io::Result<JournalContents> {
+    let mut r = io::BufReader::new(fs::File::open(path)?);
+    let mut header = vec![0u8; HEADER.len()];
+    r.read_exact(&mut header)?;
+    if header != HEADER {
+        return Err(invalid("not a gar move journal"));
+    }
+    let scan_root = read_path(&mut r)?.ok_or_else(|| invalid("journal has no root"))?;
+    expect_byte(&mut r, b'\n')?;
+
+    let mut options = Vec::new();
+    while r.fill_buf()?.first().is_some_and(u8::is_ascii_lowercase) {
+        let mut name = Vec::new();
+        r.read_until(b' ', &mut name)?;
+        if name.pop() != Some(b' ') {
+            return Err(invalid("malformed journal option"));
+        }
+        let name = String::from_utf8(name).map_err(|_| invalid("bad option name"))?;
+        let value = read_path(&mut r)?.ok_or_else(|| invalid("journal option has no value"))?;
+        expect_byte(&mut r, b'\n')?;
+        options.push((name, value.into_os_string()));
+    }
+
+    let mut moves = Vec::new();
+    loop {
+        match read_move(&mut r) {
+            Ok(Some(entry)) => moves.push(entry),
+            Ok(None) => break,
+            // A record that was only partly written means we crashed while writing it,
+            // and so never got as far as the move it describes.  Ignore it.
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(JournalContents {
+        scan_root,
+        options,
+        moves,
+    })
+}
+
+fn read_move(r: &mut impl BufRead) -> io::Result<Option<(PathBuf, String)>> {
+    let path = match read_path(r)? {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    expect_byte(r, b' ')?;
+    let mut blob_name = Vec::new();
+    r.read_until(b'\n', &mut blob_name)?;
+    if blob_name.pop() != Some(b'\n') {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let blob_name = String::from_utf8(blob_name).map_err(|_| invalid("bad blob name"))?;
+    let path = path
+        .strip_prefix(".")
+        .map_err(|_| invalid("journal entry path must start with a dot"))?
+        .to_owned();
+    Ok(Some((path, blob_name)))
+}
+
+fn write_path(w: &mut impl Write, path: &Path) -> io::Result<()> {
+    let b = path.as_os_str().as_encoded_bytes();
+    write!(w, "{:5} ", b.len())?;
+    w.write_all(b)
+}
+
+/// Reads a length-prefixed path.  Returns None on a clean EOF before the entry starts.
+fn read_path(r: &mut impl BufRead) -> io::Result<Option<PathBuf>> {
+    let mut len_field = [0u8; 6];
+    if r.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    r.read_exact(&mut len_field)?;
+    let len: usize = std::str::from_utf8(&len_field[..5])
+        .ok()
+        .and_then(|s| s.trim_start().parse().ok())
+        .filter(|_| len_field[5] == b' ')
+        .ok_or_else(|| invalid("bad length prefix"))?;
+    let mut b = vec![0u8; len];
+    r.read_exact(&mut b)?;
+    Ok(Some(PathBuf::from(std::ffi::OsString::from_vec(b))))
+}
+
+fn expect_byte(r: &mut impl Read, want: u8) -> io::Result<()> {
+    let mut b = [0u8];
+    r.read_exact(&mut b)?;
+    if b[0] != want {
+        return Err(invalid("malformed journal entry"));
+    }
+    Ok(())
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_torn_tail_is_ignored() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = repo::Repo::new(td.path()).unwrap();
+        r.create_dir_all().unwrap();
+
+        let j = MoveJournal::create(&r, td.path(), &[("exclude", OsStr::new("a b\nc"))]).unwrap();
+        j.record(Path::new("some/file\nwith a linebreak"), "abcd-x")
+            .unwrap();
+        j.file.lock().unwrap().write_all(b"    9 ./partia").unwrap();
+
+        let contents = read(&j.path).unwrap();
+        assert_eq!(contents.scan_root, fs::canonicalize(td.path()).unwrap());
+        assert_eq!(
+            contents.options,
+            vec![("exclude".to_owned(), OsString::from("a b\nc"))]
+        );
+        assert_eq!(
+            contents.moves,
+            vec![(
+                PathBuf::from("some/file\nwith a linebreak"),
+                "abcd-x".to_owned()
+            )]
+        );
+    }
+}
@@ -0,0 +1,509 @@
+//! Labels: human-readable names for trees, kept in `.gar/labels/`.
+//!
+//! These are a lot like git's branches and tags: a label is a file, named for the label, containing the hex of the hash it points at.
+//! Names can have slashes in them (e.g. "nightly/linux"), which become subdirs.
+//!
+//! A label is changed by writing its new value aside and renaming it into place, so it never reads as half-written.
+//! Changes can be made conditional on what the label says beforehand (see `Expect`), for a compare-and-swap;
+//! they hold the labels lock (`Repo::lock_labels`) from the reading to the writing, so that's atomic, too.
+//! And they hold the heap's lock in shared mode, like an add, so a label can't be pointed at something a gc is in the middle of deleting.
+//!
+//! Every change to a label is recorded in its history (see `history`), which is kept in `.gar/labelhistory/`, apart from the labels themselves.
+//!
+//! Labels are gc roots: whatever they point at is kept.  So is whatever they pointed at recently, per their histories;
+//! "recently" being `Repo::label_history_days`.
+
+use std::fs;
+use std::io;
+use std::io::Write as _;
+use std::path::{Component, Path, PathBuf};
+
+use crate::error::{Context as _, GarError, Stage};
+use crate::gittree;
+use crate::repo;
+
+/// What a label has to say before a change, for the change to go ahead.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum Expect {
+    /// Whatever it says (or if it doesn't exist).
+    #[default]
+    Anything,
+    /// It mustn't exist yet.
+    Absent,
+    /// It has to point at this.
+    Hash(gittree::Hash),
+}
+
+/// Check a label name.
+///
+/// Names are made of letters, digits, and `-_.+@`, with slashes between parts;
+/// no part may start with a dot, and a name mayn't look like a hash (or everything that takes either would be ambiguous).
+pub fn check_name(repo: &repo::Repo, name: &str) -> Result<(), GarError> {
+    let bad = |why: &str| {
+        Err(GarError::InvalidInput {
+            reason: format!("{:?} can't be a label name: {}", name, why),
+        })
+    };
+    if name.is_empty() {
+        return bad("it's empty");
+    }
+    for part in name.split('/') {
+        if part.is_empty() {
+            return bad("it has an empty part");
+        }
+        if part.starts_with('.') {
+            return bad("no part of it may start with a dot");
+        }
+        if !part
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.+@".contains(c))
+        {
+            return bad("only letters, digits, slashes, and -_.+@ are allowed");
+        }
+    }
+    if parse_hash(repo, name).is_some() {
+        return bad("it looks like a hash");
+    }
+    Ok(())
+}
+
+fn parse_hash(repo: &repo::Repo, s: &str) -> Option<gittree::Hash> {
+    gittree::Hash::from_hex(s)
+        .ok()
+        .filter(|hash| hash.format() == repo.object_format())
+}
+
+fn label_path(repo: &repo::Repo, name: &str) -> Result<PathBuf, GarError> {
+    check_name(repo, name)?;
+    Ok(repo.labels_path().join(name))
+}
+
+/// What a label points at.  None if there's no such label.
+pub fn get(repo: &repo::Repo, name: &str) -> Result<Option<gittree::Hash>, GarError> {
+    read(repo, &label_path(repo, name)?)
+}
+
+fn read(repo: &repo::Repo, path: &Path) -> Result<Option<gittree::Hash>, GarError> {
+    let body = match fs::read_to_string(path) {
+        Ok(body) => body,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        // (A dir, where a label with this name would be, just means there are labels *under* this name.)
+        Err(_) if path.is_dir() => return Ok(None),
+        Err(e) => return Err(e).at(Stage::Label, path),
+    };
+    match parse_hash(repo, body.trim_end_matches('\n')) {
+        Some(hash) => Ok(Some(hash)),
+        None => Err(GarError::Corrupt {
+            path: path.to_owned(),
+            reason: "a label should contain just a hash".to_owned(),
+        }),
+    }
+}
+
+/// Point a label at a tree (or blob), creating it if need be.
+/// The change is recorded in the label's history, with `message` (which can be empty).
+pub fn set(
+    repo: &repo::Repo,
+    name: &str,
+    hash: &gittree::Hash,
+    expect: &Expect,
+    message: &str,
+) -> Result<(), GarError> {
+    let path = label_path(repo, name)?;
+    let _heap_lock = repo.lock_shared()?;
+    if !repo.contains(hash) {
+        return Err(GarError::InvalidInput {
+            reason: format!("{} isn't a tree or blob in this heap", hash.as_hex()),
+        });
+    }
+    let _lock = repo.lock_labels()?;
+    let old = check_expected(repo, name, &path, expect)?;
+    let dir = path.parent().expect("labels are in the labels dir");
+    let td = fs::create_dir_all(dir)
+        .and_then(|_| tempdir::TempDir::new_in(repo.labels_path(), ".wiplabel-"))
+        .at(Stage::Label, &path)?;
+    let wip_path = td.path().join("label");
+    fs::write(&wip_path, format!("{}\n", hash.as_hex())).at(Stage::Label, &wip_path)?;
+    // History first, so there's never a change that isn't in it.  (If the rename then fails, the history has one entry too many,
+    // which is the lesser evil: it only makes gc keep something a while longer.)
+    append_history(repo, name, old, Some(hash.clone()), message)?;
+    fs::rename(&wip_path, &path).at(Stage::Label, &path)
+}
+
+/// Remove a label.  Its history stays, and records the removal (with `message`).
+pub fn remove(
+    repo: &repo::Repo,
+    name: &str,
+    expect: &Expect,
+    message: &str,
+) -> Result<(), GarError> {
+    let path = label_path(repo, name)?;
+    let _lock = repo.lock_labels()?;
+    let Some(old) = check_expected(repo, name, &path, expect)? else {
+        return Err(GarError::InvalidInput {
+            reason: format!("there's no label {:?}", name),
+        });
+    };
+    append_history(repo, name, Some(old), None, message)?;
+    fs::remove_file(&path).at(Stage::Label, &path)?;
+    // Tidy away dirs that only held this label.  (Removing a dir that isn't empty fails, which is how we know to stop.)
+    for dir in path.ancestors().skip(1) {
+        if dir == repo.labels_path() || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Check that a label says what's expected, and return what it says.
+fn check_expected(
+    repo: &repo::Repo,
+    name: &str,
+    path: &Path,
+    expect: &Expect,
+) -> Result<Option<gittree::Hash>, GarError> {
+    let found = read(repo, path)?;
+    let conflict = |what: String| {
+        Err(GarError::Conflict {
+            reason: format!("label {:?} {}", name, what),
+        })
+    };
+    match (expect, &found) {
+        (Expect::Anything, _) | (Expect::Absent, None) => {}
+        (Expect::Hash(want), Some(have)) if want == have => {}
+        (Expect::Absent, Some(have)) => {
+            return conflict(format!("already exists (pointing at {})", have.as_hex()))
+        }
+        (Expect::Hash(want), Some(have)) => {
+            return conflict(format!(
+                "points at {}, not {}",
+                have.as_hex(),
+                want.as_hex()
+            ))
+        }
+        (Expect::Hash(want), None) => {
+            return conflict(format!(
+                "doesn't exist (it was expected to point at {})",
+                want.as_hex()
+            ))
+        }
+    }
+    Ok(found)
+}
+
+/// Every label, and what it points at, in order of name.
+pub fn list(repo: &repo::Repo) -> Result<Vec<(String, gittree::Hash)>, GarError> {
+    let mut labels = Vec::new();
+    for (name, path) in walk(repo.labels_path())? {
+        if let Some(hash) = read(repo, &path)? {
+            labels.push((name, hash));
+        }
+    }
+    Ok(labels)
+}
+
+/// Every file under a dir (of labels, or of their histories), with its name as a label name, in order of name.
+fn walk(root: &Path) -> Result<Vec<(String, PathBuf)>, GarError> {
+    let mut found = Vec::new();
+    walk_dir(root, root, &mut found)?;
+    found.sort();
+    Ok(found)
+}
+
+fn walk_dir(root: &Path, dir: &Path, found: &mut Vec<(String, PathBuf)>) -> Result<(), GarError> {
+    let rd = match fs::read_dir(dir) {
+        Ok(rd) => rd,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).at(Stage::Label, dir),
+    };
+    for ent in rd {
+        let ent = ent.at(Stage::Label, dir)?;
+        // (Dot files are ours: the lock, and temp dirs.)
+        if ent.file_name().as_encoded_bytes().starts_with(b".") {
+            continue;
+        }
+        let path = ent.path();
+        if ent.file_type().at(Stage::Label, &path)?.is_dir() {
+            walk_dir(root, &path, found)?;
+        } else {
+            let name = path
+                .strip_prefix(root)
+                .expect("walking under the root")
+                .components()
+                .filter_map(|c| match c {
+                    Component::Normal(part) => Some(part.to_string_lossy()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("/");
+            found.push((name, path));
+        }
+    }
+    Ok(())
+}
+
+/// One change to a label, as its history records it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    /// When, in seconds since the unix epoch.
+    pub timestamp: i64,
+    /// What the label pointed at before.  (None if it was created by this change.)
+    pub old: Option<gittree::Hash>,
+    /// What the label pointed at after.  (None if it was removed by this change.)
+    pub new: Option<gittree::Hash>,
+    pub message: String,
+}
+
+// Each label's history is a file of one line per change: `<timestamp> <old> <new>\t<message>`,
+// with "-" for an old or new that there isn't.  It's only ever appended to (while holding the labels lock),
+// and each line goes in with a single write, so a reader never sees half of one.
+//
+// Histories are kept flat, with the slashes in their names as "%" (which can't be in a label name), rather than nested like labels.
+// Histories outlive their labels, so nesting them would mean that once there'd been a label "a",
+// there'd be a file in the way of ever having a history for "a/b".
+
+fn history_path(repo: &repo::Repo, name: &str) -> PathBuf {
+    repo.label_history_path().join(name.replace('/', "%"))
+}
+
+fn append_history(
+    repo: &repo::Repo,
+    name: &str,
+    old: Option<gittree::Hash>,
+    new: Option<gittree::Hash>,
+    message: &str,
+) -> Result<(), GarError> {
+    let path = history_path(repo, name);
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let hex = |hash: &Option<gittree::Hash>| hash.as_ref().map_or("-".to_owned(), |h| h.as_hex());
+    // (A message is kept to one line, so it can't be mistaken for more entries.)
+    let message: String = message
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+    let line = format!("{} {} {}\t{}\n", timestamp, hex(&old), hex(&new), message);
+    fs::create_dir_all(repo.label_history_path())
+        .and_then(|_| fs::OpenOptions::new().create(true).append(true).open(&path))
+        .and_then(|mut f| f.write_all(line.as_bytes()))
+        .at(Stage::Label, &path)
+}
+
+/// A label's history, oldest first.  Empty if it has none (including if there's never been such a label).
+///
+/// The history outlives the label: after a label is removed, its history is how to find what it used to point at.
+pub fn history(repo: &repo::Repo, name: &str) -> Result<Vec<HistoryEntry>, GarError> {
+    check_name(repo, name)?;
+    read_history(repo, &history_path(repo, name))
+}
+
+fn read_history(repo: &repo::Repo, path: &Path) -> Result<Vec<HistoryEntry>, GarError> {
+    let body = match fs::read_to_string(path) {
+        Ok(body) => body,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).at(Stage::Label, path),
+    };
+    let hash = |s: &str| match s {
+        "-" => Some(None),
+        hex => parse_hash(repo, hex).map(Some),
+    };
+    let mut entries = Vec::new();
+    for (i, line) in body.lines().enumerate() {
+        let (fields, message) = line.split_once('\t').unwrap_or((line, ""));
+        let mut fields = fields.split(' ');
+        let entry = match (fields.next(), fields.next(), fields.next(), fields.next()) {
+            (Some(timestamp), Some(old), Some(new), None) => (|| {
+                Some(HistoryEntry {
+                    timestamp: timestamp.parse().ok()?,
+                    old: hash(old)?,
+                    new: hash(new)?,
+                    message: message.to_owned(),
+                })
+            })(),
+            _ => None,
+        };
+        entries.push(entry.ok_or_else(|| GarError::Corrupt {
+            path: path.to_owned(),
+            reason: format!("can't make sense of line {} of a label history", i + 1),
+        })?);
+    }
+    Ok(entries)
+}
+
+/// Everything any label has pointed at since `since` (in seconds since the unix epoch), according to their histories.
+/// These are gc roots, so what a label used to point at can still be gotten back for a while.
+pub fn recent_history(repo: &repo::Repo, since: i64) -> Result<Vec<gittree::Hash>, GarError> {
+    let mut hashes = Vec::new();
+    for (_, path) in walk(repo.label_history_path())? {
+        for entry in read_history(repo, &path)? {
+            // (A recent entry's old hash was labelled until recently; its new one might have been replaced since, but was labelled recently too.)
+            if entry.timestamp >= since {
+                hashes.extend(entry.old);
+                hashes.extend(entry.new);
+            }
+        }
+    }
+    Ok(hashes)
+}
+
+/// Make sense of something given where a hash is wanted: either a hash (in hex), or a label name.
+pub fn resolve(repo: &repo::Repo, hash_or_label: &str) -> Result<gittree::Hash, GarError> {
+    if let Some(hash) = parse_hash(repo, hash_or_label) {
+        return Ok(hash);
+    }
+    match check_name(repo, hash_or_label).and_then(|_| get(repo, hash_or_label))? {
+        Some(hash) => Ok(hash),
+        None => Err(GarError::InvalidInput {
+            reason: format!("{:?} is neither a hash nor a label", hash_or_label),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::add;
+    use rstest::rstest;
+
+    fn fresh_repo(td: &tempdir::TempDir) -> repo::Repo {
+        let r = repo::Repo::new(td.path()).unwrap();
+        r.create_dir_all().unwrap();
+        r
+    }
+
+    fn add_copy(r: &repo::Repo, path: &str) -> gittree::Hash {
+        let opts = add::AddOptions {
+            faithmode: add::FaithMode::Copy,
+            ..Default::default()
+        };
+        add::add(r, path, &opts).expect("add to succeed").hash
+    }
+
+    #[rstest]
+    #[case::plain("nightly", true)]
+    #[case::nested("nightly/linux-x86_64", true)]
+    #[case::version("v1.2.3+build@host", true)]
+    #[case::empty("", false)]
+    #[case::empty_part("a//b", false)]
+    #[case::trailing_slash("a/", false)]
+    #[case::dotfile(".lock", false)]
+    #[case::dotdot("a/../b", false)]
+    #[case::space("a b", false)]
+    #[case::hashlike(
+        "82a644301657f063a20ab5a725a799518bcd06dcf02889f7a211ac9c01dcaed8",
+        false
+    )]
+    fn test_check_name(#[case] name: &str, #[case] ok: bool) {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        assert_eq!(check_name(&r, name).is_ok(), ok, "{:?}", name);
+    }
+
+    #[test]
+    fn test_labels() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let alpha = add_copy(&r, "fixtures/alpha");
+        let tricky = add_copy(&r, "fixtures/tricky");
+
+        assert_eq!(get(&r, "nightly").unwrap(), None);
+        set(&r, "nightly", &alpha, &Expect::Absent, "").unwrap();
+        set(&r, "release/1.0", &tricky, &Expect::Anything, "").unwrap();
+        assert_eq!(get(&r, "nightly").unwrap(), Some(alpha.clone()));
+        assert_eq!(resolve(&r, "release/1.0").unwrap(), tricky);
+        assert_eq!(resolve(&r, &alpha.as_hex()).unwrap(), alpha);
+        assert!(resolve(&r, "nope").is_err());
+        assert_eq!(
+            list(&r).unwrap(),
+            [
+                ("nightly".to_owned(), alpha.clone()),
+                ("release/1.0".to_owned(), tricky.clone())
+            ]
+        );
+
+        // Compare-and-swap.
+        assert!(matches!(
+            set(&r, "nightly", &tricky, &Expect::Absent, ""),
+            Err(GarError::Conflict { .. })
+        ));
+        assert!(matches!(
+            set(&r, "nightly", &tricky, &Expect::Hash(tricky.clone()), ""),
+            Err(GarError::Conflict { .. })
+        ));
+        set(&r, "nightly", &tricky, &Expect::Hash(alpha.clone()), "").unwrap();
+        assert_eq!(get(&r, "nightly").unwrap(), Some(tricky.clone()));
+
+        // Only things that are in the heap can be labelled.
+        let nowhere = gittree::Hash::from([1u8; 32]);
+        assert!(matches!(
+            set(&r, "nightly", &nowhere, &Expect::Anything, ""),
+            Err(GarError::InvalidInput { .. })
+        ));
+
+        // Removing cleans up the dirs of nested names, too.
+        assert!(matches!(
+            remove(&r, "release/1.0", &Expect::Hash(alpha.clone()), ""),
+            Err(GarError::Conflict { .. })
+        ));
+        remove(&r, "release/1.0", &Expect::Anything, "").unwrap();
+        assert!(!r.labels_path().join("release").exists());
+        assert!(remove(&r, "release/1.0", &Expect::Anything, "").is_err());
+        assert_eq!(list(&r).unwrap(), [("nightly".to_owned(), tricky)]);
+    }
+
+    #[test]
+    fn test_history() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = fresh_repo(&td);
+        let alpha = add_copy(&r, "fixtures/alpha");
+        let tricky = add_copy(&r, "fixtures/tricky");
+
+        assert_eq!(history(&r, "nightly").unwrap(), []);
+        set(&r, "nightly/linux", &alpha, &Expect::Absent, "first").unwrap();
+        set(
+            &r,
+            "nightly/linux",
+            &tricky,
+            &Expect::Anything,
+            "multi\nline\tmessage",
+        )
+        .unwrap();
+        remove(&r, "nightly/linux", &Expect::Anything, "").unwrap();
+
+        let entries = history(&r, "nightly/linux").unwrap();
+        let changes: Vec<_> = entries
+            .iter()
+            .map(|e| (e.old.clone(), e.new.clone(), e.message.as_str()))
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                (None, Some(alpha.clone()), "first"),
+                (
+                    Some(alpha.clone()),
+                    Some(tricky.clone()),
+                    "multi line message"
+                ),
+                (Some(tricky.clone()), None, ""),
+            ]
+        );
+        assert!(entries[0].timestamp > 0);
+
+        // A name can be reused as a prefix of another, histories and all.
+        set(&r, "a", &alpha, &Expect::Absent, "").unwrap();
+        remove(&r, "a", &Expect::Anything, "").unwrap();
+        set(&r, "a/b", &alpha, &Expect::Absent, "").unwrap();
+        assert_eq!(history(&r, "a").unwrap().len(), 2);
+        assert_eq!(history(&r, "a/b").unwrap().len(), 1);
+
+        // Everything's recent, and nothing's after the future.
+        let mut recent = recent_history(&r, 0).unwrap();
+        recent.sort_by_key(|h| h.as_hex());
+        recent.dedup();
+        let mut both = vec![alpha, tricky];
+        both.sort_by_key(|h| h.as_hex());
+        assert_eq!(recent, both);
+        assert_eq!(recent_history(&r, i64::MAX).unwrap(), []);
+    }
+}
@@ -0,0 +1,17 @@
+pub mod add;
+pub mod error;
+pub mod export;
+pub mod fsck;
+pub mod garidx;
+pub mod garignore;
+pub mod gc;
+pub mod gitexport;
+pub mod gitimport;
+pub mod gitrepo;
+pub mod gittree;
+pub mod journal;
+pub mod labels;
+pub mod reflink;
+pub mod repo;
+pub mod statcache;
+pub mod treeobj;
@@ -1,7 +1,19 @@
-mod add;
 mod cmds;
-mod gittree;
-mod repo;
+
+use gar_rust::add;
+use gar_rust::error::{Context as _, GarError, Stage};
+use gar_rust::export;
+use gar_rust::fsck;
+use gar_rust::garidx;
+use gar_rust::garignore;
+use gar_rust::gc;
+use gar_rust::gitexport;
+use gar_rust::gitimport;
+use gar_rust::gittree;
+use gar_rust::journal;
+use gar_rust::labels;
+use gar_rust::repo;
+use gar_rust::statcache;
 
 mod clap {
     pub use clap::error::ErrorKind;
@@ -9,6 +21,7 @@ mod clap {
 }
 use clap::Parser;
 
+use std::fs;
 use std::process;
 
 fn main() {
@@ -32,45 +45,484 @@ fn main() {
         Some(path) => path,
         None => std::env::current_dir().expect("must be able to find cwd"),
     };
-    let repo = repo::find_repo_from(&repo_search_start)
-        .expect("io error while searching for existing repos");
+    let repo = repo::find_repo_from(&repo_search_start).unwrap_or_else(|e| exit_with(e));
 
     match root_args.subcommand {
-        cmds::Subcommands::Init(_) => match repo {
+        cmds::Subcommands::Init(args) => match repo {
             Some(repo) => {
                 println!("warning: a repo already exists in this dir or its parent");
                 println!("gar repo exists at {:?}", repo.repo_path());
                 process::exit(4);
             }
             None => {
-                let r = repo::Repo::new(repo_search_start).expect("opening repo");
-                r.create_dir_all().expect("creating repo dirs");
+                let r = repo::Repo::new(repo_search_start)
+                    .unwrap_or_else(|e| exit_with(e))
+                    .with_object_format(args.object_format.into());
+                r.create_dir_all().unwrap_or_else(|e| exit_with(e));
                 println!("gar repo created at {:?}", r.repo_path());
                 process::exit(0);
             }
         },
         cmds::Subcommands::Add(args) => match repo {
             Some(repo) => {
-                repo.create_dir_all().expect("creating repo dirs");
-                match add::add(&repo, args.path, add::FaithMode::LinkOriginals) {
-                    Ok(hash) => {
-                        println!("{}", hash.as_hex());
+                repo.create_dir_all().unwrap_or_else(|e| exit_with(e));
+                let faithmode = match args.faith {
+                    cmds::FaithModeArg::Copy => add::FaithMode::Copy,
+                    cmds::FaithModeArg::LinkOriginals => add::FaithMode::LinkOriginals,
+                    cmds::FaithModeArg::Move => add::FaithMode::Move,
+                    cmds::FaithModeArg::LinkOrCopy => add::FaithMode::LinkOrCopy,
+                    cmds::FaithModeArg::Reflink => add::FaithMode::Reflink,
+                    cmds::FaithModeArg::ReflinkOrCopy => add::FaithMode::ReflinkOrCopy,
+                };
+                let mode_policy = match args.mode_policy {
+                    cmds::ModePolicyArg::Ignore => add::ModePolicy::Ignore,
+                    cmds::ModePolicyArg::Normalize => add::ModePolicy::Normalize,
+                    cmds::ModePolicyArg::Copy => add::ModePolicy::Copy,
+                    cmds::ModePolicyArg::Refuse => add::ModePolicy::Refuse,
+                };
+                let opts = add::AddOptions {
+                    faithmode,
+                    mode_policy,
+                    wrap_name: args.wrap,
+                    threads: args.threads,
+                    excludes: args.exclude,
+                    special_files: args.special_files.into(),
+                    no_index: args.no_index,
+                    stat_cache: match (args.trust_stat_cache, args.rehash) {
+                        (true, _) => statcache::StatCacheMode::Trust,
+                        (_, true) => statcache::StatCacheMode::Rehash,
+                        _ => statcache::StatCacheMode::Off,
+                    },
+                    label: args.label,
+                };
+                match add::add(&repo, args.path, &opts) {
+                    Ok(report) => {
+                        for (path, mode) in &report.odd_modes {
+                            let action = match mode_policy {
+                                add::ModePolicy::Normalize => "normalized",
+                                _ => "copied",
+                            };
+                            eprintln!("{} {:?} (mode was {:o})", action, path, mode);
+                        }
+                        if opts.special_files == gittree::SpecialFilePolicy::Warn
+                            && !report.skipped.is_empty()
+                        {
+                            eprintln!(
+                                "warning: skipped {} special files (not a file, dir, or symlink):",
+                                report.skipped.len()
+                            );
+                            for path in &report.skipped {
+                                eprintln!("  {:?}", path);
+                            }
+                        }
+                        if root_args.verbosity > 0 {
+                            eprintln!(
+                                "{} files linked, {} copied, {} moved, {} reflinked, {} unchanged per the stat cache",
+                                report.stats.linked,
+                                report.stats.copied,
+                                report.stats.moved,
+                                report.stats.reflinked,
+                                report.stats.cached
+                            );
+                            if report.new_tree {
+                                eprintln!("built a new tree in the treecas");
+                            }
+                        }
+                        println!("{}", report.hash.as_hex());
+                        process::exit(0);
+                    }
+                    Err(e) => exit_with(e),
+                }
+            }
+            None => {
+                eprintln!("this subcommand needs to be run within, or pointed to, a gar repo");
+                process::exit(3);
+            }
+        },
+        cmds::Subcommands::Recover(args) => match repo {
+            Some(repo) => {
+                repo.create_dir_all().unwrap_or_else(|e| exit_with(e));
+                let journals = journal::list(&repo)
+                    .at(Stage::Journal, repo.journal_path())
+                    .unwrap_or_else(|e| exit_with(e));
+                for journal_path in &journals {
+                    let contents = journal::read(journal_path)
+                        .at(Stage::Journal, journal_path)
+                        .unwrap_or_else(|e| exit_with(e));
+                    println!(
+                        "interrupted move-mode add of {:?}: {} files already moved into the blobcas",
+                        contents.scan_root,
+                        contents.moves.len()
+                    );
+                    if !args.roll_forward {
+                        continue;
+                    }
+                    match add::roll_forward(&repo, journal_path) {
+                        Ok(hash) => println!("finished: {}", hash.as_hex()),
+                        Err(e) => exit_with(e),
+                    }
+                }
+                if !journals.is_empty() && !args.roll_forward {
+                    // Something needs attention; say so in the exit code, too.
+                    process::exit(6);
+                }
+                process::exit(0);
+            }
+            None => {
+                eprintln!("this subcommand needs to be run within, or pointed to, a gar repo");
+                process::exit(3);
+            }
+        },
+        cmds::Subcommands::Reindex(args) => match repo {
+            Some(repo) => {
+                let hash = resolve_hash(&repo, &args.hash);
+                match garidx::reindex(&repo, &hash) {
+                    Ok(()) => process::exit(0),
+                    Err(e) => exit_with(e),
+                }
+            }
+            None => {
+                eprintln!("this subcommand needs to be run within, or pointed to, a gar repo");
+                process::exit(3);
+            }
+        },
+        cmds::Subcommands::Export(args) => match repo {
+            Some(repo) => {
+                let hash = resolve_hash(&repo, &args.hash);
+                let opts = export::ExportOptions {
+                    mode: match args.mode {
+                        cmds::ExportModeArg::Copy => export::ExportMode::Copy,
+                        cmds::ExportModeArg::Hardlink => export::ExportMode::Hardlink,
+                        cmds::ExportModeArg::Reflink => export::ExportMode::Reflink,
+                    },
+                    overwrite: args.overwrite,
+                    verify: args.verify,
+                };
+                match export::export(&repo, &hash, &args.dest, &opts) {
+                    Ok(report) => {
+                        if root_args.verbosity > 0 {
+                            eprintln!(
+                                "{} files ({} bytes), {} symlinks, {} dirs",
+                                report.files, report.bytes, report.symlinks, report.dirs
+                            );
+                        }
+                        process::exit(0);
+                    }
+                    Err(e) => exit_with(e),
+                }
+            }
+            None => {
+                eprintln!("this subcommand needs to be run within, or pointed to, a gar repo");
+                process::exit(3);
+            }
+        },
+        cmds::Subcommands::GitExport(args) => match repo {
+            Some(repo) => {
+                let hash = resolve_hash(&repo, &args.hash);
+                match gitexport::export(&repo, &hash, &args.git_dir) {
+                    Ok(report) => {
+                        if root_args.verbosity > 0 {
+                            eprintln!(
+                                "{} objects written, {} already there",
+                                report.written, report.existing
+                            );
+                        }
+                        process::exit(0);
+                    }
+                    Err(e) => exit_with(e),
+                }
+            }
+            None => {
+                eprintln!("this subcommand needs to be run within, or pointed to, a gar repo");
+                process::exit(3);
+            }
+        },
+        cmds::Subcommands::GitImport(args) => match repo {
+            Some(repo) => {
+                repo.create_dir_all().unwrap_or_else(|e| exit_with(e));
+                match gitimport::import(&repo, &args.git_dir, &args.treeish, args.no_index) {
+                    Ok(report) => {
+                        if root_args.verbosity > 0 {
+                            eprintln!(
+                                "{} blobs written, {} already there",
+                                report.blobs_written, report.blobs_existing
+                            );
+                            if report.new_tree {
+                                eprintln!("built a new tree in the treecas");
+                            }
+                        }
+                        println!("{}", report.hash.as_hex());
+                        process::exit(0);
+                    }
+                    Err(e) => exit_with(e),
+                }
+            }
+            None => {
+                eprintln!("this subcommand needs to be run within, or pointed to, a gar repo");
+                process::exit(3);
+            }
+        },
+        cmds::Subcommands::Fsck(args) => match repo {
+            Some(repo) => {
+                let report = fsck::fsck(&repo).unwrap_or_else(|e| exit_with(e));
+                match args.format {
+                    cmds::FsckFormatArg::Text => {
+                        for problem in &report.problems {
+                            println!(
+                                "{}\t{}\t{}",
+                                problem.kind.name(),
+                                problem.path.to_string_lossy(),
+                                problem.detail
+                            );
+                        }
+                    }
+                    cmds::FsckFormatArg::Json => {
+                        let problems: Vec<_> = report
+                            .problems
+                            .iter()
+                            .map(|problem| {
+                                serde_json::json!({
+                                    "kind": problem.kind.name(),
+                                    "path": problem.path.to_string_lossy(),
+                                    "detail": problem.detail,
+                                })
+                            })
+                            .collect();
+                        let doc = serde_json::json!({
+                            "blobs_checked": report.blobs_checked,
+                            "trees_checked": report.trees_checked,
+                            "problems": problems,
+                        });
+                        println!(
+                            "{}",
+                            serde_json::to_string_pretty(&doc).expect("json of json")
+                        );
+                    }
+                }
+                if root_args.verbosity > 0 {
+                    eprintln!(
+                        "checked {} blobs and {} trees: {} problems",
+                        report.blobs_checked,
+                        report.trees_checked,
+                        report.problems.len()
+                    );
+                }
+                // (The same code as a `GarError::Corrupt`.)
+                process::exit(if report.is_ok() { 0 } else { 9 });
+            }
+            None => {
+                eprintln!("this subcommand needs to be run within, or pointed to, a gar repo");
+                process::exit(3);
+            }
+        },
+        cmds::Subcommands::Gc(args) => match repo {
+            Some(repo) => {
+                let opts = gc::GcOptions {
+                    roots: args.roots.iter().map(|s| resolve_hash(&repo, s)).collect(),
+                    dry_run: args.dry_run,
+                    label_history_days: args.label_history_days,
+                };
+                match gc::gc(&repo, &opts) {
+                    Ok(report) => {
+                        if root_args.verbosity > 0 {
+                            for hash in &report.trees {
+                                eprintln!("tree {}", hash.as_hex());
+                            }
+                            for path in &report.temp_dirs {
+                                eprintln!("temp dir {:?}", path);
+                            }
+                        }
+                        println!(
+                            "{} {} trees, {} blobs, {} tree objects, {} garidx files, and {} temp dirs: {} bytes",
+                            if args.dry_run { "would delete" } else { "deleted" },
+                            report.trees.len(),
+                            report.blobs,
+                            report.tree_objects,
+                            report.indexes,
+                            report.temp_dirs.len(),
+                            report.reclaimable_bytes
+                        );
+                        if report.tree_objects_skipped {
+                            eprintln!("warning: some trees kept have no tree objects (`gar reindex` them), so no tree objects were collected");
+                        }
                         process::exit(0);
                     }
-                    Err(e) => {
-                        eprintln!("{}", e);
-                        process::exit(5);
+                    Err(e) => exit_with(e),
+                }
+            }
+            None => {
+                eprintln!("this subcommand needs to be run within, or pointed to, a gar repo");
+                process::exit(3);
+            }
+        },
+        cmds::Subcommands::Pin(args) => match repo {
+            Some(repo) => {
+                if args.hashes.is_empty() {
+                    for hash in gc::pins(&repo).unwrap_or_else(|e| exit_with(e)) {
+                        println!("{}", hash.as_hex());
                     }
+                    process::exit(0);
+                }
+                for s in &args.hashes {
+                    let hash = resolve_hash(&repo, s);
+                    let result = match args.remove {
+                        true => gc::unpin(&repo, &hash),
+                        false => gc::pin(&repo, &hash),
+                    };
+                    result.unwrap_or_else(|e| exit_with(e));
                 }
+                process::exit(0);
             }
             None => {
                 eprintln!("this subcommand needs to be run within, or pointed to, a gar repo");
                 process::exit(3);
             }
         },
+        cmds::Subcommands::Label(args) => match repo {
+            Some(repo) => {
+                match args.action {
+                    cmds::LabelAction::Set(args) => {
+                        let hash = resolve_hash(&repo, &args.hash);
+                        let expect = match (&args.expect, args.create) {
+                            (Some(old), _) => labels::Expect::Hash(resolve_hash(&repo, old)),
+                            (None, true) => labels::Expect::Absent,
+                            (None, false) => labels::Expect::Anything,
+                        };
+                        labels::set(&repo, &args.name, &hash, &expect, &args.message)
+                            .unwrap_or_else(|e| exit_with(e));
+                    }
+                    cmds::LabelAction::Get(args) => {
+                        match labels::get(&repo, &args.name).unwrap_or_else(|e| exit_with(e)) {
+                            Some(hash) => println!("{}", hash.as_hex()),
+                            None => {
+                                eprintln!("there's no label {:?}", args.name);
+                                process::exit(1);
+                            }
+                        }
+                    }
+                    cmds::LabelAction::List => {
+                        for (name, hash) in labels::list(&repo).unwrap_or_else(|e| exit_with(e)) {
+                            println!("{}\t{}", hash.as_hex(), name);
+                        }
+                    }
+                    cmds::LabelAction::Rm(args) => {
+                        let expect = match &args.expect {
+                            Some(old) => labels::Expect::Hash(resolve_hash(&repo, old)),
+                            None => labels::Expect::Anything,
+                        };
+                        labels::remove(&repo, &args.name, &expect, &args.message)
+                            .unwrap_or_else(|e| exit_with(e));
+                    }
+                    cmds::LabelAction::Log(args) => {
+                        let entries =
+                            labels::history(&repo, &args.name).unwrap_or_else(|e| exit_with(e));
+                        let hex = |hash: &Option<gittree::Hash>| {
+                            hash.as_ref().map_or("-".to_owned(), |h| h.as_hex())
+                        };
+                        for entry in entries.iter().rev() {
+                            println!(
+                                "{}\t{}\t{}\t{}",
+                                entry.timestamp,
+                                hex(&entry.old),
+                                hex(&entry.new),
+                                entry.message
+                            );
+                        }
+                    }
+                }
+                process::exit(0);
+            }
+            None => {
+                eprintln!("this subcommand needs to be run within, or pointed to, a gar repo");
+                process::exit(3);
+            }
+        },
+        cmds::Subcommands::Hash(args) => {
+            let special_files = args.special_files.into();
+            let object_format = match (args.object_format, &repo) {
+                (Some(arg), _) => arg.into(),
+                (None, Some(repo)) => repo.object_format(),
+                (None, None) => gittree::ObjectFormat::default(),
+            };
+            // Leave out just what `gar add` would, so the hash is the one it'd get.
+            let heap_meta = repo.as_ref().map(|repo| {
+                fs::metadata(repo.repo_path())
+                    .at(Stage::Repo, repo.repo_path())
+                    .unwrap_or_else(|e| exit_with(e))
+            });
+            let exclusions = garignore::Exclusions::new(&args.exclude, heap_meta.as_ref());
+            let mut skipped = Vec::new();
+            let (hash, entries) = match gittree::hash_of_path_with_entries(
+                object_format,
+                &args.path,
+                special_files,
+                Some(&exclusions),
+                &mut skipped,
+            ) {
+                Ok(result) => result,
+                Err(e) => exit_with(e),
+            };
+            if special_files == gittree::SpecialFilePolicy::Warn && !skipped.is_empty() {
+                eprintln!(
+                    "warning: skipped {} special files (not a file, dir, or symlink):",
+                    skipped.len()
+                );
+                for path in &skipped {
+                    eprintln!("  {:?}", path);
+                }
+            }
+            match args.format {
+                cmds::HashFormatArg::Text => println!("{}", hash.as_hex()),
+                cmds::HashFormatArg::Json => {
+                    // Paths that aren't UTF-8 can't be JSON strings; they come out lossily.
+                    // (Git itself doesn't do any better in most of its porcelain, for what it's worth.)
+                    let is_tree = args.path.symlink_metadata().is_ok_and(|m| m.is_dir());
+                    let entries: Vec<_> = entries
+                        .iter()
+                        .map(|ent| {
+                            serde_json::json!({
+                                "path": ent.path.to_string_lossy(),
+                                "mode": ent.kind.git_mode(),
+                                "size": ent.size,
+                                "hash": ent.hash.as_hex(),
+                            })
+                        })
+                        .collect();
+                    let doc = serde_json::json!({
+                        "path": args.path.to_string_lossy(),
+                        "type": if is_tree { "tree" } else { "blob" },
+                        "hash": hash.as_hex(),
+                        "entries": entries,
+                    });
+                    println!(
+                        "{}",
+                        serde_json::to_string_pretty(&doc).expect("json of json")
+                    );
+                }
+            }
+            process::exit(0);
+        }
     };
     // let r = repo::Repo::new("/tmp");
     // r.create_dir_all().expect("waa");
-    // let hash = add::add(&r, "/tmp/slurpie", add::FaithMode::LinkOriginals).expect("whee");
+    // let hash = add::add(&r, "/tmp/slurpie", &add::AddOptions::default()).expect("whee");
     // println!("{hash:?}")
 }
+
+/// Make sense of a hash (or label name) given on the command line, or exit saying it's neither.
+fn resolve_hash(repo: &repo::Repo, hash_or_label: &str) -> gittree::Hash {
+    match labels::resolve(repo, hash_or_label) {
+        Ok(hash) => hash,
+        Err(GarError::InvalidInput { reason }) => {
+            eprintln!("{}", reason);
+            process::exit(1);
+        }
+        Err(e) => exit_with(e),
+    }
+}
+
+/// Report an error, and exit with the code for its kind.
+fn exit_with(e: GarError) -> ! {
+    eprintln!("{}", e);
+    process::exit(e.exit_code());
+}
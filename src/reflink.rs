@@ -0,0 +1,49 @@
+//! Copy-on-write file clones, via the `FICLONE` ioctl.
+//!
+//! Reflinks are the COW we've always wanted: a fresh file, with its own attributes,
+//! which shares storage with the original until one of them is written to.
+//! Unfortunately they're only available on some filesystems (btrfs, XFS, and a few others),
+//! and only within a single filesystem, so callers have to be ready to hear "no".
+
+use std::fs;
+use std::io;
+use std::io::Write as _;
+use std::os::fd::AsRawFd as _;
+use std::path::Path;
+
+/// Make `dst` a clone of `src`.  `dst` should be a freshly created, empty file.
+pub fn clone_file(src: &fs::File, dst: &fs::File) -> io::Result<()> {
+    // Safety: both fds are open for the duration of the call, and FICLONE takes only the source fd as its argument.
+    let ret = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// True if the error is the kernel telling us reflinks aren't possible for these files
+/// (as opposed to some other IO problem).
+pub fn is_unsupported(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EOPNOTSUPP | libc::EXDEV | libc::EINVAL | libc::ENOTTY)
+    )
+}
+
+/// Find out if the filesystem holding `dir` supports reflinks, by trying one.
+pub fn probe(dir: &Path) -> io::Result<bool> {
+    let td = tempdir::TempDir::new_in(dir, ".reflinkprobe-")?;
+    // (The source of a clone has to be open for reading, so `File::create` won't do.)
+    let mut src = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(td.path().join("src"))?;
+    src.write_all(b"probe")?;
+    let dst = fs::File::create(td.path().join("dst"))?;
+    match clone_file(&src, &dst) {
+        Ok(()) => Ok(true),
+        Err(e) if is_unsupported(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
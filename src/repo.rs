@@ -1,7 +1,13 @@
 use std::fs;
 use std::io;
+use std::os::fd::AsRawFd as _;
 use std::path::{Path, PathBuf};
 
+use crate::add;
+use crate::error::{Context as _, GarError, Stage};
+use crate::gittree;
+use crate::gittree::ObjectFormat;
+
 pub struct Repo {
     /// Path as originally specified.
     /// Includes ".gar" (unless this is a bare repo).
@@ -12,30 +18,95 @@ pub struct Repo {
     blobcas_path: PathBuf,
     treecas_path: PathBuf,
     treeidx_path: PathBuf,
+    treeobj_path: PathBuf,
+    journal_path: PathBuf,
+    /// A file, not a dir.  (And only there if an add has used it; see the `statcache` module.)
+    statcache_path: PathBuf,
+    /// A file, not a dir.  See `Repo::object_format`.
+    config_path: PathBuf,
+    /// A file, not a dir, and always empty.  See `Repo::lock_shared`.
+    lock_path: PathBuf,
+    /// Trees (and blobs) that `gc` should keep: an empty file for each, named by its hash.
+    pins_path: PathBuf,
+    /// See the `labels` module.
+    labels_path: PathBuf,
+    /// The history of each label, in a flat dir of files named for the labels.  See `labels::history`.
+    label_history_path: PathBuf,
+
+    object_format: ObjectFormat,
+    label_history_days: u64,
 }
 
 impl Repo {
-    pub fn new(root_path: impl AsRef<Path>) -> Result<Self, io::Error> {
+    pub fn new(root_path: impl AsRef<Path>) -> Result<Self, GarError> {
         Self::new_bare(root_path.as_ref().join(".gar"))
     }
-    pub fn new_bare(root_path: impl AsRef<Path>) -> Result<Self, io::Error> {
+    pub fn new_bare(root_path: impl AsRef<Path>) -> Result<Self, GarError> {
         let path = root_path.as_ref().to_owned();
+        let config_path = path.join("config");
+        let config = read_config(&config_path)?;
         Ok(Repo {
-            blobcas_path: (&path).join("blobcas"),
-            treecas_path: (&path).join("treecas"),
-            treeidx_path: (&path).join("treeidx"),
+            blobcas_path: path.join("blobcas"),
+            treecas_path: path.join("treecas"),
+            treeidx_path: path.join("treeidx"),
+            treeobj_path: path.join("treeobj"),
+            journal_path: path.join("journal"),
+            statcache_path: path.join("statcache"),
+            lock_path: path.join("lock"),
+            pins_path: path.join("pins"),
+            labels_path: path.join("labels"),
+            label_history_path: path.join("labelhistory"),
+            config_path,
+            object_format: config.object_format,
+            label_history_days: config.label_history_days,
             path,
         })
     }
 
-    pub fn create_dir_all(&self) -> io::Result<()> {
-        fs::create_dir_all(self.repo_path())?;
-        fs::create_dir_all(self.blobcas_path())?;
-        fs::create_dir_all(self.treecas_path())?;
-        fs::create_dir_all(self.treeidx_path())?;
+    /// Choose the object format for a heap that's about to be created.
+    /// (It's only written down by `create_dir_all`, and only if the heap doesn't have a config already:
+    /// a heap's format can't change once it has contents, since every hash in it would be wrong.)
+    pub fn with_object_format(mut self, object_format: ObjectFormat) -> Self {
+        self.object_format = object_format;
+        self
+    }
+
+    pub fn create_dir_all(&self) -> Result<(), GarError> {
+        for path in [
+            self.repo_path(),
+            self.blobcas_path(),
+            self.treecas_path(),
+            self.treeidx_path(),
+            self.treeobj_path(),
+            self.journal_path(),
+            self.pins_path(),
+            self.labels_path(),
+            self.label_history_path(),
+        ] {
+            fs::create_dir_all(path).at(Stage::Repo, path)?;
+        }
+        if !self.config_path.exists() {
+            self.write_config()?;
+        }
         Ok(())
     }
 
+    /// Write the config, atomically.
+    fn write_config(&self) -> Result<(), GarError> {
+        let td = tempdir::TempDir::new_in(&self.path, ".wipconfig-").at(Stage::Repo, &self.path)?;
+        let wip_path = td.path().join("config");
+        fs::write(
+            &wip_path,
+            format!(
+                "{}\nobject-format = {}\n",
+                CONFIG_HEADER,
+                self.object_format.name()
+            ),
+        )
+        .and_then(|_| fs::rename(&wip_path, &self.config_path))
+        .at(Stage::Repo, &self.config_path)
+    }
+
     pub fn repo_path(&self) -> &Path {
         &self.path
     }
@@ -48,19 +119,173 @@ impl Repo {
     pub fn treeidx_path(&self) -> &Path {
         &self.treeidx_path
     }
+    pub fn treeobj_path(&self) -> &Path {
+        &self.treeobj_path
+    }
+    pub fn journal_path(&self) -> &Path {
+        &self.journal_path
+    }
+    pub fn statcache_path(&self) -> &Path {
+        &self.statcache_path
+    }
+    pub fn config_path(&self) -> &Path {
+        &self.config_path
+    }
+    pub fn lock_path(&self) -> &Path {
+        &self.lock_path
+    }
+    pub fn pins_path(&self) -> &Path {
+        &self.pins_path
+    }
+    pub fn labels_path(&self) -> &Path {
+        &self.labels_path
+    }
+    pub fn label_history_path(&self) -> &Path {
+        &self.label_history_path
+    }
+
+    /// What hash function everything in this heap is hashed with.
+    ///
+    /// This comes from the heap's config file.  Heaps from before there was a config are sha256,
+    /// which was the only choice back then.
+    pub fn object_format(&self) -> ObjectFormat {
+        self.object_format
+    }
+
+    /// How many days label history keeps what labels used to point at safe from gc.
+    /// (30, unless the heap's config says otherwise with `label-history-days`.)
+    pub fn label_history_days(&self) -> u64 {
+        self.label_history_days
+    }
+
+    /// Whether a hash is of a tree in the treecas, or a blob in the blobcas.
+    pub fn contains(&self, hash: &gittree::Hash) -> bool {
+        hash.format() == self.object_format
+            && (self.treecas_path.join(hash.as_hex()).is_dir()
+                || [false, true].into_iter().any(|executable| {
+                    self.blobcas_path
+                        .join(add::blob_name(hash, executable))
+                        .is_file()
+                }))
+    }
+
+    /// Take the heap's lock in shared mode, waiting for it if need be.  It's held until the `HeapLock` drops.
+    ///
+    /// Everything that adds to the heap holds this, and any number of those can run at once:
+    /// the heap being content-addressed, they can't get in each other's way.
+    /// What they can't run alongside is `gc`, which takes the lock exclusively,
+    /// since it deletes blobs that an add may be about to link to.
+    pub fn lock_shared(&self) -> Result<HeapLock, GarError> {
+        lock(&self.lock_path, libc::LOCK_SH)
+    }
+
+    /// Take the heap's lock in exclusive mode, for `gc`.
+    /// This doesn't wait: if anything else holds the lock, it's `GarError::Busy`.
+    pub fn lock_exclusive(&self) -> Result<HeapLock, GarError> {
+        lock(&self.lock_path, libc::LOCK_EX | libc::LOCK_NB)
+    }
+
+    /// Take the lock on the labels, waiting for it if need be.
+    /// Changes to labels hold this from reading the old value to writing the new one, so a compare-and-swap really is one.
+    pub fn lock_labels(&self) -> Result<HeapLock, GarError> {
+        // (Heaps from before there were labels don't have the dir yet.)
+        fs::create_dir_all(&self.labels_path).at(Stage::Lock, &self.labels_path)?;
+        lock(&self.labels_path.join(".lock"), libc::LOCK_EX)
+    }
+}
+
+fn lock(path: &Path, operation: libc::c_int) -> Result<HeapLock, GarError> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .at(Stage::Lock, path)?;
+    // flock(2) rather than fcntl locks, since those are per-process, and adds in the same process shouldn't exclude each other.
+    // The lock belongs to this open file, and goes away when it's closed (or the process dies), so it can't go stale.
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(HeapLock { _file: file });
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            Some(libc::EWOULDBLOCK) => {
+                return Err(GarError::Busy {
+                    path: path.to_owned(),
+                })
+            }
+            _ => return Err(e).at(Stage::Lock, path),
+        }
+    }
+}
+
+/// A hold on one of the heap's locks.  See `Repo::lock_shared`.
+#[must_use = "the lock is released as soon as this drops"]
+pub struct HeapLock {
+    _file: fs::File,
+}
+
+const CONFIG_HEADER: &str = "# gar heap config";
+
+/// What a heap's config says.
+struct Config {
+    object_format: ObjectFormat,
+    label_history_days: u64,
+}
+
+/// How long label history protects trees from gc, unless the config says otherwise.
+const DEFAULT_LABEL_HISTORY_DAYS: u64 = 30;
+
+/// Read a heap's config.  No config at all means the defaults.
+///
+/// The config is lines of `key = value`, and `#` comments.  The keys are `object-format` and `label-history-days`.
+fn read_config(path: &Path) -> Result<Config, GarError> {
+    let mut config = Config {
+        object_format: ObjectFormat::default(),
+        label_history_days: DEFAULT_LABEL_HISTORY_DAYS,
+    };
+    let body = match fs::read_to_string(path) {
+        Ok(body) => body,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(config),
+        Err(e) => return Err(e).at(Stage::Repo, path),
+    };
+    let corrupt = |reason: String| GarError::Corrupt {
+        path: path.to_owned(),
+        reason,
+    };
+    for line in body.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once('=').map(|(k, v)| (k.trim(), v.trim())) {
+            Some(("object-format", value)) => {
+                config.object_format = ObjectFormat::from_name(value)
+                    .ok_or_else(|| corrupt(format!("unknown object format {:?}", value)))?;
+            }
+            Some(("label-history-days", value)) => {
+                config.label_history_days = value
+                    .parse()
+                    .map_err(|_| corrupt(format!("{:?} isn't a number of days", value)))?;
+            }
+            _ => return Err(corrupt(format!("can't make sense of {:?}", line))),
+        }
+    }
+    Ok(config)
 }
 
-pub fn find_repo() -> Result<Option<Repo>, io::Error> {
-    return find_repo_from(std::env::current_dir()?);
+pub fn find_repo() -> Result<Option<Repo>, GarError> {
+    find_repo_from(std::env::current_dir().at(Stage::Repo, Path::new("."))?)
 }
 
-pub fn find_repo_from(p: impl AsRef<Path>) -> Result<Option<Repo>, io::Error> {
+pub fn find_repo_from(p: impl AsRef<Path>) -> Result<Option<Repo>, GarError> {
     let path = p.as_ref();
     if path.join(".gar").exists() {
         return Ok(Some(Repo::new(path)?));
     }
     match path.parent() {
-        Some(p) => return find_repo_from(p),
-        None => return Ok(None),
+        Some(p) => find_repo_from(p),
+        None => Ok(None),
     }
 }
@@ -0,0 +1,231 @@
+//! The stat cache: remembered blobhashes of source files, keyed by what `stat` says about them.
+//!
+//! This is the same trick as git's index.  Re-adding a big tree where only a few files changed
+//! shouldn't need to read every byte of every file again: if a file is the same inode, with the same size, mtime, and ctime
+//! as the last time we hashed it, it's a very good bet that it still has the same content.
+//! It's only a bet, though -- nothing stops a determined process from changing a file and then putting its mtime back --
+//! which is why the cache is opt-in (see `StatCacheMode`).
+//!
+//! Only files are cached.  Dirs are still walked, but when every file in a subtree is found in the cache,
+//! that takes only stat calls, and the subtree's tree hash falls straight out of the hashes of its entries.
+//!
+//! The cache is one file per heap (`.gar/statcache`), and it's only ever a cache:
+//! if it's missing, or unreadable, or garbled, we just start over with an empty one.
+//! Each add that uses it saves only the entries for the files it saw, so it never grows beyond one add's worth.
+//!
+//! ### racy mtimes
+//!
+//! Timestamps have a granularity (often a kernel clock tick, sometimes a whole second or two, depending on the filesystem).
+//! So a file could be hashed, and then written to again *within the same tick*, and come out with exactly the same mtime and ctime.
+//! A cache entry made from that first hash would then be wrong, and nothing in the stat would say so.
+//! (Git calls these "racily clean" entries.)
+//!
+//! The safeguard: every entry remembers when it was recorded, and an entry whose mtime isn't at least `RACY_WINDOW_SECS`
+//! older than that isn't trusted.  Such files just get hashed again (and re-recorded), and by the next time around
+//! their mtime is old enough to trust.  Separately, a file is only recorded at all if its size and mtime were the same
+//! after it was stored as they were before it was read, so a file that changes while we're hashing it is never recorded.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{BufRead, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::error::{Context as _, GarError, Stage};
+use crate::gittree;
+
+const HEADER: &str = "# gar stat cache v1";
+
+/// How much older than the time of recording an mtime must be for the entry to be trusted.
+/// (Generous, because some filesystems only have two-second timestamps.)
+pub const RACY_WINDOW_SECS: i64 = 2;
+
+/// Whether an add uses the stat cache.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum StatCacheMode {
+    /// Neither use the stat cache, nor update it.
+    #[default]
+    Off,
+    /// Skip hashing files the stat cache says are unchanged, and record the rest.
+    Trust,
+    /// Hash everything, as if there was no stat cache, but record what was found (replacing anything stale).
+    Rehash,
+}
+
+#[derive(Debug, Clone)]
+struct Record {
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+    /// When this was recorded (in seconds since the epoch).  See the module docs about racy mtimes.
+    recorded_at: i64,
+    hash: gittree::Hash,
+}
+
+#[derive(Debug, Default)]
+pub struct StatCache {
+    /// Keyed by (dev, ino).  A file only ever has one entry; re-recording it replaces the old one.
+    records: HashMap<(u64, u64), Record>,
+}
+
+impl StatCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load the stat cache from a file.  A missing or garbled file gives an empty cache.
+    pub fn load(path: &Path) -> Result<Self, GarError> {
+        let f = match fs::File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(e).at(Stage::StatCache, path),
+        };
+        let mut lines = io::BufReader::new(f).lines();
+        match lines.next() {
+            Some(Ok(line)) if line == HEADER => {}
+            _ => return Ok(Self::new()),
+        }
+        let mut cache = Self::new();
+        for line in lines {
+            let line = line.at(Stage::StatCache, path)?;
+            match parse_line(&line) {
+                Some((key, record)) => {
+                    cache.records.insert(key, record);
+                }
+                // Garbled: forget the lot.  (It's only a cache.)
+                None => return Ok(Self::new()),
+            }
+        }
+        Ok(cache)
+    }
+
+    /// Write the stat cache to a file, atomically.
+    pub fn save(&self, path: &Path) -> Result<(), GarError> {
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let td = tempdir::TempDir::new_in(dir, ".wipstatcache-").at(Stage::StatCache, dir)?;
+        let wip_path = td.path().join("statcache");
+        (|| {
+            let mut w = io::BufWriter::new(fs::File::create(&wip_path)?);
+            writeln!(w, "{}", HEADER)?;
+            for ((dev, ino), r) in &self.records {
+                writeln!(
+                    w,
+                    "{} {} {} {} {} {} {} {} {}",
+                    dev,
+                    ino,
+                    r.size,
+                    r.mtime.0,
+                    r.mtime.1,
+                    r.ctime.0,
+                    r.ctime.1,
+                    r.recorded_at,
+                    r.hash.as_hex()
+                )?;
+            }
+            w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+            fs::rename(&wip_path, path)
+        })()
+        .at(Stage::StatCache, path)
+    }
+
+    /// The hash of a file, if the cache has a trustworthy entry for it that matches its stat exactly.
+    pub fn lookup(&self, meta: &fs::Metadata) -> Option<&gittree::Hash> {
+        let r = self.records.get(&(meta.dev(), meta.ino()))?;
+        let matches = r.size == meta.size()
+            && r.mtime == (meta.mtime(), meta.mtime_nsec())
+            && r.ctime == (meta.ctime(), meta.ctime_nsec());
+        let racy = r.mtime.0 >= r.recorded_at - RACY_WINDOW_SECS;
+        (matches && !racy).then_some(&r.hash)
+    }
+
+    /// Remember the hash of a file with the given stat.  `recorded_at` should be no later than when the file was read.
+    pub fn record(&mut self, meta: &fs::Metadata, hash: &gittree::Hash, recorded_at: i64) {
+        self.records.insert(
+            (meta.dev(), meta.ino()),
+            Record {
+                size: meta.size(),
+                mtime: (meta.mtime(), meta.mtime_nsec()),
+                ctime: (meta.ctime(), meta.ctime_nsec()),
+                recorded_at,
+                hash: hash.clone(),
+            },
+        );
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+fn parse_line(line: &str) -> Option<((u64, u64), Record)> {
+    let mut fields = line.split(' ');
+    fn next<T: std::str::FromStr>(fields: &mut std::str::Split<'_, char>) -> Option<T> {
+        fields.next()?.parse().ok()
+    }
+    let key = (next(&mut fields)?, next(&mut fields)?);
+    let record = Record {
+        size: next(&mut fields)?,
+        mtime: (next(&mut fields)?, next(&mut fields)?),
+        ctime: (next(&mut fields)?, next(&mut fields)?),
+        recorded_at: next(&mut fields)?,
+        hash: gittree::Hash::from_hex(fields.next()?).ok()?,
+    };
+    if fields.next().is_some() {
+        return None;
+    }
+    Some((key, record))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_lookup_and_racy() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let file_path = td.path().join("f");
+        fs::write(&file_path, b"a file\n").unwrap();
+        let hash = gittree::hash_of_path(gittree::ObjectFormat::Sha256, &file_path).unwrap();
+        let meta = fs::metadata(&file_path).unwrap();
+
+        // Recorded right away: too close to the mtime to trust.
+        let mut cache = StatCache::new();
+        cache.record(&meta, &hash, meta.mtime());
+        assert_eq!(cache.lookup(&meta), None);
+
+        // Recorded well after: fine.
+        cache.record(&meta, &hash, meta.mtime() + RACY_WINDOW_SECS + 1);
+        assert_eq!(cache.lookup(&meta), Some(&hash));
+
+        // Survives a round trip through the file.
+        let cache_path = td.path().join("statcache");
+        cache.save(&cache_path).unwrap();
+        let cache = StatCache::load(&cache_path).unwrap();
+        assert_eq!(cache.lookup(&meta), Some(&hash));
+
+        // Any change in the stat is a miss.  (Even with the mtime put back, the ctime moves.)
+        std::thread::sleep(Duration::from_millis(10));
+        fs::write(&file_path, b"b file\n").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&file_path)
+            .unwrap()
+            .set_modified(
+                SystemTime::UNIX_EPOCH
+                    + Duration::new(meta.mtime() as u64, meta.mtime_nsec() as u32),
+            )
+            .unwrap();
+        let meta2 = fs::metadata(&file_path).unwrap();
+        assert_eq!(cache.lookup(&meta2), None);
+
+        // And garbage is just an empty cache.
+        fs::write(&cache_path, b"# gar stat cache v1\nnonsense\n").unwrap();
+        assert!(StatCache::load(&cache_path).unwrap().is_empty());
+    }
+}
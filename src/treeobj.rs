@@ -0,0 +1,162 @@
+//! The treeobj store: raw git tree objects, one file per tree, named by their hash.
+//!
+//! `gar add` writes one of these for every dir it hashes (subtrees included, not just the root),
+//! with exactly the bytes that were hashed, minus git's "tree {size}" header.
+//! With these, what's in a tree can be answered by reading one small file, rather than walking a dir in the treecas;
+//! and subtrees have an object of their own even though they're not materialized at the treecas root.
+//!
+//! Like the blobcas, this is content-addressed, so it only ever grows, and writing an object that's already there is a no-op.
+//! Unlike the treeidx, it's never rewritten: a tree object can only ever say one thing.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::error::{Context as _, GarError, Stage};
+use crate::gittree;
+use crate::gittree::EntryKind;
+use crate::repo;
+
+/// Store a tree object, unless it's there already.
+///
+/// The caller promises `hash` is the hash of `payload` (as `TreeHashAccumulator::finish_with_payload` gives them together).
+pub fn store(repo: &repo::Repo, hash: &gittree::Hash, payload: &[u8]) -> Result<(), GarError> {
+    let dest_path = repo.treeobj_path().join(hash.as_hex());
+    if dest_path.exists() {
+        return Ok(());
+    }
+    // Written aside and renamed into place, so an object that exists is always whole.
+    // (Two adds racing to write the same object will write the same bytes, so whichever rename lands last is fine.)
+    let td = tempdir::TempDir::new_in(repo.treeobj_path(), ".wiptreeobj-")
+        .at(Stage::TreeObj, repo.treeobj_path())?;
+    let wip_path = td.path().join("obj");
+    fs::write(&wip_path, payload)
+        .and_then(|_| fs::rename(&wip_path, &dest_path))
+        .at(Stage::TreeObj, &dest_path)
+}
+
+/// Store the tree object of every dir in a list of entries (as `gittree::hash_of_path_with_entries` finds them,
+/// plus the root, as `garidx::with_root` adds it).
+///
+/// A tree object is fully determined by the entries directly in it, so these can be rebuilt from that list alone;
+/// this is how trees added before there was a treeobj store get objects.
+pub fn store_from_entries(
+    repo: &repo::Repo,
+    entries: &[gittree::HashedEntry],
+) -> Result<(), GarError> {
+    let mut children: HashMap<&Path, Vec<&gittree::HashedEntry>> = HashMap::new();
+    for ent in entries {
+        if let Some(parent) = ent.path.parent() {
+            children.entry(parent).or_default().push(ent);
+        }
+    }
+    for dir in entries.iter().filter(|ent| ent.kind == EntryKind::Dir) {
+        let kids = children.get(dir.path.as_path()).map_or(&[][..], |v| &v[..]);
+        let mut tha = gittree::TreeHashAccumulator::new(repo.object_format(), kids.len());
+        for kid in kids {
+            let name = kid
+                .path
+                .file_name()
+                .expect("entries below the root have names");
+            tha.append(kid.kind, name.as_encoded_bytes(), &kid.hash);
+        }
+        let (hash, payload) = tha.finish_with_payload();
+        // The entries say what the dir's hash is, too; if that disagrees, the entries are inconsistent, and nothing should be stored.
+        if hash != dir.hash {
+            return Err(GarError::InvalidInput {
+                reason: format!(
+                    "entries for {:?} hash to {}, not {}",
+                    dir.path,
+                    hash.as_hex(),
+                    dir.hash.as_hex()
+                ),
+            });
+        }
+        store(repo, &hash, &payload)?;
+    }
+    Ok(())
+}
+
+/// Load and decode a tree object.  None if the store doesn't have it.
+///
+/// It's checked against its hash on the way: one that doesn't match, or doesn't decode, is `GarError::Corrupt`.
+pub fn load(
+    repo: &repo::Repo,
+    hash: &gittree::Hash,
+) -> Result<Option<gittree::TreeObject>, GarError> {
+    let path = repo.treeobj_path().join(hash.as_hex());
+    let payload = match fs::read(&path) {
+        Ok(payload) => payload,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).at(Stage::TreeObj, &path),
+    };
+    let corrupt = |reason: String| GarError::Corrupt {
+        path: path.clone(),
+        reason,
+    };
+    let tree =
+        gittree::TreeObject::decode(hash.format(), &payload).map_err(|e| corrupt(e.to_string()))?;
+    let found_hash = tree.hash(hash.format());
+    if found_hash != *hash {
+        return Err(corrupt(format!(
+            "actually hashes to {}",
+            found_hash.as_hex()
+        )));
+    }
+    Ok(Some(tree))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::add;
+    use crate::garidx;
+
+    #[test]
+    fn test_add_stores_tree_objects() {
+        let td = tempdir::TempDir::new_in("target", "gar-test-").expect("tempdir");
+        let r = repo::Repo::new(td.path()).unwrap();
+        r.create_dir_all().unwrap();
+        let opts = add::AddOptions {
+            faithmode: add::FaithMode::Copy,
+            ..Default::default()
+        };
+        let report = add::add(&r, "fixtures/alpha", &opts).expect("add to succeed");
+
+        let root = load(&r, &report.hash).unwrap().expect("root tree object");
+        let names: Vec<_> = root.entries.iter().map(|ent| &ent.name[..]).collect();
+        assert_eq!(names, [&b"a_dir"[..], b"a_file", b"a_symlink"]);
+
+        // Subtrees get one too, though they're not in the treecas root.
+        let a_dir = &root.entries[0];
+        assert_eq!(a_dir.kind, EntryKind::Dir);
+        assert!(!r.treecas_path().join(a_dir.hash.as_hex()).exists());
+        let sub = load(&r, &a_dir.hash).unwrap().expect("subtree object");
+        assert_eq!(
+            sub.hash(r.object_format()),
+            gittree::hash_of_path(r.object_format(), "fixtures/alpha/a_dir").unwrap()
+        );
+
+        // They can all be rebuilt from the index, too.
+        let objs_before = fs::read_dir(r.treeobj_path()).unwrap().count();
+        fs::remove_dir_all(r.treeobj_path()).unwrap();
+        fs::create_dir(r.treeobj_path()).unwrap();
+        garidx::reindex(&r, &report.hash).expect("reindex to succeed");
+        assert_eq!(fs::read_dir(r.treeobj_path()).unwrap().count(), objs_before);
+        assert_eq!(load(&r, &a_dir.hash).unwrap(), Some(sub));
+
+        // Missing is None; tampered with is corrupt.
+        let missing = gittree::Hash::from([0u8; 32]);
+        assert!(load(&r, &missing).unwrap().is_none());
+        let obj_path = r.treeobj_path().join(report.hash.as_hex());
+        let mut payload = fs::read(&obj_path).unwrap();
+        let last = payload.len() - 1;
+        payload[last] ^= 1;
+        fs::write(&obj_path, payload).unwrap();
+        assert!(matches!(
+            load(&r, &report.hash),
+            Err(GarError::Corrupt { .. })
+        ));
+    }
+}